@@ -0,0 +1,402 @@
+//! Async variant of `Hcp2Driver` for firmwares with an async executor (feature `async`).
+//!
+//! The UART is any `embedded_io_async` reader/writer. Instead of being polled in a loop, the
+//! driver awaits received bytes, bounded by the inter-frame gap while a partial frame is
+//! buffered and by `ASYNC_TICK_MS` otherwise. Frames are handled by the same `Hcp2Driver` the
+//! LP and HP cores run, through a `HcpHal` that serves the bytes of one read.
+
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use embedded_io_async::{Error, ErrorKind, Read, Write};
+
+use crate::config::Hcp2Config;
+use crate::driver::Hcp2Driver;
+use crate::hal::{HcpHal, LINE_ERROR_FRAMING, LINE_ERROR_OVERRUN};
+use crate::shared::SharedData;
+use crate::trace::{NoTrace, TraceSink};
+
+/// Longest wait for bytes before the link state and the shared memory are updated.
+pub const ASYNC_TICK_MS: u32 = 10;
+
+/// Responses of one step are queued here and sent once the step is done.
+const TX_QUEUE_LEN: usize = 256;
+
+/// Clock and delay used by `AsyncHcp2Driver`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTimer {
+    /// Current timestamp in milliseconds (wrapping).
+    fn now_ms(&self) -> u32;
+
+    /// Current timestamp in microseconds (wrapping), or None without a microsecond clock.
+    /// Same meaning as `HcpHal::now_us`.
+    fn now_us(&self) -> Option<u32> {
+        None
+    }
+
+    /// Waits for at least `us` microseconds.
+    async fn delay_us(&mut self, us: u32);
+}
+
+/// RS-485 direction control. `()` is for transceivers that switch by themselves and UARTs
+/// in a hardware RS-485 mode.
+pub trait TxEnable {
+    /// Set the direction pin (true = TX, false = RX).
+    fn set_tx_enable(&mut self, enable: bool);
+}
+
+impl TxEnable for () {
+    fn set_tx_enable(&mut self, _enable: bool) {}
+}
+
+/// `Hcp2Driver` on an async UART.
+///
+/// The UART's `read` must be cancel-safe: it is dropped whenever the wait for bytes times out.
+/// `flush` must only return once the last byte has left the transmitter, because the
+/// direction pin is released right after it; `tx_turnaround_ms` is not used.
+/// Log messages of the driver are dropped, the counters in `BusStats` cover them.
+pub struct AsyncHcp2Driver<U, T, D = ()> {
+    driver: Hcp2Driver,
+    uart: U,
+    timer: T,
+    tx_enable: D,
+    tx_queue: [u8; TX_QUEUE_LEN],
+}
+
+impl<U: Read + Write, T: AsyncTimer, D: TxEnable> AsyncHcp2Driver<U, T, D> {
+    pub fn new(config: Hcp2Config, uart: U, timer: T, tx_enable: D) -> Self {
+        Self { driver: Hcp2Driver::new(config), uart, timer, tx_enable, tx_queue: [0u8; TX_QUEUE_LEN] }
+    }
+
+    /// The synchronous driver underneath, e.g. for `resume`, `status` or `stats`.
+    pub fn driver(&mut self) -> &mut Hcp2Driver {
+        &mut self.driver
+    }
+
+    /// Waits for bus activity (or the next tick) and handles it. Call repeatedly.
+    ///
+    /// A read error drops the frame being received and is counted like a UART line error
+    /// (see `line_errors`) before it is returned; the driver stays usable and the next call
+    /// carries on.
+    pub async fn poll(&mut self, shared: &mut SharedData) -> Result<(), U::Error> {
+        self.poll_with_trace(shared, &mut NoTrace).await
+    }
+
+    /// Like `poll`, additionally passing every received and sent frame to `trace`.
+    pub async fn poll_with_trace<S: TraceSink>(&mut self, shared: &mut SharedData, trace: &mut S) -> Result<(), U::Error> {
+        // Wake up once a buffered partial frame is due to be dropped: one tick of a
+        // millisecond clock past the gap, so it has certainly passed.
+        let wait_us = match self.driver.pending_gap_us() {
+            Some(gap_us) => gap_us.saturating_add(1000),
+            None => ASYNC_TICK_MS * 1000,
+        };
+        let mut rx = [0u8; 32];
+        let (received, error) = match select(self.uart.read(&mut rx), self.timer.delay_us(wait_us)).await {
+            Either::First(Ok(count)) => (count, None),
+            Either::First(Err(e)) => (0, Some(e)),
+            Either::Second(()) => (0, None),
+        };
+
+        let mut hal = StepHal {
+            rx: &rx[..received],
+            now_ms: self.timer.now_ms(),
+            now_us: self.timer.now_us(),
+            tx_queue: &mut self.tx_queue,
+            tx_len: 0,
+            line_errors: error.as_ref().map_or(0, |e| line_errors(e.kind())),
+        };
+        self.driver.poll_with_trace(&mut hal, shared, trace);
+        let tx_len = hal.tx_len;
+
+        if tx_len > 0 {
+            self.tx_enable.set_tx_enable(true);
+            let sent = match self.uart.write_all(&self.tx_queue[..tx_len]).await {
+                Ok(()) => self.uart.flush().await,
+                Err(e) => Err(e),
+            };
+            self.tx_enable.set_tx_enable(false);
+            sent?;
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+/// `LINE_ERROR_*` bits for a read error. `ErrorKind` does not tell a parity error from a
+/// framing error (HALs report both as `InvalidData`), so anything but an overrun counts as framing.
+fn line_errors(kind: ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::OutOfMemory => LINE_ERROR_OVERRUN,
+        _ => LINE_ERROR_FRAMING,
+    }
+}
+
+/// `HcpHal` for one step of the synchronous driver: reads return the bytes received by the
+/// step, writes are queued and sent afterwards.
+struct StepHal<'a> {
+    rx: &'a [u8],
+    now_ms: u32,
+    now_us: Option<u32>,
+    tx_queue: &'a mut [u8; TX_QUEUE_LEN],
+    tx_len: usize,
+    /// From a failed read, reported once.
+    line_errors: u8,
+}
+
+impl HcpHal for StepHal<'_> {
+    fn uart_read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.rx.len());
+        buf[..len].copy_from_slice(&self.rx[..len]);
+        self.rx = &self.rx[len..];
+        len
+    }
+
+    fn uart_write(&mut self, buf: &[u8]) -> usize {
+        let Some(slot) = self.tx_queue.get_mut(self.tx_len..self.tx_len + buf.len()) else {
+            return 0;
+        };
+        slot.copy_from_slice(buf);
+        self.tx_len += buf.len();
+        buf.len()
+    }
+
+    // Direction is switched around the actual write in `poll_with_trace`
+    fn set_tx_enable(&mut self, _enable: bool) {}
+
+    fn now_ms(&self) -> u32 {
+        self.now_ms
+    }
+
+    fn sleep_ms(&mut self, _ms: u32) {}
+
+    fn log(&mut self, _message: &str) {}
+
+    fn now_us(&self) -> Option<u32> {
+        self.now_us
+    }
+
+    // Completion is awaited through `flush`
+    fn wait_tx_complete(&mut self) -> bool {
+        true
+    }
+
+    fn take_line_errors(&mut self) -> u8 {
+        core::mem::take(&mut self.line_errors)
+    }
+}
+
+enum Either<A, B> {
+    First(A),
+    Second(B),
+}
+
+/// Runs both futures until one completes; the other one is dropped.
+async fn select<A: Future, B: Future>(a: A, b: B) -> Either<A::Output, B::Output> {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    poll_fn(|cx| {
+        if let Poll::Ready(out) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::First(out));
+        }
+        if let Poll::Ready(out) = b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Second(out));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{ReadResponse, ReadWriteRequest, RegisterBlock};
+    use crate::registers::{ADDR_POLL, ADDR_SYNC_COUNTER};
+    use core::task::{Context, Waker};
+
+    /// Polls `fut` to completion, busy-looping instead of sleeping.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    struct MockUart {
+        rx: [u8; 64],
+        rx_len: usize,
+        tx: [u8; 64],
+        tx_len: usize,
+        flushed: bool,
+        /// Returned by the next read instead of data.
+        rx_error: Option<ErrorKind>,
+    }
+
+    impl MockUart {
+        fn new() -> Self {
+            Self { rx: [0; 64], rx_len: 0, tx: [0; 64], tx_len: 0, flushed: false, rx_error: None }
+        }
+
+        fn receive(&mut self, bytes: &[u8]) {
+            self.rx[self.rx_len..self.rx_len + bytes.len()].copy_from_slice(bytes);
+            self.rx_len += bytes.len();
+        }
+    }
+
+    impl embedded_io_async::ErrorType for MockUart {
+        type Error = ErrorKind;
+    }
+
+    impl Read for MockUart {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            if let Some(e) = self.rx_error.take() {
+                return Err(e);
+            }
+            // Nothing received: wait forever, like a real UART on a quiet bus
+            poll_fn(|_| if self.rx_len == 0 { Poll::Pending } else { Poll::Ready(()) }).await;
+            let len = buf.len().min(self.rx_len);
+            buf[..len].copy_from_slice(&self.rx[..len]);
+            self.rx.copy_within(len..self.rx_len, 0);
+            self.rx_len -= len;
+            Ok(len)
+        }
+    }
+
+    impl Write for MockUart {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.tx[self.tx_len..self.tx_len + buf.len()].copy_from_slice(buf);
+            self.tx_len += buf.len();
+            self.flushed = false;
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), ErrorKind> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    /// Time only passes in `delay_us`.
+    struct MockTimer {
+        now_us: u32,
+    }
+
+    impl AsyncTimer for MockTimer {
+        fn now_ms(&self) -> u32 {
+            self.now_us / 1000
+        }
+
+        fn now_us(&self) -> Option<u32> {
+            Some(self.now_us)
+        }
+
+        async fn delay_us(&mut self, us: u32) {
+            self.now_us += us;
+        }
+    }
+
+    #[derive(Default)]
+    struct MockPin {
+        switches: u32,
+        enabled: bool,
+    }
+
+    impl TxEnable for MockPin {
+        fn set_tx_enable(&mut self, enable: bool) {
+            self.switches += 1;
+            self.enabled = enable;
+        }
+    }
+
+    fn bus_scan(buf: &mut [u8]) -> usize {
+        let write = RegisterBlock::new(ADDR_SYNC_COUNTER, &[0, 0, 0]).unwrap();
+        ReadWriteRequest { address: 0x02, read_start: ADDR_POLL, read_qty: 5, write }.encode(buf).unwrap()
+    }
+
+    fn driver() -> AsyncHcp2Driver<MockUart, MockTimer, MockPin> {
+        AsyncHcp2Driver::new(Hcp2Config::default(), MockUart::new(), MockTimer { now_us: 1_000_000 }, MockPin::default())
+    }
+
+    #[test]
+    fn test_request_is_answered() {
+        let mut driver = driver();
+        let mut shared = SharedData::default();
+        let mut frame = [0u8; 32];
+        let len = bus_scan(&mut frame);
+        driver.uart.receive(&frame[..len]);
+
+        block_on(driver.poll(&mut shared)).unwrap();
+
+        let uart = &driver.uart;
+        let response = ReadResponse::decode(&uart.tx[..uart.tx_len]).expect("scan response");
+        assert_eq!(response.address, 0x02);
+        assert_eq!(response.regs.quantity(), 5);
+        assert!(uart.flushed);
+        assert_eq!((driver.tx_enable.switches, driver.tx_enable.enabled), (2, false));
+        assert_eq!(driver.driver().stats().bus_scans, 1);
+    }
+
+    #[test]
+    fn test_partial_frame_dropped_after_gap() {
+        let mut driver = driver();
+        let mut shared = SharedData::default();
+        let mut frame = [0u8; 32];
+        let len = bus_scan(&mut frame);
+        driver.uart.receive(&frame[..5]);
+
+        block_on(driver.poll(&mut shared)).unwrap();
+        assert_eq!(driver.driver().stats().framer_truncated, 0);
+
+        // Nothing else arrives: the next poll waits out the gap instead of a full tick
+        let before = driver.timer.now_us;
+        block_on(driver.poll(&mut shared)).unwrap();
+        assert!(driver.timer.now_us - before < ASYNC_TICK_MS * 1000);
+        assert_eq!(driver.driver().stats().framer_truncated, 1);
+
+        // Quiet bus: one tick per poll
+        let before = driver.timer.now_us;
+        block_on(driver.poll(&mut shared)).unwrap();
+        assert_eq!(driver.timer.now_us - before, ASYNC_TICK_MS * 1000);
+
+        driver.uart.receive(&frame[..len]);
+        block_on(driver.poll(&mut shared)).unwrap();
+        assert_eq!(driver.driver().stats().bus_scans, 1);
+        assert_eq!(driver.uart.tx_len, ReadResponse::frame_len(5));
+    }
+
+    #[test]
+    fn test_read_error_counted_as_line_error() {
+        let mut driver = driver();
+        let mut shared = SharedData::default();
+        let mut frame = [0u8; 32];
+        let len = bus_scan(&mut frame);
+
+        // The UART overruns in the middle of the scan
+        driver.uart.receive(&frame[..6]);
+        block_on(driver.poll(&mut shared)).unwrap();
+        driver.uart.rx_error = Some(ErrorKind::OutOfMemory);
+        assert_eq!(block_on(driver.poll(&mut shared)), Err(ErrorKind::OutOfMemory));
+        driver.uart.receive(&frame[6..len]);
+        block_on(driver.poll(&mut shared)).unwrap();
+
+        // Dropped without an answer and without a truncated frame
+        assert_eq!(driver.uart.tx_len, 0);
+        let stats = *driver.driver().stats();
+        assert_eq!((stats.line_overruns, stats.line_framing_errors, stats.line_parity_errors), (1, 0, 0));
+        assert_eq!((stats.framer_truncated, stats.framer_garbage), (0, 0));
+
+        // Other errors count as framing errors
+        driver.uart.rx_error = Some(ErrorKind::InvalidData);
+        assert_eq!(block_on(driver.poll(&mut shared)), Err(ErrorKind::InvalidData));
+        assert_eq!(driver.driver().stats().line_framing_errors, 1);
+
+        // The drive retries after the gap and is answered
+        block_on(driver.poll(&mut shared)).unwrap();
+        driver.uart.receive(&frame[..len]);
+        block_on(driver.poll(&mut shared)).unwrap();
+        assert_eq!(driver.driver().stats().bus_scans, 1);
+        assert_eq!(driver.uart.tx_len, ReadResponse::frame_len(5));
+    }
+}
@@ -0,0 +1,496 @@
+//! Modbus RTU wire format of the frames used on the HCP2 bus.
+//!
+//! The bridge and the tester both build and parse frames through these types, so the two
+//! ends of the bus cannot disagree about the layout. Every `decode` checks the CRC and that
+//! the header fields agree with each other and with the frame length; every `encode`
+//! appends the CRC and returns the frame length.
+
+use crate::protocol::DispatchError;
+use crate::registers::{FUNC_READ_WRITE_MULTIPLE_REGISTERS, FUNC_WRITE_MULTIPLE_REGISTERS};
+
+/// Largest register block a frame may carry. The drive never sends more than 9.
+pub const MAX_REGISTERS: usize = 16;
+
+/// Set in the function code of an exception response.
+const EXCEPTION_FLAG: u8 = 0x80;
+/// Address and function code
+const HEADER_LEN: usize = 2;
+/// CRC low byte, CRC high byte
+const CRC_LEN: usize = 2;
+
+/// Modbus CRC16 (polynomial 0xA001, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if (crc & 1) != 0 {
+                crc >>= 1;
+                crc ^= 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// True if the last two bytes of `frame` are the CRC of the rest (low byte first).
+pub fn crc_valid(frame: &[u8]) -> bool {
+    let Some(body_len) = frame.len().checked_sub(CRC_LEN) else {
+        return false;
+    };
+    let received = (frame[body_len] as u16) | ((frame[body_len + 1] as u16) << 8);
+    crc16(&frame[..body_len]) == received
+}
+
+/// Returns the frame without its CRC, after checking the minimum length and the CRC.
+fn frame_body(frame: &[u8]) -> Result<&[u8], DispatchError> {
+    if frame.len() < HEADER_LEN + CRC_LEN {
+        return Err(DispatchError::FrameTooShort);
+    }
+    if !crc_valid(frame) {
+        return Err(DispatchError::CrcMismatch);
+    }
+    Ok(&frame[..frame.len() - CRC_LEN])
+}
+
+fn be16(body: &[u8], offset: usize) -> Result<u16, DispatchError> {
+    let bytes = body.get(offset..offset + 2).ok_or(DispatchError::FrameTooShort)?;
+    Ok(((bytes[0] as u16) << 8) | (bytes[1] as u16))
+}
+
+/// Sequential writer for `encode`.
+struct FrameWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FrameWriter<'a> {
+    /// Fails up front if `len` bytes (CRC included) do not fit.
+    fn new(buf: &'a mut [u8], len: usize) -> Result<Self, DispatchError> {
+        if buf.len() < len {
+            return Err(DispatchError::BufferTooSmall);
+        }
+        Ok(Self { buf, len: 0 })
+    }
+
+    fn u8(&mut self, val: u8) -> &mut Self {
+        self.buf[self.len] = val;
+        self.len += 1;
+        self
+    }
+
+    fn u16(&mut self, val: u16) -> &mut Self {
+        self.u8((val >> 8) as u8).u8((val & 0xFF) as u8)
+    }
+
+    /// Byte count followed by the register values.
+    fn block(&mut self, regs: &[u16]) -> &mut Self {
+        self.u8((regs.len() * 2) as u8);
+        for &reg in regs {
+            self.u16(reg);
+        }
+        self
+    }
+
+    fn finish(&mut self) -> usize {
+        let crc = crc16(&self.buf[..self.len]);
+        self.u8((crc & 0xFF) as u8).u8((crc >> 8) as u8);
+        self.len
+    }
+}
+
+/// Register values carried by a frame, already checked against the frame.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegisterBlock {
+    pub start: u16,
+    len: usize,
+    regs: [u16; MAX_REGISTERS],
+}
+
+impl RegisterBlock {
+    pub fn new(start: u16, regs: &[u16]) -> Result<Self, DispatchError> {
+        if regs.len() > MAX_REGISTERS {
+            return Err(DispatchError::TooManyRegisters);
+        }
+        let mut block = Self { start, len: regs.len(), regs: [0; MAX_REGISTERS] };
+        block.regs[..regs.len()].copy_from_slice(regs);
+        Ok(block)
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.regs[..self.len]
+    }
+
+    pub fn quantity(&self) -> u16 {
+        self.len as u16
+    }
+
+    /// Parses a block whose quantity (if the frame carries one) is at `qty_offset`
+    /// and whose byte count is just before `data_offset`. The data must end the body.
+    fn decode(body: &[u8], start: u16, qty_offset: Option<usize>, data_offset: usize) -> Result<Self, DispatchError> {
+        let byte_count = *body.get(data_offset - 1).ok_or(DispatchError::FrameTooShort)? as usize;
+        if !byte_count.is_multiple_of(2) {
+            return Err(DispatchError::OddByteCount);
+        }
+        if let Some(qty_offset) = qty_offset {
+            let qty = be16(body, qty_offset)? as usize;
+            if qty > MAX_REGISTERS {
+                return Err(DispatchError::TooManyRegisters);
+            }
+            if qty * 2 != byte_count {
+                return Err(DispatchError::QuantityMismatch);
+            }
+        }
+        if byte_count / 2 > MAX_REGISTERS {
+            return Err(DispatchError::TooManyRegisters);
+        }
+        if body.len() != data_offset + byte_count {
+            return Err(DispatchError::LengthMismatch);
+        }
+
+        let mut block = Self { start, len: byte_count / 2, regs: [0; MAX_REGISTERS] };
+        for (reg, bytes) in block.regs.iter_mut().zip(body[data_offset..].chunks_exact(2)) {
+            *reg = ((bytes[0] as u16) << 8) | (bytes[1] as u16);
+        }
+        Ok(block)
+    }
+}
+
+/// 0x10 Write Multiple Registers, sent by the drive (status broadcast, sync write).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WriteRequest {
+    pub address: u8,
+    pub write: RegisterBlock,
+}
+
+impl WriteRequest {
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        let body = frame_body(frame)?;
+        expect_function(body, FUNC_WRITE_MULTIPLE_REGISTERS)?;
+        let start = be16(body, 2)?;
+        Ok(Self { address: body[0], write: RegisterBlock::decode(body, start, Some(4), 7)? })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        let regs = self.write.as_slice();
+        Ok(FrameWriter::new(buf, 9 + regs.len() * 2)?
+            .u8(self.address)
+            .u8(FUNC_WRITE_MULTIPLE_REGISTERS)
+            .u16(self.write.start)
+            .u16(self.write.quantity())
+            .block(regs)
+            .finish())
+    }
+}
+
+/// 0x17 Read/Write Multiple Registers, sent by the drive (bus scan, poll).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ReadWriteRequest {
+    pub address: u8,
+    pub read_start: u16,
+    pub read_qty: u16,
+    pub write: RegisterBlock,
+}
+
+impl ReadWriteRequest {
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        let body = frame_body(frame)?;
+        expect_function(body, FUNC_READ_WRITE_MULTIPLE_REGISTERS)?;
+        let read_start = be16(body, 2)?;
+        let read_qty = be16(body, 4)?;
+        let write_start = be16(body, 6)?;
+        Ok(Self {
+            address: body[0],
+            read_start,
+            read_qty,
+            write: RegisterBlock::decode(body, write_start, Some(8), 11)?,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        let regs = self.write.as_slice();
+        Ok(FrameWriter::new(buf, 13 + regs.len() * 2)?
+            .u8(self.address)
+            .u8(FUNC_READ_WRITE_MULTIPLE_REGISTERS)
+            .u16(self.read_start)
+            .u16(self.read_qty)
+            .u16(self.write.start)
+            .u16(self.write.quantity())
+            .block(regs)
+            .finish())
+    }
+}
+
+/// Answer to a `WriteRequest`, echoing start and quantity.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WriteResponse {
+    pub address: u8,
+    pub start: u16,
+    pub quantity: u16,
+}
+
+impl WriteResponse {
+    pub const FRAME_LEN: usize = 8;
+
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        let body = frame_body(frame)?;
+        expect_function(body, FUNC_WRITE_MULTIPLE_REGISTERS)?;
+        let (start, quantity) = (be16(body, 2)?, be16(body, 4)?);
+        if frame.len() != Self::FRAME_LEN {
+            return Err(DispatchError::LengthMismatch);
+        }
+        Ok(Self { address: body[0], start, quantity })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        Ok(FrameWriter::new(buf, Self::FRAME_LEN)?
+            .u8(self.address)
+            .u8(FUNC_WRITE_MULTIPLE_REGISTERS)
+            .u16(self.start)
+            .u16(self.quantity)
+            .finish())
+    }
+}
+
+/// Answer to a `ReadWriteRequest`. `regs.start` is 0, the read address is only in the request.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ReadResponse {
+    pub address: u8,
+    pub regs: RegisterBlock,
+}
+
+impl ReadResponse {
+    /// Length of a response carrying `quantity` registers.
+    pub const fn frame_len(quantity: usize) -> usize {
+        5 + quantity * 2
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        let body = frame_body(frame)?;
+        expect_function(body, FUNC_READ_WRITE_MULTIPLE_REGISTERS)?;
+        Ok(Self { address: body[0], regs: RegisterBlock::decode(body, 0, None, 3)? })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        let regs = self.regs.as_slice();
+        Ok(FrameWriter::new(buf, Self::frame_len(regs.len()))?
+            .u8(self.address)
+            .u8(FUNC_READ_WRITE_MULTIPLE_REGISTERS)
+            .block(regs)
+            .finish())
+    }
+}
+
+/// Standard Modbus exception codes the bridge can answer with.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExceptionCode {
+    /// Function code not supported
+    IllegalFunction = 0x01,
+    /// Register address not served
+    IllegalDataAddress = 0x02,
+    /// Quantity or value not served
+    IllegalDataValue = 0x03,
+}
+
+/// Modbus exception response: the request's function code with the top bit set, and a reason.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ExceptionResponse {
+    pub address: u8,
+    /// Function code of the request, without `EXCEPTION_FLAG`
+    pub function: u8,
+    pub code: u8,
+}
+
+impl ExceptionResponse {
+    pub const FRAME_LEN: usize = 5;
+
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        let body = frame_body(frame)?;
+        if body[1] & EXCEPTION_FLAG == 0 {
+            return Err(DispatchError::InvalidFunction);
+        }
+        if frame.len() != Self::FRAME_LEN {
+            return Err(DispatchError::LengthMismatch);
+        }
+        Ok(Self { address: body[0], function: body[1] & !EXCEPTION_FLAG, code: body[2] })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        Ok(FrameWriter::new(buf, Self::FRAME_LEN)?
+            .u8(self.address)
+            .u8(self.function | EXCEPTION_FLAG)
+            .u8(self.code)
+            .finish())
+    }
+}
+
+fn expect_function(body: &[u8], function: u8) -> Result<(), DispatchError> {
+    if body[1] == function { Ok(()) } else { Err(DispatchError::InvalidFunction) }
+}
+
+/// A request from the drive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Request {
+    Write(WriteRequest),
+    ReadWrite(ReadWriteRequest),
+}
+
+impl Request {
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        match frame.get(1) {
+            Some(&FUNC_WRITE_MULTIPLE_REGISTERS) => WriteRequest::decode(frame).map(Request::Write),
+            Some(&FUNC_READ_WRITE_MULTIPLE_REGISTERS) => ReadWriteRequest::decode(frame).map(Request::ReadWrite),
+            Some(_) => frame_body(frame).and(Err(DispatchError::InvalidFunction)),
+            None => Err(DispatchError::FrameTooShort),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        match self {
+            Request::Write(request) => request.encode(buf),
+            Request::ReadWrite(request) => request.encode(buf),
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        match self {
+            Request::Write(request) => request.address,
+            Request::ReadWrite(request) => request.address,
+        }
+    }
+}
+
+/// Any frame seen on the bus, in either direction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BusFrame {
+    /// Sent by the drive
+    Request(Request),
+    ReadResponse(ReadResponse),
+    WriteResponse(WriteResponse),
+    Exception(ExceptionResponse),
+}
+
+impl BusFrame {
+    /// Decodes a frame from either direction. Requests take precedence where the same
+    /// bytes would also make a valid response.
+    pub fn decode(frame: &[u8]) -> Result<Self, DispatchError> {
+        match frame.get(1) {
+            // A 0x10 request is at least 9 bytes, so the 8-byte echo is unambiguous
+            Some(&FUNC_WRITE_MULTIPLE_REGISTERS) if frame.len() == WriteResponse::FRAME_LEN => {
+                WriteResponse::decode(frame).map(BusFrame::WriteResponse)
+            }
+            Some(&FUNC_READ_WRITE_MULTIPLE_REGISTERS) => Request::decode(frame)
+                .map(BusFrame::Request)
+                .or_else(|e| ReadResponse::decode(frame).map(BusFrame::ReadResponse).map_err(|_| e)),
+            Some(f) if f & EXCEPTION_FLAG != 0 && frame.len() == ExceptionResponse::FRAME_LEN => {
+                ExceptionResponse::decode(frame).map(BusFrame::Exception)
+            }
+            _ => Request::decode(frame).map(BusFrame::Request),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DispatchError> {
+        match self {
+            BusFrame::Request(request) => request.encode(buf),
+            BusFrame::ReadResponse(response) => response.encode(buf),
+            BusFrame::WriteResponse(response) => response.encode(buf),
+            BusFrame::Exception(response) => response.encode(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::{ADDR_POLL, ADDR_STATUS_UPDATE, ADDR_SYNC_COUNTER};
+
+    fn block(start: u16, regs: &[u16]) -> RegisterBlock {
+        RegisterBlock::new(start, regs).unwrap()
+    }
+
+    fn roundtrip(frame: BusFrame) -> usize {
+        let mut buf = [0u8; 64];
+        let len = frame.encode(&mut buf).unwrap();
+        assert_eq!(BusFrame::decode(&buf[..len]), Ok(frame));
+        assert!(crc_valid(&buf[..len]));
+        len
+    }
+
+    #[test]
+    fn test_crc() {
+        let request = [0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02];
+        assert_eq!(crc16(&request), 0x35F8);
+        assert!(crc_valid(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02, 0xF8, 0x35]));
+        assert!(!crc_valid(&[0xF8]));
+    }
+
+    #[test]
+    fn test_roundtrip_every_frame_type() {
+        let broadcast = WriteRequest { address: 0, write: block(ADDR_STATUS_UPDATE, &[0, 0xC8C8, 0x4000, 0, 0, 0, 0x10, 0, 0]) };
+        assert_eq!(roundtrip(BusFrame::Request(Request::Write(broadcast))), 27);
+
+        let scan = ReadWriteRequest { address: 2, read_start: ADDR_POLL, read_qty: 5, write: block(ADDR_SYNC_COUNTER, &[2, 0, 0x0102]) };
+        assert_eq!(roundtrip(BusFrame::Request(Request::ReadWrite(scan))), 19);
+
+        let response = ReadResponse { address: 2, regs: block(0, &[0, 5, 0x0430, 0x10FF, 0xA845]) };
+        assert_eq!(roundtrip(BusFrame::ReadResponse(response)), ReadResponse::frame_len(5));
+
+        let echo = WriteResponse { address: 3, start: ADDR_SYNC_COUNTER, quantity: 1 };
+        assert_eq!(roundtrip(BusFrame::WriteResponse(echo)), WriteResponse::FRAME_LEN);
+
+        let exception = ExceptionResponse { address: 3, function: FUNC_READ_WRITE_MULTIPLE_REGISTERS, code: 2 };
+        assert_eq!(roundtrip(BusFrame::Exception(exception)), ExceptionResponse::FRAME_LEN);
+    }
+
+    #[test]
+    fn test_known_wire_bytes() {
+        // Bus scan from the protocol notes
+        let scan = ReadWriteRequest { address: 2, read_start: ADDR_POLL, read_qty: 5, write: block(ADDR_SYNC_COUNTER, &[2, 0, 0x0102]) };
+        let mut buf = [0u8; 32];
+        let len = scan.encode(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02, 0xF8, 0x35]
+        );
+    }
+
+    #[test]
+    fn test_typed_decode_checks_everything() {
+        let mut buf = [0u8; 32];
+        let echo = WriteResponse { address: 3, start: ADDR_SYNC_COUNTER, quantity: 1 };
+        let len = echo.encode(&mut buf).unwrap();
+
+        assert_eq!(ReadResponse::decode(&buf[..len]), Err(DispatchError::InvalidFunction));
+        assert_eq!(WriteResponse::decode(&buf[..len - 1]), Err(DispatchError::CrcMismatch));
+        assert_eq!(WriteResponse::decode(&buf[..3]), Err(DispatchError::FrameTooShort));
+        buf[3] ^= 1;
+        assert_eq!(WriteResponse::decode(&buf[..len]), Err(DispatchError::CrcMismatch));
+
+        // Byte count says 4 registers, only 3 follow
+        let mut frame = [0x02, 0x17, 0x08, 0, 1, 0, 2, 0, 3, 0, 0];
+        let crc = crc16(&frame[..9]);
+        frame[9..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(ReadResponse::decode(&frame), Err(DispatchError::LengthMismatch));
+    }
+
+    #[test]
+    fn test_encode_checks_buffer() {
+        let broadcast = WriteRequest { address: 0, write: block(ADDR_STATUS_UPDATE, &[0; 9]) };
+        let mut buf = [0u8; 26];
+        assert_eq!(broadcast.encode(&mut buf), Err(DispatchError::BufferTooSmall));
+        assert_eq!(RegisterBlock::new(0, &[0; MAX_REGISTERS + 1]), Err(DispatchError::TooManyRegisters));
+    }
+
+    #[test]
+    fn test_decode_prefers_requests() {
+        // Neither a valid request nor a valid response: the request error is reported
+        let mut frame = [0x03, 0x17, 0x05, 0x00, 0x00, 0x00, 0, 0];
+        let crc = crc16(&frame[..6]);
+        frame[6..].copy_from_slice(&crc.to_le_bytes());
+        assert!(BusFrame::decode(&frame).is_err());
+        assert_eq!(Request::decode(&[0x03, 0x42, 0x00]), Err(DispatchError::FrameTooShort));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::codec::ExceptionCode;
+use crate::framer::DEFAULT_INTER_FRAME_TIMEOUT_MS;
+use crate::link::DEFAULT_SILENCE_TIMEOUT_MS;
+use crate::registers::ADDRESS_HCP;
+
+/// Delay after writing a response before the transceiver is switched back to RX.
+pub const DEFAULT_TX_TURNAROUND_MS: u32 = 2;
+/// How long the "pressing" registers are sent before the single "release".
+pub const DEFAULT_PRESS_DURATION_MS: u32 = 500;
+/// Device identity returned in the bus scan response.
+pub const DEFAULT_SCAN_IDENTITY: [u16; 3] = [0x0430, 0x10FF, 0xA845];
+
+/// Nominal frequency of RC_FAST, the LP core clock, when the HP core has not measured it.
+pub const DEFAULT_LP_CLOCK_HZ: u32 = 17_500_000;
+
+/// How many unicast addresses the bridge can listen on.
+pub const MAX_BUS_ADDRESSES: usize = 4;
+
+/// `Hcp2Config::exception_responses` bit: unknown function codes get exception 01 (illegal function).
+pub const EXCEPTION_ILLEGAL_FUNCTION: u8 = 1 << 0;
+/// Writes or reads of registers we do not serve get exception 02 (illegal data address).
+pub const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 1 << 1;
+/// Polls for a quantity other than 2, 5 or 8 get exception 03 (illegal data value).
+pub const EXCEPTION_ILLEGAL_DATA_VALUE: u8 = 1 << 2;
+
+/// Highest valid Modbus unicast address.
+const MAX_BUS_ADDRESS: u8 = 247;
+
+/// Driver tuning for a particular drive and transceiver.
+///
+/// Fields left at zero use the built-in default, so a zeroed block (e.g. freshly cleared
+/// shared memory) is a valid configuration.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Hcp2Config {
+    /// Bus silence after which a partial frame is dropped
+    pub inter_frame_timeout_ms: u32,
+    /// Delay between writing a response and releasing the bus, if the HAL cannot report TX complete
+    pub tx_turnaround_ms: u32,
+    /// How long a command button is held
+    pub press_duration_ms: u32,
+    /// How long the bus may stay without a valid frame before the drive is reported offline
+    pub silence_timeout_ms: u32,
+    /// Identity words returned in the bus scan response
+    pub scan_identity: [u16; 3],
+    /// Modbus addresses the bridge answers on (1-247), 0 = unused slot
+    pub bus_addresses: [u8; MAX_BUS_ADDRESSES],
+    /// Only watch the bus: never transmit, never execute commands
+    pub listen_only: bool,
+    /// Unicast requests we cannot serve that are answered with a Modbus exception
+    /// (`EXCEPTION_*` bits, bit n-1 for exception code n). 0 = none: unknown functions
+    /// and read addresses are dropped, writes to unknown addresses are echoed and
+    /// unknown poll quantities are answered with zeroed registers.
+    pub exception_responses: u8,
+    /// Frequency of the LP core clock in Hz as calibrated by the HP core, for the LP
+    /// driver's timing. Unused in HP mode.
+    pub lp_clock_hz: u32,
+}
+
+impl Hcp2Config {
+    /// Returns a copy with every unset or out-of-range field replaced by its default.
+    pub fn resolved(&self) -> Self {
+        fn or(val: u32, default: u32) -> u32 {
+            if val == 0 { default } else { val }
+        }
+        Self {
+            inter_frame_timeout_ms: or(self.inter_frame_timeout_ms, DEFAULT_INTER_FRAME_TIMEOUT_MS),
+            tx_turnaround_ms: or(self.tx_turnaround_ms, DEFAULT_TX_TURNAROUND_MS),
+            press_duration_ms: or(self.press_duration_ms, DEFAULT_PRESS_DURATION_MS),
+            silence_timeout_ms: or(self.silence_timeout_ms, DEFAULT_SILENCE_TIMEOUT_MS),
+            scan_identity: if self.scan_identity == [0; 3] { DEFAULT_SCAN_IDENTITY } else { self.scan_identity },
+            bus_addresses: self.resolved_addresses(),
+            listen_only: self.listen_only,
+            exception_responses: self.exception_responses,
+            lp_clock_hz: or(self.lp_clock_hz, DEFAULT_LP_CLOCK_HZ),
+        }
+    }
+
+    fn resolved_addresses(&self) -> [u8; MAX_BUS_ADDRESSES] {
+        let mut addresses = [0u8; MAX_BUS_ADDRESSES];
+        let valid = self.bus_addresses.iter().filter(|a| (1..=MAX_BUS_ADDRESS).contains(*a));
+        for (slot, addr) in addresses.iter_mut().zip(valid) {
+            *slot = *addr;
+        }
+        if addresses[0] == 0 {
+            addresses[0] = ADDRESS_HCP;
+        }
+        addresses
+    }
+
+    /// Whether requests that would need `code` are answered with an exception.
+    pub fn answers_exception(&self, code: ExceptionCode) -> bool {
+        self.exception_responses & (1 << (code as u8 - 1)) != 0
+    }
+
+    /// Whether `address` is one of our unicast addresses. Never true for the broadcast address.
+    pub fn answers_to(&self, address: u8) -> bool {
+        address != 0 && self.bus_addresses.contains(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zeroed_config_resolves_to_defaults() {
+        let config = Hcp2Config::default().resolved();
+        assert_eq!(config.inter_frame_timeout_ms, DEFAULT_INTER_FRAME_TIMEOUT_MS);
+        assert_eq!(config.tx_turnaround_ms, DEFAULT_TX_TURNAROUND_MS);
+        assert_eq!(config.press_duration_ms, DEFAULT_PRESS_DURATION_MS);
+        assert_eq!(config.silence_timeout_ms, DEFAULT_SILENCE_TIMEOUT_MS);
+        assert_eq!(config.scan_identity, DEFAULT_SCAN_IDENTITY);
+        assert_eq!(config.bus_addresses, [ADDRESS_HCP, 0, 0, 0]);
+        assert_eq!(config.lp_clock_hz, DEFAULT_LP_CLOCK_HZ);
+        assert!(config.answers_to(ADDRESS_HCP));
+        assert!(!config.answers_to(0));
+        assert!(!config.answers_exception(ExceptionCode::IllegalFunction));
+    }
+
+    #[test]
+    fn test_exception_bits_match_codes() {
+        let config = Hcp2Config { exception_responses: EXCEPTION_ILLEGAL_DATA_VALUE, ..Default::default() }.resolved();
+        assert!(config.answers_exception(ExceptionCode::IllegalDataValue));
+        assert!(!config.answers_exception(ExceptionCode::IllegalDataAddress));
+        assert!(!config.answers_exception(ExceptionCode::IllegalFunction));
+
+        let config = Hcp2Config { exception_responses: EXCEPTION_ILLEGAL_FUNCTION | EXCEPTION_ILLEGAL_DATA_ADDRESS, ..Default::default() };
+        assert!(config.answers_exception(ExceptionCode::IllegalFunction));
+        assert!(config.answers_exception(ExceptionCode::IllegalDataAddress));
+    }
+
+    #[test]
+    fn test_set_fields_are_kept() {
+        let config = Hcp2Config {
+            inter_frame_timeout_ms: 4,
+            press_duration_ms: 300,
+            bus_addresses: [0x05, 0, 0, 0],
+            ..Default::default()
+        }
+        .resolved();
+        assert_eq!(config.inter_frame_timeout_ms, 4);
+        assert_eq!(config.tx_turnaround_ms, DEFAULT_TX_TURNAROUND_MS);
+        assert_eq!(config.press_duration_ms, 300);
+        assert_eq!(config.bus_addresses, [0x05, 0, 0, 0]);
+        assert!(!config.answers_to(ADDRESS_HCP));
+
+        // Broadcast and reserved addresses fall back to the default
+        for addr in [0x00, 248, 0xFF] {
+            let config = Hcp2Config { bus_addresses: [addr, 0, 0, 0], ..Default::default() }.resolved();
+            assert_eq!(config.bus_addresses, [ADDRESS_HCP, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_multiple_addresses() {
+        let config = Hcp2Config { bus_addresses: [0, 0x03, 0xFF, 0x07], ..Default::default() }.resolved();
+        // Invalid entries are dropped and the rest packed to the front
+        assert_eq!(config.bus_addresses, [0x03, 0x07, 0, 0]);
+        assert!(config.answers_to(0x03));
+        assert!(config.answers_to(0x07));
+        assert!(!config.answers_to(ADDRESS_HCP));
+        assert!(!config.answers_to(0));
+    }
+}
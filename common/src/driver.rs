@@ -1,85 +1,286 @@
+use crate::config::Hcp2Config;
+use crate::framer::{FrameAssembler, FramerError, RTU_INTER_FRAME_TIMEOUT_US};
 use crate::hal::HcpHal;
-use crate::protocol::{Hcp2Protocol, DispatchError};
-use crate::shared::{SharedData, OWNER_LP, OWNER_FREE};
+use crate::link::LinkMonitor;
+use crate::registers::ADDRESS_BROADCAST;
+use crate::codec::{BusFrame, ExceptionCode};
+use crate::protocol::{Hcp2Protocol, DispatchError, FrameKind};
+use crate::shared::{SharedData, HpToLp, LpToHp};
+use crate::stats::{BusStats, StatsCollector};
+use crate::trace::{Direction, NoTrace, TraceSink};
 
 pub struct Hcp2Driver {
+    config: Hcp2Config,
     protocol: Hcp2Protocol,
-    rx_buf: [u8; 128],
-    rx_idx: usize,
-    last_rx_ms: u32,
+    framer: FrameAssembler,
+    /// Inter-frame gap used with a microsecond clock: the configured one, or the RTU gap.
+    rtu_gap_us: u32,
     tx_buf: [u8; 128],
+    /// Last consistent snapshot of the HP -> LP region.
+    request: HpToLp,
+    /// Working copy of the LP -> HP region.
+    status: LpToHp,
+    /// What the HP core currently sees, to skip redundant seqlock writes.
+    published: LpToHp,
+    link: LinkMonitor,
+    stats: StatsCollector,
+    /// Counters the HP core currently sees.
+    published_stats: BusStats,
+}
+
+impl Default for Hcp2Driver {
+    fn default() -> Self {
+        Self::new(Hcp2Config::default())
+    }
 }
 
 impl Hcp2Driver {
-    pub fn new() -> Self {
+    pub fn new(config: Hcp2Config) -> Self {
+        let rtu_gap_us = match config.inter_frame_timeout_ms {
+            0 => RTU_INTER_FRAME_TIMEOUT_US,
+            ms => ms.saturating_mul(1000),
+        };
+        let config = config.resolved();
+        let mut framer = FrameAssembler::new(config.inter_frame_timeout_ms.saturating_mul(1000));
+        if config.answers_exception(ExceptionCode::IllegalFunction) {
+            framer.set_unknown_function_addresses(config.bus_addresses);
+        }
         Self {
-            protocol: Hcp2Protocol::new(),
-            rx_buf: [0u8; 128],
-            rx_idx: 0,
-            last_rx_ms: 0,
+            config,
+            protocol: Hcp2Protocol::new(config),
+            framer,
+            rtu_gap_us,
             tx_buf: [0u8; 128],
+            request: HpToLp::default(),
+            status: LpToHp::default(),
+            published: LpToHp::default(),
+            link: LinkMonitor::new(config.silence_timeout_ms),
+            stats: StatsCollector::default(),
+            published_stats: BusStats::default(),
+        }
+    }
+
+    /// Takes over the shared memory after the driver core was restarted, before the first `poll`.
+    /// Commands queued before the restart are dropped rather than executed late, and the
+    /// published regions are rewritten in case the previous run stopped in the middle of a write.
+    /// Counters start over.
+    pub fn resume(&mut self, shared: &mut SharedData) {
+        if let Some(request) = shared.hp_to_lp.read() {
+            self.request = request;
+            self.status.cmd_tail = request.cmd_head;
         }
+        shared.lp_to_hp.recover();
+        shared.lp_to_hp.write(&self.status);
+        self.published = self.status;
+        shared.stats.recover();
+        shared.stats.write(self.stats.stats());
+        self.published_stats = *self.stats.stats();
     }
 
     /// Runs a single iteration of the main loop.
     /// This should be called repeatedly.
     pub fn poll<H: HcpHal>(&mut self, hal: &mut H, shared: &mut SharedData) {
+        self.poll_with_trace(hal, shared, &mut NoTrace);
+    }
+
+    /// Like `poll`, additionally passing every received and sent frame to `trace`.
+    pub fn poll_with_trace<H: HcpHal, T: TraceSink>(&mut self, hal: &mut H, shared: &mut SharedData, trace: &mut T) {
         let current_ms = hal.now_ms();
+        let (now_us, gap_us) = match hal.now_us() {
+            Some(us) => (us, self.rtu_gap_us),
+            None => (current_ms.wrapping_mul(1000), self.config.inter_frame_timeout_ms.saturating_mul(1000)),
+        };
+        self.framer.set_inter_frame_timeout_us(gap_us);
+        // Taken before reading: if nothing arrives below, the idle line came after
+        // everything the framer holds.
+        let rx_idle = hal.take_rx_idle();
 
-        // 1. Read available bytes
-        let read_count = hal.uart_read(&mut self.rx_buf[self.rx_idx..]);
-        if read_count > 0 {
-            self.rx_idx += read_count;
-            self.last_rx_ms = current_ms;
+        // Read everything the UART has, handling frames as they complete so the
+        // assembler only ever holds a partial frame.
+        let mut chunk = [0u8; 32];
+        let mut received = false;
+        loop {
+            let read_count = hal.uart_read(&mut chunk);
+            received |= read_count > 0;
+            let line_errors = hal.take_line_errors();
+            self.framer.push(&chunk[..read_count], now_us);
+            self.handle_frames(hal, shared, trace, current_ms, now_us);
+            if line_errors != 0 {
+                // Frames completed by these bytes were decided by their CRC; the one still
+                // being received cannot be valid, so drop it now instead of waiting for its
+                // CRC. A noisy line counts towards a CRC storm all the same.
+                self.stats.on_line_errors(line_errors);
+                self.link.on_crc_error();
+                self.framer.discard_frame();
+                hal.log("Error: UART Line Error");
+            }
+            if read_count < chunk.len() {
+                break;
+            }
+        }
+        if rx_idle && !received {
+            self.framer.line_idle();
+            self.handle_frames(hal, shared, trace, current_ms, now_us);
         }
 
-        // 2. Check for frame timeout (Modbus RTU: 3.5 chars, ~2-10ms)
-        // We trigger processing if we have data AND enough time passed since last byte
-        if self.rx_idx > 0 && current_ms.wrapping_sub(self.last_rx_ms) > 10 {
-            
-            // Try to acquire lock (Non-blocking)
-            // Note: On HP core, we might be the only writer if we own the task, 
-            // but we respect the flag in case LP core is also active (unlikely but safe).
-            if shared.read_owner() != 1 { 
-                shared.write_owner(OWNER_LP); // Using OWNER_LP (2) as "Active Driver" ID
-
-                match self.protocol.dispatch_frame(
-                    &self.rx_buf[..self.rx_idx], 
-                    &mut self.tx_buf, 
-                    shared, 
-                    current_ms
-                ) {
-                    Ok(tx_len) => {
-                        if tx_len > 0 {
-                            // Switch to TX
-                            hal.set_tx_enable(true);
-                            hal.uart_write(&self.tx_buf[..tx_len]);
-                            
-                            // Wait for transmission to finish is handled by HAL or caller?
-                            // Usually blocking write is simplest.
-                            // We add a small safety delay to ensure UART FIFO is empty before dropping DE
-                            hal.sleep_ms(2); 
-                            
-                            // Switch back to RX
-                            hal.set_tx_enable(false);
+        self.link.tick(current_ms);
+        self.status.link_online = self.link.is_online();
+        self.status.error_code = self.link.error() as u8;
+
+        // We are the only writer of this region, so publishing never waits on the HP core.
+        if self.status != self.published {
+            shared.lp_to_hp.write(&self.status);
+            self.published = self.status;
+        }
+        if *self.stats.stats() != self.published_stats {
+            shared.stats.write(self.stats.stats());
+            self.published_stats = *self.stats.stats();
+        }
+    }
+
+    /// Handles every frame that is complete. Frames are split by length and CRC,
+    /// so a broadcast directly followed by a poll is processed as two frames.
+    fn handle_frames<H: HcpHal, T: TraceSink>(
+        &mut self,
+        hal: &mut H,
+        shared: &SharedData,
+        trace: &mut T,
+        current_ms: u32,
+        now_us: u32,
+    ) {
+        while let Some(next) = self.framer.next_frame(now_us) {
+            let frame = match next {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.stats.on_framer_error(e);
+                    trace.on_framer_error(current_ms, self.framer.rejected(), e);
+                    match e {
+                        FramerError::Garbage => {
+                            self.link.on_crc_error();
+                            hal.log("Error: Skipped bytes outside a valid frame");
                         }
-                    },
-                    Err(e) => {
-                        match e {
-                            DispatchError::CrcMismatch => hal.log("Error: CRC Mismatch"),
-                            DispatchError::InvalidAddress => hal.log("Debug: Discarding message - Invalid Address"),
-                            DispatchError::FrameTooShort => hal.log("Error: Frame Too Short"),
-                            DispatchError::InvalidFunction => hal.log("Debug: Invalid Function Code"),
-                            DispatchError::ParsingError => hal.log("Error: Frame Parsing Failed"),
+                        FramerError::CrcMismatch => {
+                            self.link.on_crc_error();
+                            hal.log("Error: CRC Mismatch");
                         }
+                        FramerError::Truncated => hal.log("Error: Truncated Frame"),
+                        FramerError::Overflow => hal.log("Error: RX Buffer Overflow"),
                     }
+                    continue;
                 }
+            };
 
-                shared.write_owner(OWNER_FREE);
+            // Pick up new commands. If the HP core is mid-write we keep the previous
+            // snapshot and see the update on the next frame instead of waiting.
+            if let Some(request) = shared.hp_to_lp.read() {
+                self.request = request;
             }
+            self.status.mark_pending(&self.request);
 
-            // Reset buffer after processing attempt
-            self.rx_idx = 0;
+            let decoded = BusFrame::decode(frame);
+            self.stats.on_frame();
+            if frame[0] == ADDRESS_BROADCAST {
+                self.stats.on_broadcast();
+            }
+
+            // Listen-only: frames between the drive and other devices are only observed,
+            // and the transceiver is never switched to TX.
+            if self.config.listen_only {
+                trace.on_frame(current_ms, Direction::Rx, frame, decoded.as_ref().map_err(|e| *e));
+                match decoded {
+                    Ok(decoded) => {
+                        self.protocol.observe_frame(&decoded, &self.request, &mut self.status);
+                        self.status.last_update_ts = current_ms;
+                        self.link.on_valid_frame(current_ms);
+                    }
+                    Err(e) => {
+                        self.stats.on_error(e);
+                        log_dispatch_error(hal, e);
+                    }
+                }
+                continue;
+            }
+
+            let result = self.protocol.dispatch_frame(
+                frame,
+                &mut self.tx_buf,
+                &self.request,
+                &mut self.status,
+                current_ms
+            );
+            let traced = match result {
+                Ok(_) => decoded.as_ref().map_err(|e| *e),
+                Err(e) => Err(e),
+            };
+            trace.on_frame(current_ms, Direction::Rx, frame, traced);
+
+            match result {
+                Ok(tx_len) => {
+                    self.status.last_update_ts = current_ms;
+                    self.link.on_valid_frame(current_ms);
+                    let kind = self.protocol.last_frame_kind();
+                    match kind {
+                        FrameKind::BusScan => self.link.on_bus_scan(),
+                        FrameKind::IdlePoll | FrameKind::ActionPoll => self.link.on_poll(),
+                        _ => {}
+                    }
+                    self.stats.on_accepted(kind, current_ms);
+                    self.stats.on_sync_jumps(self.protocol.sync_jumps());
+
+                    if tx_len > 0 {
+                        let response = &self.tx_buf[..tx_len];
+                        trace.on_frame(current_ms, Direction::Tx, response, BusFrame::decode(response).as_ref().map_err(|e| *e));
+
+                        // Switch to TX
+                        hal.set_tx_enable(true);
+                        hal.uart_write(&self.tx_buf[..tx_len]);
+
+                        // Release the bus as soon as the last stop bit is out. Without that
+                        // signal, give the UART FIFO time to drain before dropping DE.
+                        if !hal.wait_tx_complete() {
+                            hal.sleep_ms(self.config.tx_turnaround_ms);
+                        }
+
+                        // Switch back to RX
+                        hal.set_tx_enable(false);
+                        self.stats.on_response();
+                    }
+                },
+                Err(e) => {
+                    self.stats.on_error(e);
+                    log_dispatch_error(hal, e);
+                }
+            }
         }
     }
+
+    /// Gap after which the buffered partial frame is dropped, or None if nothing is buffered.
+    #[cfg(feature = "async")]
+    pub(crate) fn pending_gap_us(&self) -> Option<u32> {
+        (self.framer.buffered() > 0).then(|| self.framer.inter_frame_timeout_us())
+    }
+
+    /// Latest status as published to the HP core.
+    pub fn status(&self) -> &LpToHp {
+        &self.status
+    }
+
+    /// Bus counters as published to the HP core.
+    pub fn stats(&self) -> &BusStats {
+        self.stats.stats()
+    }
+}
+
+fn log_dispatch_error<H: HcpHal>(hal: &mut H, e: DispatchError) {
+    match e {
+        DispatchError::CrcMismatch => hal.log("Error: CRC Mismatch"),
+        DispatchError::InvalidAddress => hal.log("Debug: Discarding message - Invalid Address"),
+        DispatchError::FrameTooShort => hal.log("Error: Frame Too Short"),
+        DispatchError::InvalidFunction => hal.log("Debug: Invalid Function Code"),
+        DispatchError::QuantityMismatch => hal.log("Error: Quantity/Byte Count Mismatch"),
+        DispatchError::OddByteCount => hal.log("Error: Odd Byte Count"),
+        DispatchError::TooManyRegisters => hal.log("Error: Too Many Registers"),
+        DispatchError::LengthMismatch => hal.log("Error: Frame Length Mismatch"),
+        DispatchError::BufferTooSmall => hal.log("Error: Response Buffer Too Small"),
+    }
 }
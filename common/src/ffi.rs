@@ -0,0 +1,162 @@
+//! Helpers for the handle-based C APIs of the firmware libraries.
+//!
+//! An instance lives in storage provided by the caller (no heap, any number of instances).
+//! The handle is the storage pointer itself. A tag word in front of the instance lets every
+//! entry point reject null, destroyed, never-created or foreign handles with an error code
+//! instead of panicking.
+
+use core::mem::{align_of, size_of};
+
+/// Bytes to reserve for one `hcp_hp_create` instance. The exact requirement depends on the
+/// target and is checked at runtime (`FfiResult::StorageTooSmall`).
+pub const HP_DRIVER_STORAGE_SIZE: usize = 2048;
+/// Bytes to reserve for one `hcp_tester_create` instance.
+pub const TESTER_STORAGE_SIZE: usize = 128;
+/// Alignment the storage must have.
+pub const FFI_STORAGE_ALIGN: usize = 8;
+
+/// Result code of the handle-based C APIs. Negative values are errors.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfiResult {
+    Ok = 0,
+    /// A required pointer argument was null.
+    NullPointer = -1,
+    /// The handle was never created, already destroyed, or belongs to another API.
+    InvalidHandle = -2,
+    /// The storage passed to `create` is smaller than the instance.
+    StorageTooSmall = -3,
+    /// The storage passed to `create` is not aligned to `FFI_STORAGE_ALIGN`.
+    StorageMisaligned = -4,
+}
+
+impl From<Result<(), FfiResult>> for FfiResult {
+    fn from(result: Result<(), FfiResult>) -> Self {
+        result.err().unwrap_or(FfiResult::Ok)
+    }
+}
+
+/// A type that can be placed in caller storage.
+pub trait FfiObject: Sized {
+    /// Tag of a live instance; distinct per type so handles cannot be mixed up.
+    const TAG: u32;
+}
+
+/// Layout of the storage behind a handle.
+#[repr(C)]
+struct Tagged<T> {
+    tag: u32,
+    value: T,
+}
+
+/// Moves `value` into `storage` (`len` bytes) and returns the handle.
+///
+/// # Safety
+/// `storage` must be null or valid for writes of `len` bytes, and must not be used for
+/// anything else until `destroy` was called.
+pub unsafe fn create<T: FfiObject>(storage: *mut u8, len: usize, value: T) -> Result<*mut u8, FfiResult> {
+    if storage.is_null() {
+        return Err(FfiResult::NullPointer);
+    }
+    if len < size_of::<Tagged<T>>() {
+        return Err(FfiResult::StorageTooSmall);
+    }
+    if !(storage as usize).is_multiple_of(align_of::<Tagged<T>>()) {
+        return Err(FfiResult::StorageMisaligned);
+    }
+    let slot = storage as *mut Tagged<T>;
+    slot.write(Tagged { tag: T::TAG, value });
+    Ok(storage)
+}
+
+/// The instance behind `handle`.
+///
+/// # Safety
+/// `handle` must be null or point to readable storage of at least 4 bytes, aligned for `T`
+/// if it holds a `T`. The returned reference must not outlive the instance or alias another one.
+pub unsafe fn get<'a, T: FfiObject>(handle: *mut u8) -> Result<&'a mut T, FfiResult> {
+    get_ref::<T>(handle)?;
+    Ok(&mut (*(handle as *mut Tagged<T>)).value)
+}
+
+/// Shared access to the instance behind `handle`, for entry points that only read it.
+///
+/// # Safety
+/// Same as `get`. No `&mut` from `get` may be live at the same time, so the caller must
+/// serialize such entry points with the ones that modify the instance (e.g. `hcp_hp_poll`).
+pub unsafe fn get_ref<'a, T: FfiObject>(handle: *const u8) -> Result<&'a T, FfiResult> {
+    if handle.is_null() {
+        return Err(FfiResult::NullPointer);
+    }
+    let slot = handle as *const Tagged<T>;
+    if core::ptr::read_volatile(core::ptr::addr_of!((*slot).tag)) != T::TAG {
+        return Err(FfiResult::InvalidHandle);
+    }
+    Ok(&(*slot).value)
+}
+
+/// Drops the instance behind `handle`; the storage may be reused afterwards.
+///
+/// # Safety
+/// Same as `get`, and no reference from `get` may be alive.
+pub unsafe fn destroy<T: FfiObject>(handle: *mut u8) -> Result<(), FfiResult> {
+    get::<T>(handle)?;
+    let slot = handle as *mut Tagged<T>;
+    core::ptr::write_volatile(core::ptr::addr_of_mut!((*slot).tag), 0);
+    core::ptr::drop_in_place(core::ptr::addr_of_mut!((*slot).value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(8))]
+    struct Storage([u8; 64]);
+
+    struct Counter(u32);
+    impl FfiObject for Counter {
+        const TAG: u32 = 0x1111_0001;
+    }
+
+    struct Other(u32);
+    impl FfiObject for Other {
+        const TAG: u32 = 0x1111_0002;
+    }
+
+    #[test]
+    fn test_create_get_destroy() {
+        let mut storage = Storage([0; 64]);
+        let handle = unsafe { create(storage.0.as_mut_ptr(), 64, Counter(7)) }.unwrap();
+        unsafe {
+            get::<Counter>(handle).unwrap().0 += 1;
+            assert_eq!(get_ref::<Counter>(handle).unwrap().0, 8);
+
+            assert_eq!(destroy::<Counter>(handle), Ok(()));
+            assert_eq!(get::<Counter>(handle).err(), Some(FfiResult::InvalidHandle));
+            assert_eq!(destroy::<Counter>(handle), Err(FfiResult::InvalidHandle));
+        }
+    }
+
+    #[test]
+    fn test_misuse_is_reported() {
+        let mut storage = Storage([0; 64]);
+        let base = storage.0.as_mut_ptr();
+        unsafe {
+            assert_eq!(create(core::ptr::null_mut(), 64, Counter(0)), Err(FfiResult::NullPointer));
+            assert_eq!(create(base, 4, Counter(0)), Err(FfiResult::StorageTooSmall));
+            assert_eq!(create(base.add(1), 32, Counter(0)), Err(FfiResult::StorageMisaligned));
+
+            // Never created
+            assert_eq!(get::<Counter>(base).err(), Some(FfiResult::InvalidHandle));
+            assert_eq!(get::<Counter>(core::ptr::null_mut()).err(), Some(FfiResult::NullPointer));
+
+            // Handle of another type
+            let handle = create(base, 64, Other(1)).unwrap();
+            assert_eq!(get::<Counter>(handle).err(), Some(FfiResult::InvalidHandle));
+            assert_eq!(get_ref::<Other>(handle).unwrap().0, 1);
+        }
+        assert_eq!(FfiResult::from(Err(FfiResult::NullPointer)), FfiResult::NullPointer);
+        assert_eq!(FfiResult::from(Ok(())), FfiResult::Ok);
+    }
+}
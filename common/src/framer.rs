@@ -0,0 +1,529 @@
+use crate::codec::crc_valid;
+use crate::config::MAX_BUS_ADDRESSES;
+use crate::registers::{FUNC_READ_WRITE_MULTIPLE_REGISTERS, FUNC_WRITE_MULTIPLE_REGISTERS};
+
+/// Largest frame the assembler can hold. The longest HCP2 frame (status broadcast) is 27 bytes.
+pub const MAX_FRAME_LEN: usize = 128;
+
+/// Bus silence after which a partial frame is dropped when only a millisecond clock is
+/// available. Generous, because bytes are only timestamped when the driver reads them.
+pub const DEFAULT_INTER_FRAME_TIMEOUT_MS: u32 = 10;
+/// Modbus RTU inter-frame gap for baud rates above 19200 (fixed 1.75 ms instead of 3.5
+/// characters), used when the HAL has a microsecond clock.
+pub const RTU_INTER_FRAME_TIMEOUT_US: u32 = 1750;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FramerError {
+    /// Bytes that did not start a valid frame were skipped to resynchronise.
+    Garbage,
+    /// A complete frame with a known function code failed its CRC and was skipped.
+    CrcMismatch,
+    /// The bus went quiet in the middle of a frame.
+    Truncated,
+    /// The buffer filled up without a complete frame and was dropped.
+    Overflow,
+}
+
+/// Possible length of a frame for a given function code.
+#[derive(Clone, Copy)]
+enum FrameLength {
+    Fixed(usize),
+    /// Byte count at `index`, plus `overhead` bytes of header and CRC.
+    Counted { index: usize, overhead: usize },
+}
+
+enum Scan {
+    Complete(usize),
+    Incomplete,
+    /// No candidate fits; `crc_len` is the longest one that was complete but had a bad CRC.
+    Invalid { crc_len: Option<usize> },
+}
+
+/// Requests and responses share function codes, so each code has several candidate
+/// lengths. The CRC decides which one the bytes actually are.
+fn candidates(func: u8) -> &'static [FrameLength] {
+    const EXCEPTION: u8 = 0x80;
+    match func {
+        // Response (echo of start + quantity) or request
+        FUNC_WRITE_MULTIPLE_REGISTERS => &[FrameLength::Fixed(8), FrameLength::Counted { index: 6, overhead: 9 }],
+        // Response or request
+        FUNC_READ_WRITE_MULTIPLE_REGISTERS => &[
+            FrameLength::Counted { index: 2, overhead: 5 },
+            FrameLength::Counted { index: 10, overhead: 13 },
+        ],
+        f if f == FUNC_WRITE_MULTIPLE_REGISTERS | EXCEPTION || f == FUNC_READ_WRITE_MULTIPLE_REGISTERS | EXCEPTION => {
+            &[FrameLength::Fixed(5)]
+        }
+        _ => &[],
+    }
+}
+
+/// Lengths tried for function codes the drive does not use, so a request we answer with an
+/// illegal function exception is still cut out: the request without data (e.g. 0x07, 0x11)
+/// and the fixed-size reads and single writes (0x01 to 0x06).
+const UNKNOWN_FUNCTION_LENGTHS: &[FrameLength] = &[FrameLength::Fixed(4), FrameLength::Fixed(8)];
+
+/// Looks for a complete frame at the start of `buf`. Unknown function codes are only tried
+/// for requests to `unknown_function_addresses` (0 = unused entry).
+fn scan(buf: &[u8], unknown_function_addresses: &[u8]) -> Scan {
+    let Some(&func) = buf.get(1) else {
+        return Scan::Incomplete;
+    };
+
+    // A failed CRC only says something about frames whose function code we know;
+    // for the others it is far more likely the bytes are not a frame at all.
+    let (candidates, known) = match candidates(func) {
+        [] if buf[0] != 0 && unknown_function_addresses.contains(&buf[0]) => (UNKNOWN_FUNCTION_LENGTHS, false),
+        [] => return Scan::Invalid { crc_len: None },
+        list => (list, true),
+    };
+    let mut waiting = false;
+    let mut crc_len = None;
+    for candidate in candidates {
+        let len = match *candidate {
+            FrameLength::Fixed(len) => len,
+            FrameLength::Counted { index, overhead } => match buf.get(index) {
+                Some(&count) => overhead + count as usize,
+                None => {
+                    waiting = true;
+                    continue;
+                }
+            },
+        };
+        if len > MAX_FRAME_LEN {
+            continue;
+        }
+        if len > buf.len() {
+            waiting = true;
+        } else if crc_valid(&buf[..len]) {
+            return Scan::Complete(len);
+        } else if known {
+            crc_len = crc_len.max(Some(len));
+        }
+    }
+
+    if waiting { Scan::Incomplete } else { Scan::Invalid { crc_len } }
+}
+
+/// Splits a Modbus RTU byte stream into frames.
+///
+/// Frames are cut as soon as their length (derived from the function code) is
+/// reached and the CRC matches, so back-to-back frames without a gap are separated.
+/// Bytes that cannot start a frame are skipped one at a time until the stream lines up again.
+pub struct FrameAssembler {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// Length of the frame handed out by the last `next_frame`, removed on the next call.
+    consumed: usize,
+    /// Length of the bytes the error returned by the last `next_frame` refers to.
+    rejected: usize,
+    last_byte_us: u32,
+    inter_frame_timeout_us: u32,
+    /// The UART reported an idle line after the last pushed byte.
+    line_idle: bool,
+    overflowed: bool,
+    /// Already reported the current run of skipped bytes (or it needs no report).
+    resyncing: bool,
+    /// See `set_unknown_function_addresses`.
+    unknown_function_addresses: [u8; MAX_BUS_ADDRESSES],
+}
+
+impl Default for FrameAssembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTER_FRAME_TIMEOUT_MS * 1000)
+    }
+}
+
+impl FrameAssembler {
+    /// All timestamps passed in are microseconds; only differences are used, so they may wrap.
+    pub fn new(inter_frame_timeout_us: u32) -> Self {
+        Self {
+            buf: [0u8; MAX_FRAME_LEN],
+            len: 0,
+            consumed: 0,
+            rejected: 0,
+            last_byte_us: 0,
+            inter_frame_timeout_us,
+            line_idle: false,
+            overflowed: false,
+            resyncing: false,
+            unknown_function_addresses: [0; MAX_BUS_ADDRESSES],
+        }
+    }
+
+    pub fn set_inter_frame_timeout_us(&mut self, us: u32) {
+        self.inter_frame_timeout_us = us;
+    }
+
+    pub fn inter_frame_timeout_us(&self) -> u32 {
+        self.inter_frame_timeout_us
+    }
+
+    /// Requests to these addresses (0 = unused slot) with a function code the drive does not
+    /// use are cut out by their CRC, so they can be answered with an illegal function
+    /// exception. Anywhere else such a function code is skipped as garbage right away.
+    pub fn set_unknown_function_addresses(&mut self, addresses: [u8; MAX_BUS_ADDRESSES]) {
+        self.unknown_function_addresses = addresses;
+    }
+
+    /// The UART detected an idle line: the bytes pushed so far are all the current frame
+    /// gets, without waiting for the inter-frame timeout. Cleared by the next `push`.
+    pub fn line_idle(&mut self) {
+        self.line_idle = true;
+    }
+
+    /// Drops the frame in progress, known to be corrupt (e.g. a UART line error). Its
+    /// remaining bytes are skipped without being reported, up to the next frame that lines up
+    /// or the next bus silence. Frames already handed out by `next_frame` are not affected.
+    pub fn discard_frame(&mut self) {
+        if self.buffered() == 0 {
+            return;
+        }
+        self.len = 0;
+        self.consumed = 0;
+        self.rejected = 0;
+        self.resyncing = true;
+    }
+
+    /// Bytes the error returned by the last `next_frame` refers to: the frame that failed its
+    /// CRC, or everything buffered from the first skipped byte on. Empty after `Overflow` and
+    /// once more bytes are pushed.
+    pub fn rejected(&self) -> &[u8] {
+        &self.buf[..self.rejected]
+    }
+
+    /// Number of bytes waiting for the rest of their frame.
+    pub fn buffered(&self) -> usize {
+        self.len - self.consumed
+    }
+
+    fn drop_front(&mut self, count: usize) {
+        self.buf.copy_within(count..self.len, 0);
+        self.len -= count;
+    }
+
+    fn is_silent(&self, now_us: u32) -> bool {
+        self.line_idle || now_us.wrapping_sub(self.last_byte_us) > self.inter_frame_timeout_us
+    }
+
+    /// Appends received bytes. If the buffer is full the pending bytes are dropped
+    /// and the next `next_frame` reports `Overflow`.
+    pub fn push(&mut self, data: &[u8], now_us: u32) {
+        if data.is_empty() {
+            return;
+        }
+        let consumed = core::mem::take(&mut self.consumed);
+        self.drop_front(consumed);
+        self.rejected = 0;
+        if self.is_silent(now_us) {
+            self.resyncing = false;
+        }
+        self.last_byte_us = now_us;
+        self.line_idle = false;
+
+        for &byte in data {
+            if self.len == MAX_FRAME_LEN {
+                self.len = 0;
+                self.overflowed = true;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Returns the next complete frame (including CRC), an error, or None if more bytes are needed.
+    /// Call repeatedly until it returns None.
+    pub fn next_frame(&mut self, now_us: u32) -> Option<Result<&[u8], FramerError>> {
+        let consumed = core::mem::take(&mut self.consumed);
+        self.drop_front(consumed);
+        self.rejected = 0;
+
+        if self.overflowed {
+            self.overflowed = false;
+            return Some(Err(FramerError::Overflow));
+        }
+
+        while self.len > 0 {
+            match scan(&self.buf[..self.len], &self.unknown_function_addresses) {
+                Scan::Complete(len) => {
+                    self.consumed = len;
+                    self.resyncing = false;
+                    return Some(Ok(&self.buf[..len]));
+                }
+                Scan::Incomplete => {
+                    if !self.is_silent(now_us) {
+                        return None;
+                    }
+                    // Nothing more is coming, so the head can never complete. It may still
+                    // hide a whole frame behind a false start, so skip a single byte.
+                    if !self.resyncing {
+                        return Some(Err(self.reject(self.len, FramerError::Truncated)));
+                    }
+                    self.drop_front(1);
+                }
+                Scan::Invalid { crc_len } => {
+                    if !self.resyncing {
+                        return Some(Err(match crc_len {
+                            Some(len) => self.reject(len, FramerError::CrcMismatch),
+                            None => self.reject(self.len, FramerError::Garbage),
+                        }));
+                    }
+                    self.drop_front(1);
+                }
+            }
+        }
+        None
+    }
+
+    /// Starts a run of skipped bytes with `error`, keeping the first `len` bytes for
+    /// `rejected` until the next call. Only the first byte is skipped.
+    fn reject(&mut self, len: usize, error: FramerError) -> FramerError {
+        self.resyncing = true;
+        self.consumed = 1;
+        self.rejected = len;
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::crc16;
+
+    const BUS_SCAN: [u8; 19] = [
+        0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03,
+        0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02, 0xF8, 0x35,
+    ];
+
+    fn with_crc(body: &[u8], out: &mut [u8]) -> usize {
+        out[..body.len()].copy_from_slice(body);
+        let crc = crc16(body);
+        out[body.len()] = (crc & 0xFF) as u8;
+        out[body.len() + 1] = (crc >> 8) as u8;
+        body.len() + 2
+    }
+
+    fn broadcast(out: &mut [u8]) -> usize {
+        let mut body = [0u8; 25];
+        body[..7].copy_from_slice(&[0x00, 0x10, 0x9D, 0x31, 0x00, 0x09, 0x12]);
+        body[7] = 0x64;
+        with_crc(&body, out)
+    }
+
+    #[test]
+    fn test_back_to_back_frames() {
+        let mut stream = [0u8; 64];
+        let len = broadcast(&mut stream);
+        stream[len..len + BUS_SCAN.len()].copy_from_slice(&BUS_SCAN);
+
+        let mut framer = FrameAssembler::default();
+        framer.push(&stream[..len + BUS_SCAN.len()], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&stream[..len])));
+        assert_eq!(framer.next_frame(0), Some(Ok(&BUS_SCAN[..])));
+        assert_eq!(framer.next_frame(0), None);
+        assert_eq!(framer.buffered(), 0);
+    }
+
+    #[test]
+    fn test_byte_by_byte_without_silence() {
+        let mut framer = FrameAssembler::default();
+        for (i, byte) in BUS_SCAN.iter().enumerate() {
+            framer.push(&[*byte], 0);
+            let frame = framer.next_frame(0);
+            if i + 1 < BUS_SCAN.len() {
+                assert_eq!(frame, None);
+            } else {
+                assert_eq!(frame, Some(Ok(&BUS_SCAN[..])));
+            }
+        }
+    }
+
+    #[test]
+    fn test_resync_after_garbage() {
+        let mut stream = [0u8; 32];
+        stream[..4].copy_from_slice(&[0xFF, 0x42, 0x03, 0x00]);
+        stream[4..4 + BUS_SCAN.len()].copy_from_slice(&BUS_SCAN);
+        let mut framer = FrameAssembler::default();
+        framer.push(&stream[..4 + BUS_SCAN.len()], 0);
+
+        // Reported once for the whole run of skipped bytes
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::Garbage)));
+        assert_eq!(framer.next_frame(0), Some(Ok(&BUS_SCAN[..])));
+        assert_eq!(framer.next_frame(0), None);
+    }
+
+    #[test]
+    fn test_corrupted_frame_does_not_swallow_next() {
+        let mut stream = [0u8; 64];
+        let len = broadcast(&mut stream);
+        stream[9] ^= 0x01;
+        stream[len..len + BUS_SCAN.len()].copy_from_slice(&BUS_SCAN);
+
+        let mut framer = FrameAssembler::default();
+        framer.push(&stream[..len + BUS_SCAN.len()], 0);
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::CrcMismatch)));
+        assert_eq!(framer.rejected(), &stream[..len]);
+        assert_eq!(framer.next_frame(0), Some(Ok(&BUS_SCAN[..])));
+        assert_eq!(framer.rejected(), &[]);
+    }
+
+    #[test]
+    fn test_truncated_frame_dropped_after_silence() {
+        let mut framer = FrameAssembler::new(10);
+        framer.push(&BUS_SCAN[..12], 0);
+        assert_eq!(framer.next_frame(10), None);
+        assert_eq!(framer.next_frame(11), Some(Err(FramerError::Truncated)));
+        assert_eq!(framer.next_frame(11), None);
+        assert_eq!(framer.buffered(), 0);
+
+        framer.push(&BUS_SCAN, 20);
+        assert_eq!(framer.next_frame(20), Some(Ok(&BUS_SCAN[..])));
+    }
+
+    #[test]
+    fn test_idle_line_ends_frame_early() {
+        let mut framer = FrameAssembler::new(RTU_INTER_FRAME_TIMEOUT_US);
+        framer.push(&BUS_SCAN[..12], 0);
+        assert_eq!(framer.next_frame(100), None);
+
+        framer.line_idle();
+        assert_eq!(framer.next_frame(100), Some(Err(FramerError::Truncated)));
+        while framer.next_frame(100).is_some() {}
+        assert_eq!(framer.buffered(), 0);
+
+        // New bytes clear the idle state
+        framer.push(&BUS_SCAN[..5], 200);
+        assert_eq!(framer.next_frame(200), None);
+        framer.push(&BUS_SCAN[5..], 400);
+        assert_eq!(framer.next_frame(400), Some(Ok(&BUS_SCAN[..])));
+    }
+
+    #[test]
+    fn test_discarded_frame_skipped_silently() {
+        let mut stream = [0u8; 64];
+        let len = broadcast(&mut stream);
+        let mut framer = FrameAssembler::new(10);
+        framer.push(&stream[..6], 0);
+        assert_eq!(framer.next_frame(0), None);
+        framer.discard_frame();
+
+        // The tail of the damaged frame is skipped without a report, and the frame
+        // right behind it is still taken
+        framer.push(&stream[6..len], 2);
+        assert_eq!(framer.next_frame(2), None);
+        framer.push(&BUS_SCAN, 3);
+        assert_eq!(framer.next_frame(3), Some(Ok(&BUS_SCAN[..])));
+        assert_eq!(framer.next_frame(3), None);
+
+        // With no frame in progress nothing is skipped
+        framer.push(&BUS_SCAN, 50);
+        assert_eq!(framer.next_frame(50), Some(Ok(&BUS_SCAN[..])));
+        framer.discard_frame();
+        framer.push(&BUS_SCAN, 51);
+        assert_eq!(framer.next_frame(51), Some(Ok(&BUS_SCAN[..])));
+    }
+
+    #[test]
+    fn test_false_start_resolved_at_silence() {
+        // 0x17 followed by a large byte count looks like the start of a long request
+        let mut stream = [0u8; 32];
+        stream[..3].copy_from_slice(&[0x42, 0x17, 0x40]);
+        stream[3..3 + BUS_SCAN.len()].copy_from_slice(&BUS_SCAN);
+        let mut framer = FrameAssembler::new(10);
+        framer.push(&stream[..3 + BUS_SCAN.len()], 0);
+        assert_eq!(framer.next_frame(5), None);
+
+        assert_eq!(framer.next_frame(11), Some(Err(FramerError::Truncated)));
+        assert_eq!(framer.next_frame(11), Some(Ok(&BUS_SCAN[..])));
+    }
+
+    #[test]
+    fn test_overflow_reported() {
+        let mut framer = FrameAssembler::new(10);
+        // A request header claiming 0x70 data bytes keeps the framer waiting
+        let mut header = [0u8; 120];
+        header[..11].copy_from_slice(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x02, 0x9C, 0x41, 0x00, 0x38, 0x70]);
+        framer.push(&header, 0);
+        assert_eq!(framer.next_frame(0), None);
+
+        framer.push(&BUS_SCAN, 0);
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::Overflow)));
+        // The tail of the dropped bytes is cleared once the bus goes quiet
+        while framer.next_frame(11).is_some() {}
+        assert_eq!(framer.buffered(), 0);
+
+        framer.push(&BUS_SCAN, 20);
+        assert_eq!(framer.next_frame(20), Some(Ok(&BUS_SCAN[..])));
+    }
+
+    #[test]
+    fn test_responses_and_exceptions() {
+        let mut buf = [0u8; 32];
+        let mut framer = FrameAssembler::default();
+
+        // 0x17 response with 2 registers
+        let len = with_crc(&[0x02, 0x17, 0x04, 0x00, 0x00, 0x00, 0x00], &mut buf);
+        framer.push(&buf[..len], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&buf[..len])));
+
+        // 0x10 response (start address + quantity)
+        let len = with_crc(&[0x02, 0x10, 0x9C, 0x41, 0x00, 0x03], &mut buf);
+        framer.push(&buf[..len], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&buf[..len])));
+
+        // Exception response
+        let len = with_crc(&[0x02, 0x97, 0x02], &mut buf);
+        framer.push(&buf[..len], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&buf[..len])));
+    }
+
+    #[test]
+    fn test_unknown_function_checked_by_crc() {
+        let mut stream = [0u8; 32];
+        let mut framer = FrameAssembler::default();
+        framer.set_unknown_function_addresses([0x02, 0, 0, 0]);
+
+        // Read holding registers, followed directly by a bus scan
+        let len = with_crc(&[0x02, 0x03, 0x9C, 0xB9, 0x00, 0x02], &mut stream);
+        stream[len..len + BUS_SCAN.len()].copy_from_slice(&BUS_SCAN);
+        framer.push(&stream[..len + BUS_SCAN.len()], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&stream[..len])));
+        assert_eq!(framer.next_frame(0), Some(Ok(&BUS_SCAN[..])));
+
+        // Report slave ID, without data
+        let len = with_crc(&[0x02, 0x11], &mut stream);
+        framer.push(&stream[..len], 0);
+        assert_eq!(framer.next_frame(0), Some(Ok(&stream[..len])));
+
+        // A bad CRC on an unknown code is not counted as a corrupted frame
+        stream[..6].copy_from_slice(&[0x02, 0x03, 0x9C, 0xB9, 0x00, 0x02]);
+        stream[6..8].copy_from_slice(&[0x00, 0x00]);
+        framer.push(&stream[..8], 0);
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::Garbage)));
+    }
+
+    #[test]
+    fn test_unknown_function_elsewhere_is_garbage() {
+        let mut stream = [0u8; 32];
+        let mut framer = FrameAssembler::default();
+        framer.set_unknown_function_addresses([0x02, 0, 0, 0]);
+
+        // Noise with an unknown-looking second byte is skipped without waiting for more bytes
+        framer.push(&[0x42, 0x03, 0x9C], 0);
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::Garbage)));
+        assert_eq!(framer.rejected(), &[0x42, 0x03, 0x9C]);
+        assert_eq!(framer.next_frame(0), None);
+        assert_eq!(framer.next_frame(20_000), None);
+
+        // Requests to other addresses are not cut out either
+        let len = with_crc(&[0x05, 0x03, 0x9C, 0xB9, 0x00, 0x02], &mut stream);
+        framer.push(&stream[..len], 40_000);
+        assert_eq!(framer.next_frame(40_000), Some(Err(FramerError::Garbage)));
+
+        // Nor anything while no address is set
+        let mut framer = FrameAssembler::default();
+        let len = with_crc(&[0x02, 0x11], &mut stream);
+        framer.push(&stream[..len], 0);
+        assert_eq!(framer.next_frame(0), Some(Err(FramerError::Garbage)));
+    }
+}
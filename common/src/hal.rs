@@ -1,6 +1,16 @@
+/// `HcpHal::take_line_errors` bit: a byte had the wrong parity bit.
+pub const LINE_ERROR_PARITY: u8 = 1 << 0;
+/// A byte had no valid stop bit (noise, wrong baud rate, or a break).
+pub const LINE_ERROR_FRAMING: u8 = 1 << 1;
+/// Bytes were lost because the receive FIFO was full.
+pub const LINE_ERROR_OVERRUN: u8 = 1 << 2;
+
 /// The Hardware Abstraction Layer trait required by the HCP2 driver.
-/// This allows the same logic to run on LP core (using esp-lp-hal) 
+/// This allows the same logic to run on LP core (using esp-lp-hal)
 /// and HP core (using C function pointers).
+///
+/// The provided methods expose finer bus timing. Their defaults fall back to what
+/// the required methods allow, so a HAL only implements those its hardware supports.
 pub trait HcpHal {
     /// Read bytes from UART into buffer. Returns number of bytes read.
     fn uart_read(&mut self, buf: &mut [u8]) -> usize;
@@ -19,4 +29,31 @@ pub trait HcpHal {
 
     /// Log a debug message.
     fn log(&mut self, message: &str);
+
+    /// Current timestamp in microseconds (wrapping), or None without a microsecond clock.
+    /// With a clock, partial frames are dropped after the Modbus RTU gap
+    /// (`RTU_INTER_FRAME_TIMEOUT_US`) instead of `inter_frame_timeout_ms`.
+    fn now_us(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns true once after the UART detected an idle line (receive timeout) following
+    /// received bytes. Lets the driver drop a partial frame without timing the gap itself.
+    fn take_rx_idle(&mut self) -> bool {
+        false
+    }
+
+    /// Line errors (`LINE_ERROR_*` bits) the UART flagged since the previous call. Called after
+    /// every `uart_read`, so they are taken to belong to the bytes just read; the driver then
+    /// discards the frame still being received instead of waiting for its CRC to fail.
+    fn take_line_errors(&mut self) -> u8 {
+        0
+    }
+
+    /// Blocks until the last byte written has left the transmitter, so the direction pin can
+    /// be released right away. Returns false if the UART cannot tell; the driver then waits
+    /// `tx_turnaround_ms` instead.
+    fn wait_tx_complete(&mut self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,332 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{fence, Ordering};
+
+/// Default time without a heartbeat before the driver is considered stalled.
+pub const DEFAULT_STALL_TIMEOUT_MS: u32 = 2000;
+
+/// How many trailing bytes of the panicking source file are kept.
+pub const PANIC_FILE_LEN: usize = 24;
+/// Size of `PanicRecord::message`, including the terminating NUL.
+pub const PANIC_MESSAGE_LEN: usize = 96;
+/// `PanicRecord::magic` of a valid record ("PNC2" in memory order).
+pub const PANIC_RECORD_MAGIC: u32 = 0x3243_4E50;
+
+/// Copies the end of `src` (the file name end of a path) into `dst`, NUL-padded.
+fn copy_tail(dst: &mut [u8], src: &str) {
+    let src = src.as_bytes();
+    let kept = &src[src.len().saturating_sub(dst.len())..];
+    dst.fill(0);
+    dst[..kept.len()].copy_from_slice(kept);
+}
+
+/// Why the driver was last started, published in `DriverHealth::reset_reason`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// First start after the HP side cleared shared memory.
+    PowerOn = 0,
+    /// Restarted after a panic.
+    Panic = 1,
+    /// Restarted after the heartbeat stopped without a panic (hang or lock-up).
+    Stall = 2,
+}
+
+/// Liveness of the driver core, written by the driver itself.
+///
+/// Not seqlocked: `heartbeat` is a single word, and everything else only changes while
+/// the driver loop is not running (at start and in the panic handler). The block survives
+/// a restart of the driver, so the last panic stays readable afterwards.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DriverHealth {
+    /// Incremented on every driver loop iteration, back to 0 on every start
+    pub heartbeat: u32,
+    /// How often the driver started since shared memory was cleared
+    pub starts: u32,
+    /// Source line of the last panic, 0 if the driver never panicked
+    pub panic_line: u32,
+    /// End of the source path of the last panic, NUL-padded
+    pub panic_file: [u8; PANIC_FILE_LEN],
+    /// Why the driver last started (see `ResetReason`)
+    pub reset_reason: u8,
+    /// Set by the panic handler, cleared on the next start
+    pub panicked: bool,
+
+    pub _pad: [u8; 2],
+}
+
+impl DriverHealth {
+    /// Called once when the driver starts, before its loop. Works out why it (re)started
+    /// from what the previous run left behind.
+    pub fn on_start(&mut self) -> ResetReason {
+        let reason = if self.panicked {
+            ResetReason::Panic
+        } else if self.starts > 0 {
+            ResetReason::Stall
+        } else {
+            ResetReason::PowerOn
+        };
+        self.reset_reason = reason as u8;
+        self.panicked = false;
+        self.starts = self.starts.wrapping_add(1);
+        self.heartbeat = 0;
+        fence(Ordering::Release);
+        reason
+    }
+
+    /// Called on every loop iteration.
+    pub fn beat(&mut self) {
+        unsafe {
+            let beat = core::ptr::read_volatile(&self.heartbeat);
+            core::ptr::write_volatile(&mut self.heartbeat, beat.wrapping_add(1));
+        }
+    }
+
+    /// Called from the panic handler. Keeps the end of `file`, which holds the file name.
+    pub fn record_panic(&mut self, file: &str, line: u32) {
+        copy_tail(&mut self.panic_file, file);
+        self.panic_line = line;
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.panicked, true) };
+    }
+
+    pub fn reset_reason(&self) -> Option<ResetReason> {
+        match self.reset_reason {
+            0 => Some(ResetReason::PowerOn),
+            1 => Some(ResetReason::Panic),
+            2 => Some(ResetReason::Stall),
+            _ => None,
+        }
+    }
+}
+
+/// A panic of the HP-side driver, kept in memory that survives a software reset.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicRecord {
+    /// `PANIC_RECORD_MAGIC` while the record holds a panic. Memory that was never
+    /// written (after power-on) is random, so nothing else is trusted without it.
+    pub magic: u32,
+    /// Source line, 0 if the location was unknown
+    pub line: u32,
+    /// End of the source path, NUL-padded
+    pub file: [u8; PANIC_FILE_LEN],
+    /// Formatted panic message, truncated and always NUL-terminated
+    pub message: [u8; PANIC_MESSAGE_LEN],
+}
+
+impl Default for PanicRecord {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl PanicRecord {
+    pub const fn empty() -> Self {
+        Self { magic: 0, line: 0, file: [0; PANIC_FILE_LEN], message: [0; PANIC_MESSAGE_LEN] }
+    }
+
+    /// Fills in a panic. The record becomes valid last.
+    pub fn record(&mut self, file: &str, line: u32, message: fmt::Arguments) {
+        self.magic = 0;
+        copy_tail(&mut self.file, file);
+        self.line = line;
+        self.message = [0; PANIC_MESSAGE_LEN];
+        let mut writer = TruncatingWriter { buf: &mut self.message[..PANIC_MESSAGE_LEN - 1], len: 0 };
+        // Running out of space is not an error, the message is cut short
+        let _ = writer.write_fmt(message);
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.magic, PANIC_RECORD_MAGIC) };
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == PANIC_RECORD_MAGIC
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::empty();
+    }
+
+    pub fn file(&self) -> &str {
+        nul_terminated(&self.file)
+    }
+
+    pub fn message(&self) -> &str {
+        nul_terminated(&self.message)
+    }
+}
+
+/// The text before the first NUL, up to the last complete character.
+fn nul_terminated(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    match core::str::from_utf8(&bytes[..end]) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+/// Writes into a fixed buffer and silently drops what does not fit.
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        if n < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+/// Verdict of `HeartbeatMonitor::check`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+    /// The driver has not beaten since monitoring started, but the timeout has not expired yet.
+    Starting = 0,
+    /// The heartbeat moved within the timeout.
+    Alive = 1,
+    /// No heartbeat within the timeout; the driver should be restarted.
+    Stalled = 2,
+}
+
+/// HP-side watchdog over `DriverHealth::heartbeat`.
+///
+/// A zeroed monitor is valid and uses `DEFAULT_STALL_TIMEOUT_MS`; reset it to zero
+/// after restarting the driver.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatMonitor {
+    /// 0 = `DEFAULT_STALL_TIMEOUT_MS`
+    pub timeout_ms: u32,
+    last_heartbeat: u32,
+    last_change_ms: u32,
+    /// `check` was called at least once
+    started: bool,
+    /// The heartbeat moved at least once
+    beating: bool,
+
+    _pad: [u8; 2],
+}
+
+impl HeartbeatMonitor {
+    pub fn new(timeout_ms: u32) -> Self {
+        Self { timeout_ms, ..Default::default() }
+    }
+
+    /// Call periodically with the current heartbeat value.
+    pub fn check(&mut self, heartbeat: u32, now_ms: u32) -> Liveness {
+        if !self.started {
+            self.started = true;
+            self.last_heartbeat = heartbeat;
+            self.last_change_ms = now_ms;
+            return Liveness::Starting;
+        }
+        if heartbeat != self.last_heartbeat {
+            self.last_heartbeat = heartbeat;
+            self.last_change_ms = now_ms;
+            self.beating = true;
+            return Liveness::Alive;
+        }
+
+        let timeout = if self.timeout_ms == 0 { DEFAULT_STALL_TIMEOUT_MS } else { self.timeout_ms };
+        if now_ms.wrapping_sub(self.last_change_ms) >= timeout {
+            Liveness::Stalled
+        } else if self.beating {
+            Liveness::Alive
+        } else {
+            Liveness::Starting
+        }
+    }
+
+    /// Starts over after the driver was restarted.
+    pub fn restart(&mut self) {
+        *self = Self::new(self.timeout_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_reasons() {
+        let mut health = DriverHealth::default();
+        assert_eq!(health.on_start(), ResetReason::PowerOn);
+        health.beat();
+        health.beat();
+        assert_eq!(health.heartbeat, 2);
+
+        // Hung without panicking, restarted by the HP side
+        assert_eq!(health.on_start(), ResetReason::Stall);
+        assert_eq!(health.heartbeat, 0);
+
+        health.record_panic("/home/user/esphome-hcp2/common/src/protocol.rs", 321);
+        assert!(health.panicked);
+        assert_eq!(health.on_start(), ResetReason::Panic);
+        assert_eq!(health.reset_reason(), Some(ResetReason::Panic));
+        assert_eq!(health.starts, 3);
+
+        // The last panic stays readable after the restart
+        assert!(!health.panicked);
+        assert_eq!(health.panic_line, 321);
+        assert_eq!(&health.panic_file, b"2/common/src/protocol.rs");
+
+        health.record_panic("main.rs", 7);
+        assert_eq!(&health.panic_file[..8], b"main.rs\0");
+    }
+
+    #[test]
+    fn test_panic_record() {
+        let mut record = PanicRecord::empty();
+        assert!(!record.is_valid());
+
+        record.record("hp-firmware/src/lib.rs", 42, format_args!("index out of bounds: {} >= {}", 9, 4));
+        assert!(record.is_valid());
+        assert_eq!(record.file(), "hp-firmware/src/lib.rs");
+        assert_eq!(record.line, 42);
+        assert_eq!(record.message(), "index out of bounds: 9 >= 4");
+
+        record.clear();
+        assert!(!record.is_valid());
+        assert_eq!(record.message(), "");
+    }
+
+    #[test]
+    fn test_panic_message_truncated_on_char_boundary() {
+        let mut record = PanicRecord::empty();
+        let long = "ä".repeat(PANIC_MESSAGE_LEN);
+        record.record("x.rs", 1, format_args!("{}", long));
+        // 95 bytes of room hold 47 two-byte characters
+        assert_eq!(record.message(), "ä".repeat(47));
+        assert_eq!(record.message[PANIC_MESSAGE_LEN - 1], 0);
+    }
+
+    #[test]
+    fn test_monitor_detects_stall() {
+        let mut monitor = HeartbeatMonitor::new(1000);
+        assert_eq!(monitor.check(0, 5000), Liveness::Starting);
+        assert_eq!(monitor.check(0, 5500), Liveness::Starting);
+        assert_eq!(monitor.check(3, 5600), Liveness::Alive);
+        assert_eq!(monitor.check(3, 6500), Liveness::Alive);
+        assert_eq!(monitor.check(3, 6600), Liveness::Stalled);
+
+        monitor.restart();
+        assert_eq!(monitor.timeout_ms, 1000);
+        assert_eq!(monitor.check(0, 7000), Liveness::Starting);
+        assert_eq!(monitor.check(0, 8000), Liveness::Stalled);
+    }
+
+    #[test]
+    fn test_zeroed_monitor_uses_default_timeout() {
+        let mut monitor = HeartbeatMonitor::default();
+        monitor.check(1, 0);
+        assert_eq!(monitor.check(1, DEFAULT_STALL_TIMEOUT_MS - 1), Liveness::Starting);
+        assert_eq!(monitor.check(1, DEFAULT_STALL_TIMEOUT_MS), Liveness::Stalled);
+    }
+}
@@ -1,13 +1,26 @@
 #![no_std]
 
 pub mod registers;
+pub mod config;
 pub mod shared;
+pub mod codec;
 pub mod protocol;
+pub mod framer;
 pub mod hal;
 pub mod driver;
+#[cfg(feature = "async")]
+pub mod async_driver;
+pub mod link;
+pub mod trace;
+pub mod stats;
+pub mod health;
+pub mod ffi;
 
+pub use config::Hcp2Config;
 pub use shared::SharedData;
 pub use protocol::Hcp2Protocol;
 pub use hal::HcpHal;
 pub use driver::Hcp2Driver;
+#[cfg(feature = "async")]
+pub use async_driver::AsyncHcp2Driver;
 
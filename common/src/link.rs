@@ -0,0 +1,165 @@
+/// Default time without a valid frame before the drive is considered offline.
+pub const DEFAULT_SILENCE_TIMEOUT_MS: u32 = 3000;
+
+/// Consecutive CRC failures that are reported as a CRC storm rather than noise.
+const CRC_STORM_THRESHOLD: u8 = 5;
+
+/// Link problem published in `LpToHp::error_code`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    None = 0,
+    /// No valid frame within the silence timeout.
+    BusSilent = 1,
+    /// Bytes are arriving but consecutive frames fail the CRC check.
+    CrcStorm = 2,
+    /// The drive polls us but we never saw it scan the bus (we booted after it).
+    NoScanSeen = 3,
+    /// The drive scanned the bus again after it had already been polling us.
+    DriveRestarted = 4,
+}
+
+/// Online/offline state machine fed by the driver for every frame outcome.
+pub struct LinkMonitor {
+    silence_timeout_ms: u32,
+    last_valid_ms: u32,
+    online: bool,
+    crc_errors_in_row: u8,
+    scan_seen: bool,
+    polled: bool,
+    error: LinkError,
+}
+
+impl LinkMonitor {
+    pub fn new(silence_timeout_ms: u32) -> Self {
+        Self {
+            silence_timeout_ms,
+            last_valid_ms: 0,
+            online: false,
+            crc_errors_in_row: 0,
+            scan_seen: false,
+            polled: false,
+            error: LinkError::BusSilent,
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
+    pub fn error(&self) -> LinkError {
+        self.error
+    }
+
+    /// Any frame that passed the CRC and was understood.
+    pub fn on_valid_frame(&mut self, now_ms: u32) {
+        self.last_valid_ms = now_ms;
+        self.online = true;
+        self.crc_errors_in_row = 0;
+        if matches!(self.error, LinkError::BusSilent | LinkError::CrcStorm) {
+            self.error = if self.polled && !self.scan_seen { LinkError::NoScanSeen } else { LinkError::None };
+        }
+    }
+
+    pub fn on_crc_error(&mut self) {
+        self.crc_errors_in_row = self.crc_errors_in_row.saturating_add(1);
+        if self.crc_errors_in_row >= CRC_STORM_THRESHOLD {
+            self.error = LinkError::CrcStorm;
+        }
+    }
+
+    pub fn on_bus_scan(&mut self) {
+        if self.polled {
+            self.error = LinkError::DriveRestarted;
+        }
+        self.scan_seen = true;
+        self.polled = false;
+    }
+
+    /// A regular (idle or action) poll addressed to us.
+    pub fn on_poll(&mut self) {
+        self.polled = true;
+        if !self.scan_seen {
+            self.error = LinkError::NoScanSeen;
+        } else if self.error == LinkError::DriveRestarted {
+            // The drive found us again after its restart.
+            self.error = LinkError::None;
+        }
+    }
+
+    /// Call periodically; takes the link offline once the bus has been quiet too long.
+    pub fn tick(&mut self, now_ms: u32) {
+        if self.online && now_ms.wrapping_sub(self.last_valid_ms) > self.silence_timeout_ms {
+            self.online = false;
+            if self.error != LinkError::CrcStorm {
+                self.error = LinkError::BusSilent;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_online_offline_by_silence() {
+        let mut link = LinkMonitor::new(1000);
+        assert!(!link.is_online());
+        assert_eq!(link.error(), LinkError::BusSilent);
+
+        link.on_bus_scan();
+        link.on_valid_frame(100);
+        assert!(link.is_online());
+        assert_eq!(link.error(), LinkError::None);
+
+        link.tick(1100);
+        assert!(link.is_online());
+        link.tick(1101);
+        assert!(!link.is_online());
+        assert_eq!(link.error(), LinkError::BusSilent);
+
+        link.on_valid_frame(2000);
+        assert!(link.is_online());
+        assert_eq!(link.error(), LinkError::None);
+    }
+
+    #[test]
+    fn test_crc_storm() {
+        let mut link = LinkMonitor::new(1000);
+        link.on_bus_scan();
+        link.on_valid_frame(0);
+        for _ in 0..CRC_STORM_THRESHOLD - 1 {
+            link.on_crc_error();
+        }
+        assert_eq!(link.error(), LinkError::None);
+        link.on_crc_error();
+        assert_eq!(link.error(), LinkError::CrcStorm);
+
+        // Goes offline as a CRC storm, not as silence
+        link.tick(2000);
+        assert!(!link.is_online());
+        assert_eq!(link.error(), LinkError::CrcStorm);
+
+        link.on_valid_frame(2100);
+        assert_eq!(link.error(), LinkError::None);
+    }
+
+    #[test]
+    fn test_no_scan_seen_then_restart() {
+        let mut link = LinkMonitor::new(1000);
+        link.on_valid_frame(0);
+        link.on_poll();
+        assert_eq!(link.error(), LinkError::NoScanSeen);
+        link.on_poll();
+        assert_eq!(link.error(), LinkError::NoScanSeen);
+
+        // The drive rescanning while it was polling us means it restarted
+        link.on_bus_scan();
+        assert_eq!(link.error(), LinkError::DriveRestarted);
+        link.on_bus_scan();
+        assert_eq!(link.error(), LinkError::DriveRestarted);
+        link.on_poll();
+        assert_eq!(link.error(), LinkError::None);
+    }
+}
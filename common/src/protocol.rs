@@ -1,3 +1,8 @@
+use crate::codec::{
+    crc_valid, BusFrame, ExceptionCode, ExceptionResponse, ReadResponse, ReadWriteRequest, RegisterBlock, Request, WriteRequest,
+    WriteResponse,
+};
+use crate::config::Hcp2Config;
 use crate::registers::*;
 use crate::shared::*;
 
@@ -9,32 +14,163 @@ pub enum RegisterType {
     Unknown,
 }
 
+/// Classification of the last frame `dispatch_frame` accepted.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameKind {
+    None,
+    StatusBroadcast,
+    SyncWrite,
+    BusScan,
+    IdlePoll,
+    ActionPoll,
+    /// Answered with a Modbus exception instead of being served.
+    Exception,
+    Other,
+}
+
+/// Why a frame was not accepted. The values are stable, they are also recorded in `TraceEntry::result`.
+#[repr(u8)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DispatchError {
-    FrameTooShort,
+    FrameTooShort = 1,
     InvalidAddress,
     InvalidFunction,
     CrcMismatch,
-    ParsingError,
+    /// Register quantity does not match the byte count.
+    QuantityMismatch,
+    /// Byte count is not a whole number of registers.
+    OddByteCount,
+    /// More registers than we can hold (write) or answer (read).
+    TooManyRegisters,
+    /// Frame is longer or shorter than its byte count says.
+    LengthMismatch,
+    /// The response does not fit into the caller's output buffer.
+    BufferTooSmall,
+}
+
+/// Largest read quantity `prepare_poll_response` can answer.
+pub const MAX_READ_REGISTERS: u16 = 8;
+
+/// How long to wait after the release for the drive to change state.
+const ACK_TIMEOUT_MS: u32 = 5000;
+
+/// How long to wait after the release for the light bit to follow a light on/off command.
+const LIGHT_ACK_TIMEOUT_MS: u32 = 2000;
+/// Extra toggles for a light on/off command the drive ignored.
+const LIGHT_RETRIES: u8 = 1;
+
+/// A set-point this close to the current position counts as reached without moving.
+pub const POSITION_TOLERANCE: u8 = 2;
+/// Upper bound for the learned overshoot, so one bad measurement cannot stop the door far off.
+pub const MAX_POSITION_OVERSHOOT: u8 = 20;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CommandPhase {
+    Idle,
+    Pressing,
+    AwaitingAck,
+    /// Go-to-position: door travelling, waiting for the stop point.
+    Tracking,
+    /// Go-to-position: stop sent, waiting for the door to stand still.
+    Settling,
+}
+
+/// Go-to-position move in progress.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct PositionMove {
+    target: u8,
+    opening: bool,
+    /// Position at which the stop was issued
+    stop_from: u8,
+}
+
+impl PositionMove {
+    /// Whether the stop has to be issued now, so the door coasts to `target`.
+    fn stop_due(&self, position: u8, overshoot: u8) -> bool {
+        if self.opening {
+            position >= self.target.saturating_sub(overshoot)
+        } else {
+            position <= self.target.saturating_add(overshoot)
+        }
+    }
 }
 
 pub struct Hcp2Protocol {
+    config: Hcp2Config,
     counter: u8,
     command_code: u8,
-    last_action: u8,
+    /// Mailbox entry currently being executed (valid unless `phase` is Idle).
+    active: CommandEntry,
+    /// Button currently pressed for `active` (differs from its action for go-to-position).
+    button: DriveAction,
+    /// Set while `active` is a go-to-position.
+    position_move: Option<PositionMove>,
+    /// Learned travel after a stop, in position units.
+    overshoot: u8,
+    /// Light state requested by the active light on/off command.
+    light_target: Option<bool>,
+    light_retries: u8,
+    /// A status broadcast has been seen since start, so `LpToHp::light_on` is the drive's.
+    status_seen: bool,
+    /// Position and time of the last observed movement, for the go-to-position timeout.
+    last_position: u8,
+    last_progress_ts: u32,
     action_start_ts: u32,
+    phase: CommandPhase,
+    release_ts: u32,
+    /// Drive state and light at the time the press started.
+    ack_reference: (u8, bool),
+    /// Set when the drive reacted while the button was still pressed.
+    ack_seen: bool,
+    last_frame: FrameKind,
+    /// A sync counter value has been seen since start.
+    sync_seen: bool,
+    sync_jumps: u32,
+}
+
+impl Default for Hcp2Protocol {
+    fn default() -> Self {
+        Self::new(Hcp2Config::default())
+    }
 }
 
 impl Hcp2Protocol {
-    pub fn new() -> Self {
+    pub fn new(config: Hcp2Config) -> Self {
         Self {
+            config: config.resolved(),
             counter: 0,
             command_code: 0,
-            last_action: CMD_NONE,
+            active: CommandEntry::default(),
+            button: DriveAction::None,
+            position_move: None,
+            overshoot: 0,
+            light_target: None,
+            light_retries: 0,
+            status_seen: false,
+            last_position: 0,
+            last_progress_ts: 0,
             action_start_ts: 0,
+            phase: CommandPhase::Idle,
+            release_ts: 0,
+            ack_reference: (0, false),
+            ack_seen: false,
+            last_frame: FrameKind::None,
+            sync_seen: false,
+            sync_jumps: 0,
         }
     }
 
+    /// What the most recent frame accepted by `dispatch_frame` was.
+    pub fn last_frame_kind(&self) -> FrameKind {
+        self.last_frame
+    }
+
+    /// How often the drive's sync counter did not continue from the previous value.
+    /// A repeated value (retransmission) does not count.
+    pub fn sync_jumps(&self) -> u32 {
+        self.sync_jumps
+    }
+
     pub(crate) fn identify_request(&self, address: u16) -> RegisterType {
         match address {
             ADDR_STATUS_UPDATE => RegisterType::StatusUpdate,
@@ -44,49 +180,122 @@ impl Hcp2Protocol {
         }
     }
 
-    pub fn handle_status_update(&mut self, regs: &[u16], shared: &mut SharedData) {
-        if regs.len() < 9 {
+    pub fn handle_status_update(&mut self, regs: &[u16], status: &mut LpToHp) {
+        if regs.len() < STATUS_REGISTER_COUNT {
             return;
         }
+        status.raw.set_status(regs);
+
         // Reg 1: Target Position (High) | Current Position (Low)
-        shared.write_target_pos((regs[1] >> 8) as u8);
-        shared.write_current_pos((regs[1] & 0xFF) as u8);
+        status.drive_target_position = (regs[1] >> 8) as u8;
+        status.current_position = (regs[1] & 0xFF) as u8;
         
-        // Reg 2: State (High)
-        let state_val = (regs[2] >> 8) as u8;
-        shared.write_state(DriveState::from(state_val) as u8);
+        // Reg 2: State (High), flags of unknown meaning (Low)
+        let drive = DriveStatus::from(regs[2]);
+        let state = u8::from(drive.state);
+        if !drive.state.is_known() && state != status.current_state {
+            status.unknown_states = status.unknown_states.wrapping_add(1);
+            status.last_unknown_state = state;
+        }
+        status.current_state = state;
+        status.state_flags = drive.flags.bits();
         
         // Reg 6: Light Status (Bit 0x10)
-        shared.write_light((regs[6] & 0x10) != 0);
+        let light = (regs[6] & 0x10) != 0;
+        status.light_on = light;
+        self.status_seen = true;
+
+        if self.phase == CommandPhase::Settling && !DriveState::from(state).is_moving() {
+            self.learn_overshoot(status);
+            self.complete_command(status, CMD_STATUS_ACKNOWLEDGED);
+            return;
+        }
+
+        let reacted = match self.light_target {
+            Some(on) => light == on,
+            None => (state, light) != self.ack_reference,
+        };
+        if self.phase != CommandPhase::Idle && reacted {
+            self.ack_seen = true;
+            if self.phase == CommandPhase::AwaitingAck {
+                self.complete_command(status, CMD_STATUS_ACKNOWLEDGED);
+            }
+        }
     }
 
-    pub fn handle_sync_counter(&mut self, regs: &[u16]) {
-        if regs.len() < 1 {
+    /// Marks a released command as timed out if the drive never reacted.
+    /// A go-to-position times out when the position stops changing instead,
+    /// a light on/off command is pressed once more before it times out.
+    pub fn check_command_timeout(&mut self, status: &mut LpToHp, millis: u32) {
+        let ack_timeout = if self.light_target.is_some() { LIGHT_ACK_TIMEOUT_MS } else { ACK_TIMEOUT_MS };
+        match self.phase {
+            CommandPhase::AwaitingAck if millis.wrapping_sub(self.release_ts) >= ack_timeout => {
+                if self.light_target.is_some() && self.light_retries > 0 {
+                    self.light_retries -= 1;
+                    self.press(DriveAction::ToggleLight, millis);
+                    self.set_command_status(status, CMD_STATUS_PRESSING);
+                } else {
+                    self.complete_command(status, CMD_STATUS_TIMED_OUT);
+                }
+            }
+            CommandPhase::Tracking | CommandPhase::Settling => {
+                if status.current_position != self.last_position {
+                    self.last_position = status.current_position;
+                    self.last_progress_ts = millis;
+                } else if millis.wrapping_sub(self.last_progress_ts) >= ACK_TIMEOUT_MS {
+                    self.complete_command(status, CMD_STATUS_TIMED_OUT);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Travel after a stop currently assumed for go-to-position.
+    pub fn position_overshoot(&self) -> u8 {
+        self.overshoot
+    }
+
+    /// Averages the travel after the last stop into the learned overshoot.
+    fn learn_overshoot(&mut self, status: &mut LpToHp) {
+        let Some(movement) = self.position_move else {
+            return;
+        };
+        let travelled = status.current_position.abs_diff(movement.stop_from).min(MAX_POSITION_OVERSHOOT);
+        self.overshoot = ((self.overshoot as u16 + travelled as u16).div_ceil(2)) as u8;
+        status.position_overshoot = self.overshoot;
+    }
+
+    pub fn handle_sync_counter(&mut self, regs: &[u16], status: &mut LpToHp) {
+        if regs.is_empty() {
             return;
         }
-        self.counter = (regs[0] >> 8) as u8;
+        status.raw.set_sync(regs[0]);
+        let counter = (regs[0] >> 8) as u8;
+        if self.sync_seen && counter != self.counter && counter != self.counter.wrapping_add(1) {
+            self.sync_jumps = self.sync_jumps.wrapping_add(1);
+        }
+        self.sync_seen = true;
+        self.counter = counter;
         self.command_code = (regs[0] & 0xFF) as u8;
     }
 
-    pub fn prepare_poll_response(&mut self, quantity: u16, shared: &SharedData, millis: u32) -> [u16; 8] {
+    pub fn prepare_poll_response(&mut self, quantity: u16, request: &HpToLp, status: &mut LpToHp, millis: u32) -> [u16; 8] {
         let mut resp = [0u16; 8];
         match quantity {
             2 => {
                 resp[0] = ((self.counter as u16) << 8) | 0x04;
-                resp[1] = ((self.command_code as u16) << 8) | 0x00;
+                resp[1] = (self.command_code as u16) << 8;
             }
             5 => {
                 resp[0] = (self.counter as u16) << 8;
                 resp[1] = ((self.command_code as u16) << 8) | 0x05;
-                resp[2] = 0x0430;
-                resp[3] = 0x10FF;
-                resp[4] = 0xA845;
+                resp[2..5].copy_from_slice(&self.config.scan_identity);
             }
             8 => {
                 resp[0] = (self.counter as u16) << 8;
                 resp[1] = ((self.command_code as u16) << 8) | 0x01;
                 
-                let (reg2, reg3) = self.get_action_registers(shared, millis);
+                let (reg2, reg3) = self.get_action_registers(request, status, millis);
                 resp[2] = reg2;
                 resp[3] = reg3;
             }
@@ -95,8 +304,43 @@ impl Hcp2Protocol {
         resp
     }
 
+    /// Listen-only counterpart of `dispatch_frame`: applies status broadcasts and
+    /// never prepares a response. Queued commands cannot be executed and are rejected.
+    pub fn observe_frame(&mut self, frame: &BusFrame, request: &HpToLp, status: &mut LpToHp) {
+        self.last_frame = FrameKind::Other;
+        match frame {
+            BusFrame::Request(Request::Write(WriteRequest { address, write })) => match self.identify_request(write.start) {
+                RegisterType::StatusUpdate if *address == ADDRESS_BROADCAST => {
+                    self.handle_status_update(write.as_slice(), status);
+                    self.last_frame = FrameKind::StatusBroadcast;
+                }
+                RegisterType::SyncCounter => self.handle_sync_counter(write.as_slice(), status),
+                _ => {}
+            },
+            BusFrame::Request(Request::ReadWrite(ReadWriteRequest { write, .. }))
+                if self.identify_request(write.start) == RegisterType::SyncCounter =>
+            {
+                self.handle_sync_counter(write.as_slice(), status);
+            }
+            _ => {}
+        }
+
+        while request.pending_commands(status.cmd_tail) > 0 {
+            let entry = request.command(status.cmd_tail);
+            status.set_result(status.cmd_tail, CommandResult { seq: entry.seq, status: CMD_STATUS_REJECTED });
+            status.cmd_tail = status.cmd_tail.wrapping_add(1);
+        }
+    }
+
     /// Dispatches a raw byte frame to the appropriate handler.
-    pub fn dispatch_frame(&mut self, frame: &[u8], out_buffer: &mut [u8], shared: &mut SharedData, millis: u32) -> Result<usize, DispatchError> {
+    pub fn dispatch_frame(
+        &mut self,
+        frame: &[u8],
+        out_buffer: &mut [u8],
+        request: &HpToLp,
+        status: &mut LpToHp,
+        millis: u32,
+    ) -> Result<usize, DispatchError> {
         if frame.len() < 4 {
             return Err(DispatchError::FrameTooShort);
         }
@@ -104,137 +348,267 @@ impl Hcp2Protocol {
         let address = frame[0];
         let func = frame[1];
 
-        if address != ADDRESS_HCP && address != ADDRESS_BROADCAST {
-            return Err(DispatchError::InvalidAddress);
-        }
+        // Broadcasts are processed but never answered: every device on the bus
+        // receives them, so a reply would collide with the others.
+        let respond = match address {
+            ADDRESS_BROADCAST => false,
+            a if self.config.answers_to(a) => true,
+            _ => return Err(DispatchError::InvalidAddress),
+        };
 
         if func != FUNC_WRITE_MULTIPLE_REGISTERS && func != FUNC_READ_WRITE_MULTIPLE_REGISTERS {
-            return Err(DispatchError::InvalidFunction);
+            if !respond || !self.config.answers_exception(ExceptionCode::IllegalFunction) {
+                return Err(DispatchError::InvalidFunction);
+            }
+            if !crc_valid(frame) {
+                return Err(DispatchError::CrcMismatch);
+            }
+            self.check_command_timeout(status, millis);
+            return self.answer_exception(address, func, ExceptionCode::IllegalFunction, out_buffer);
         }
 
-        // Validate CRC (Modbus RTU: last 2 bytes are LSB, MSB)
-        let len = frame.len();
-        let received_crc = (frame[len - 2] as u16) | ((frame[len - 1] as u16) << 8);
-        let computed_crc = crc16(&frame[..len - 2]);
-        if received_crc != computed_crc {
-            return Err(DispatchError::CrcMismatch);
+        // Validates the CRC before anything else is looked at
+        let decoded = Request::decode(frame)?;
+
+        // The drive talks continuously, so every valid frame doubles as a tick.
+        self.check_command_timeout(status, millis);
+
+        if let Some(code) = self.exception_for(&decoded).filter(|_| respond) {
+            return self.answer_exception(address, func, code, out_buffer);
         }
 
-        match func {
-            FUNC_WRITE_MULTIPLE_REGISTERS => {
-                if frame.len() < 9 { return Err(DispatchError::ParsingError); }
-                let start_addr = ((frame[2] as u16) << 8) | (frame[3] as u16);
-                let qty = ((frame[4] as u16) << 8) | (frame[5] as u16);
-                let byte_count = frame[6] as usize;
-                if frame.len() < 9 + byte_count { return Err(DispatchError::ParsingError); }
-                
-                let mut regs = [0u16; 16];
-                for i in 0..(byte_count / 2).min(16) {
-                    regs[i] = ((frame[7 + i * 2] as u16) << 8) | (frame[8 + i * 2] as u16);
+        match decoded {
+            Request::Write(WriteRequest { write, .. }) => {
+                if respond && out_buffer.len() < WriteResponse::FRAME_LEN {
+                    return Err(DispatchError::BufferTooSmall);
                 }
-
-                match self.identify_request(start_addr) {
-                    RegisterType::StatusUpdate => self.handle_status_update(&regs[..qty as usize], shared),
-                    RegisterType::SyncCounter => self.handle_sync_counter(&regs[..qty as usize]),
-                    _ => {}
+                self.last_frame = match self.identify_request(write.start) {
+                    RegisterType::StatusUpdate => {
+                        self.handle_status_update(write.as_slice(), status);
+                        FrameKind::StatusBroadcast
+                    }
+                    RegisterType::SyncCounter => {
+                        self.handle_sync_counter(write.as_slice(), status);
+                        FrameKind::SyncWrite
+                    }
+                    _ => FrameKind::Other,
+                };
+                if !respond {
+                    return Ok(0);
                 }
-                Ok(0) 
+
+                WriteResponse { address, start: write.start, quantity: write.quantity() }.encode(out_buffer)
             }
-            FUNC_READ_WRITE_MULTIPLE_REGISTERS => {
-                if frame.len() < 13 { return Err(DispatchError::ParsingError); }
-                let rd_addr = ((frame[2] as u16) << 8) | (frame[3] as u16);
-                let rd_qty = ((frame[4] as u16) << 8) | (frame[5] as u16);
-                let wr_addr = ((frame[6] as u16) << 8) | (frame[7] as u16);
-                let wr_qty = ((frame[8] as u16) << 8) | (frame[9] as u16);
-                let byte_count = frame[10] as usize;
-                
-                if frame.len() < 13 + byte_count { return Err(DispatchError::ParsingError); }
+            Request::ReadWrite(ReadWriteRequest { read_start, read_qty, write, .. }) => {
+                if self.identify_request(write.start) == RegisterType::SyncCounter {
+                    self.handle_sync_counter(write.as_slice(), status);
+                }
 
-                let mut wr_regs = [0u16; 16];
-                for i in 0..(byte_count / 2).min(16) {
-                    wr_regs[i] = ((frame[11 + i * 2] as u16) << 8) | (frame[12 + i * 2] as u16);
+                self.last_frame = FrameKind::Other;
+                if !respond || self.identify_request(read_start) != RegisterType::Poll {
+                    return Ok(0);
+                }
+                if read_qty > MAX_READ_REGISTERS {
+                    return Err(DispatchError::TooManyRegisters);
+                }
+                if out_buffer.len() < ReadResponse::frame_len(read_qty as usize) {
+                    return Err(DispatchError::BufferTooSmall);
                 }
 
-                if self.identify_request(wr_addr) == RegisterType::SyncCounter {
-                    self.handle_sync_counter(&wr_regs[..wr_qty as usize]);
+                self.last_frame = match read_qty {
+                    2 => FrameKind::IdlePoll,
+                    5 => FrameKind::BusScan,
+                    8 => FrameKind::ActionPoll,
+                    _ => FrameKind::Other,
+                };
+                let resp_regs = self.prepare_poll_response(read_qty, request, status, millis);
+                let regs = RegisterBlock::new(0, &resp_regs[..read_qty as usize])?;
+                ReadResponse { address, regs }.encode(out_buffer)
+            }
+        }
+    }
+
+    /// The exception to answer `request` with instead of serving it, if that case is
+    /// enabled in the configuration. A request that fails is not applied at all.
+    fn exception_for(&self, request: &Request) -> Option<ExceptionCode> {
+        let code = match request {
+            Request::Write(WriteRequest { write, .. }) => match self.identify_request(write.start) {
+                RegisterType::StatusUpdate | RegisterType::SyncCounter => return None,
+                _ => ExceptionCode::IllegalDataAddress,
+            },
+            Request::ReadWrite(ReadWriteRequest { read_start, read_qty, write, .. }) => {
+                let write_served = write.as_slice().is_empty()
+                    || self.identify_request(write.start) == RegisterType::SyncCounter;
+                if self.identify_request(*read_start) != RegisterType::Poll || !write_served {
+                    ExceptionCode::IllegalDataAddress
+                } else if !matches!(read_qty, 2 | 5 | 8) {
+                    ExceptionCode::IllegalDataValue
+                } else {
+                    return None;
                 }
+            }
+        };
+        self.config.answers_exception(code).then_some(code)
+    }
 
-                if self.identify_request(rd_addr) == RegisterType::Poll {
-                    let resp_regs = self.prepare_poll_response(rd_qty, shared, millis);
-                    let resp_byte_count = (rd_qty * 2) as u8;
-                    
-                    if out_buffer.len() < 5 + resp_byte_count as usize { return Err(DispatchError::ParsingError); }
-
-                    out_buffer[0] = ADDRESS_HCP;
-                    out_buffer[1] = FUNC_READ_WRITE_MULTIPLE_REGISTERS;
-                    out_buffer[2] = resp_byte_count;
-                    for i in 0..rd_qty as usize {
-                        out_buffer[3 + i * 2] = (resp_regs[i] >> 8) as u8;
-                        out_buffer[4 + i * 2] = (resp_regs[i] & 0xFF) as u8;
-                    }
-                    let out_len = 3 + resp_byte_count as usize;
-                    let crc = crc16(&out_buffer[..out_len]);
-                    out_buffer[out_len] = (crc & 0xFF) as u8;
-                    out_buffer[out_len + 1] = (crc >> 8) as u8;
-                    return Ok(out_len + 2);
+    fn answer_exception(&mut self, address: u8, function: u8, code: ExceptionCode, out_buffer: &mut [u8]) -> Result<usize, DispatchError> {
+        let len = ExceptionResponse { address, function, code: code as u8 }.encode(out_buffer)?;
+        self.last_frame = FrameKind::Exception;
+        Ok(len)
+    }
+
+    fn get_action_registers(&mut self, request: &HpToLp, status: &mut LpToHp, millis: u32) -> (u16, u16) {
+        if self.phase == CommandPhase::Idle && !self.latch_next_command(request, status, millis) {
+            return (0, 0);
+        }
+        if self.phase == CommandPhase::Tracking {
+            match self.position_move.as_mut() {
+                Some(movement) if movement.stop_due(status.current_position, self.overshoot) => {
+                    movement.stop_from = status.current_position;
+                    self.press(DriveAction::Stop, millis);
+                    self.set_command_status(status, CMD_STATUS_PRESSING);
                 }
-                Ok(0)
+                _ => return (0, 0),
             }
-            _ => Err(DispatchError::InvalidFunction)
         }
-    }
+        if self.phase != CommandPhase::Pressing {
+            // Released and waiting for the drive to react before taking the next entry.
+            return (0, 0);
+        }
 
-    fn get_action_registers(&mut self, shared: &SharedData, millis: u32) -> (u16, u16) {
-        let action = shared.read_command();
-        if action == CMD_NONE {
-            self.last_action = CMD_NONE;
+        let Some((press, release)) = self.button.registers() else {
             return (0, 0);
+        };
+
+        if millis.wrapping_sub(self.action_start_ts) < self.config.press_duration_ms {
+            return press;
         }
 
-        if self.last_action != action {
-            self.last_action = action;
-            self.action_start_ts = millis;
+        // Press window is over: send the release exactly once.
+        if self.position_move.is_some() {
+            self.phase = if self.button == DriveAction::Stop { CommandPhase::Settling } else { CommandPhase::Tracking };
+            self.last_position = status.current_position;
+            self.last_progress_ts = millis;
+            self.set_command_status(status, CMD_STATUS_RELEASED);
+        } else if self.ack_seen {
+            self.complete_command(status, CMD_STATUS_ACKNOWLEDGED);
+        } else {
+            self.phase = CommandPhase::AwaitingAck;
+            self.release_ts = millis;
+            self.set_command_status(status, CMD_STATUS_RELEASED);
         }
+        release
+    }
 
-        let is_pressing = millis.wrapping_sub(self.action_start_ts) < 500;
+    /// Takes the next mailbox entry, skipping (and rejecting) entries that cannot be executed.
+    fn latch_next_command(&mut self, request: &HpToLp, status: &mut LpToHp, millis: u32) -> bool {
+        while request.pending_commands(status.cmd_tail) > 0 {
+            let tail = status.cmd_tail;
+            let entry = request.command(tail);
+            // Whether the light needs toggling is only known from a broadcast; until then
+            // the command stays queued.
+            if matches!(entry.action(), Ok(DriveAction::LightOn | DriveAction::LightOff)) && !self.status_seen {
+                return false;
+            }
+            self.active = entry;
+            self.position_move = None;
+            self.light_target = None;
 
-        match action {
-            CMD_OPEN => if is_pressing { (0x0210, 0x0000) } else { (0x0110, 0x0000) },
-            CMD_CLOSE => if is_pressing { (0x0220, 0x0000) } else { (0x0120, 0x0000) },
-            CMD_STOP => if is_pressing { (0x0240, 0x0000) } else { (0x0140, 0x0000) },
-            CMD_HALF_OPEN => if is_pressing { (0x0200, 0x0400) } else { (0x0100, 0x0400) },
-            CMD_VENT => if is_pressing { (0x0200, 0x4000) } else { (0x0100, 0x4000) },
-            CMD_TOGGLE_LIGHT => if is_pressing { (0x0100, 0x0200) } else { (0x0800, 0x0200) },
-            _ => (0, 0),
+            let action = match entry.action() {
+                Ok(action) => action,
+                Err(_) => {
+                    status.set_result(tail, CommandResult { seq: entry.seq, status: CMD_STATUS_INVALID });
+                    status.cmd_tail = tail.wrapping_add(1);
+                    continue;
+                }
+            };
+            let button = match action {
+                DriveAction::GoToPosition => match self.start_move(request.target_position, status) {
+                    Some(button) => button,
+                    None => {
+                        // Already there
+                        self.complete_command(status, CMD_STATUS_ACKNOWLEDGED);
+                        continue;
+                    }
+                },
+                DriveAction::LightOn | DriveAction::LightOff => {
+                    let on = action == DriveAction::LightOn;
+                    if status.light_on == on {
+                        self.complete_command(status, CMD_STATUS_ACKNOWLEDGED);
+                        continue;
+                    }
+                    self.light_target = Some(on);
+                    self.light_retries = LIGHT_RETRIES;
+                    DriveAction::ToggleLight
+                }
+                _ => action,
+            };
+            if button.registers().is_none() {
+                status.set_result(tail, CommandResult { seq: entry.seq, status: CMD_STATUS_REJECTED });
+                status.cmd_tail = tail.wrapping_add(1);
+                continue;
+            }
+
+            self.press(button, millis);
+            self.ack_reference = (status.current_state, status.light_on);
+            self.set_command_status(status, CMD_STATUS_PRESSING);
+            return true;
         }
+        false
     }
-}
 
-fn crc16(data: &[u8]) -> u16 {
-    let mut crc = 0xFFFFu16;
-    for &byte in data {
-        crc ^= byte as u16;
-        for _ in 0..8 {
-            if (crc & 1) != 0 {
-                crc >>= 1;
-                crc ^= 0xA001;
-            } else {
-                crc >>= 1;
+    /// Plans a go-to-position and returns the button that starts it, or None if
+    /// the door is already at `target`. The end positions are left to the drive's limits.
+    fn start_move(&mut self, target: u8, status: &LpToHp) -> Option<DriveAction> {
+        let target = target.min(POSITION_OPEN);
+        let position = status.current_position;
+        if position.abs_diff(target) <= POSITION_TOLERANCE {
+            return None;
+        }
+        match target {
+            POSITION_CLOSED => Some(DriveAction::Close),
+            POSITION_OPEN => Some(DriveAction::Open),
+            _ => {
+                let opening = target > position;
+                self.position_move = Some(PositionMove { target, opening, stop_from: position });
+                Some(if opening { DriveAction::Open } else { DriveAction::Close })
             }
         }
     }
-    crc
+
+    fn press(&mut self, button: DriveAction, millis: u32) {
+        self.button = button;
+        self.action_start_ts = millis;
+        self.phase = CommandPhase::Pressing;
+        self.ack_seen = false;
+    }
+
+    fn set_command_status(&self, status: &mut LpToHp, result: u8) {
+        status.set_result(status.cmd_tail, CommandResult { seq: self.active.seq, status: result });
+    }
+
+    /// Publishes the final status and frees the mailbox slot.
+    fn complete_command(&mut self, status: &mut LpToHp, result: u8) {
+        self.set_command_status(status, result);
+        status.cmd_tail = status.cmd_tail.wrapping_add(1);
+        self.phase = CommandPhase::Idle;
+        self.position_move = None;
+        self.light_target = None;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::codec::crc16;
+    use crate::config::{EXCEPTION_ILLEGAL_DATA_ADDRESS, EXCEPTION_ILLEGAL_DATA_VALUE, EXCEPTION_ILLEGAL_FUNCTION};
     use crate::registers::DriveState;
 
     #[test]
     fn test_handle_status_update_parsing() {
-        let mut proto = Hcp2Protocol::new();
-        let mut shared = SharedData::default();
+        let mut proto = Hcp2Protocol::default();
+        let request = HpToLp::default();
+        let mut status = LpToHp::default();
 
         // Verify constants usage via validation
         // NOTE: validate_frame is now internal/inline. Testing dispatch directly.
@@ -243,43 +617,109 @@ mod tests {
         
         // Invalid Address
         let invalid_addr = [0x99, FUNC_WRITE_MULTIPLE_REGISTERS, 0x00, 0x00];
-        assert_eq!(proto.dispatch_frame(&invalid_addr, &mut buf, &mut shared, 0), Err(DispatchError::InvalidAddress));
+        assert_eq!(proto.dispatch_frame(&invalid_addr, &mut buf, &request, &mut status, 0), Err(DispatchError::InvalidAddress));
 
         // Invalid Func
         let invalid_func = [ADDRESS_HCP, 0x88, 0x00, 0x00];
-        assert_eq!(proto.dispatch_frame(&invalid_func, &mut buf, &mut shared, 0), Err(DispatchError::InvalidFunction));
+        assert_eq!(proto.dispatch_frame(&invalid_func, &mut buf, &request, &mut status, 0), Err(DispatchError::InvalidFunction));
 
         // Example from PROTOCOL.md: 0x1635 (target 0x16, current 0x35), state 0x01 (Opening), light bit 0x10
         let regs1 = [0x0000, 0x1635, 0x0100, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
-        proto.handle_status_update(&regs1, &mut shared);
-        assert_eq!(shared.target_position, 0x16);
-        assert_eq!(shared.current_position, 0x35);
-        assert_eq!(shared.current_state, DriveState::Opening as u8);
-        assert!(shared.light_on);
+        proto.handle_status_update(&regs1, &mut status);
+        assert_eq!(status.drive_target_position, 0x16);
+        assert_eq!(status.current_position, 0x35);
+        assert_eq!(status.drive_state(), DriveState::Opening);
+        assert!(status.light_on);
 
         // Test Case 2: Closed, Light Off
         let regs2 = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
-        proto.handle_status_update(&regs2, &mut shared);
-        assert_eq!(shared.current_state, DriveState::Closed as u8);
-        assert!(!shared.light_on);
+        proto.handle_status_update(&regs2, &mut status);
+        assert_eq!(status.drive_state(), DriveState::Closed);
+        assert!(!status.light_on);
+    }
+
+    #[test]
+    fn test_unknown_state_kept_and_counted() {
+        let mut proto = Hcp2Protocol::default();
+        let mut status = LpToHp::default();
+        let mut regs = [0u16; 9];
+
+        regs[2] = 0x1103;
+        proto.handle_status_update(&regs, &mut status);
+        proto.handle_status_update(&regs, &mut status);
+        assert_eq!(status.drive_state(), DriveState::Unknown(0x11));
+        assert_eq!(status.state_flags().bits(), 0x03);
+        assert_eq!((status.unknown_states, status.last_unknown_state), (1, 0x11));
+
+        regs[2] = 0x4000;
+        proto.handle_status_update(&regs, &mut status);
+        regs[2] = 0x1100;
+        proto.handle_status_update(&regs, &mut status);
+        assert_eq!(status.drive_state(), DriveState::Unknown(0x11));
+        assert_eq!(status.unknown_states, 2);
+    }
+
+    #[test]
+    fn test_sync_jumps() {
+        let mut proto = Hcp2Protocol::default();
+        let mut status = LpToHp::default();
+        // First value and normal increments (including the wrap) are not jumps
+        for reg in [0x7F00, 0x8000, 0x8000, 0x8100] {
+            proto.handle_sync_counter(&[reg], &mut status);
+        }
+        proto.handle_sync_counter(&[0xFF00], &mut status);
+        proto.handle_sync_counter(&[0x0000], &mut status);
+        assert_eq!(proto.sync_jumps(), 1);
+
+        proto.handle_sync_counter(&[0x0500], &mut status);
+        assert_eq!(proto.sync_jumps(), 2);
+    }
+
+    #[test]
+    fn test_raw_registers_mirrored() {
+        let mut proto = Hcp2Protocol::default();
+        let mut status = LpToHp::default();
+        let regs = [0x0001, 0xC8C8, 0x2003, 0x0004, 0x0005, 0x0006, 0x0017, 0x0008, 0x0009];
+        proto.handle_status_update(&regs, &mut status);
+        assert_eq!(status.raw.status, regs);
+        assert_eq!(status.raw.changes, 1);
+
+        // Repeated broadcast is not a change
+        proto.handle_status_update(&regs, &mut status);
+        assert_eq!(status.raw.changes, 1);
+
+        // Only the sync command code counts as a change, not the counter
+        proto.handle_sync_counter(&[0x0100], &mut status);
+        proto.handle_sync_counter(&[0x0200], &mut status);
+        assert_eq!(status.raw.changes, 1);
+        proto.handle_sync_counter(&[0x0312], &mut status);
+        assert_eq!(status.raw.sync, 0x0312);
+        assert_eq!(status.raw.changes, 2);
+
+        // Sync writes are mirrored when only observing, too
+        let (frame, len) = with_crc(&SCAN_BODY);
+        proto.observe_frame(&BusFrame::decode(&frame[..len]).unwrap(), &HpToLp::default(), &mut status);
+        assert_eq!(status.raw.sync, 0x0002);
+        assert_eq!(status.raw.changes, 3);
     }
 
     #[test]
     fn test_poll_responses() {
-        let mut proto = Hcp2Protocol::new();
-        let shared = SharedData::default();
+        let mut proto = Hcp2Protocol::default();
+        let request = HpToLp::default();
+        let mut status = LpToHp::default();
 
         // Simulate sync counter update from drive
         let sync_regs = [0x1234];
-        proto.handle_sync_counter(&sync_regs);
+        proto.handle_sync_counter(&sync_regs, &mut status);
 
         // Test Length 2 (Idle Poll)
-        let resp2 = proto.prepare_poll_response(2, &shared, 0);
+        let resp2 = proto.prepare_poll_response(2, &request, &mut status, 0);
         assert_eq!(resp2[0], 0x1204); 
         assert_eq!(resp2[1], 0x3400); 
 
         // Test Length 5 (Bus Scan)
-        let resp5 = proto.prepare_poll_response(5, &shared, 0);
+        let resp5 = proto.prepare_poll_response(5, &request, &mut status, 0);
         assert_eq!(resp5[0], 0x1200); 
         assert_eq!(resp5[1], 0x3405); 
         assert_eq!(resp5[2], 0x0430);
@@ -287,7 +727,7 @@ mod tests {
         assert_eq!(resp5[4], 0xA845);
 
         // Test Length 8 (Command Action - None)
-        let resp8 = proto.prepare_poll_response(8, &shared, 0);
+        let resp8 = proto.prepare_poll_response(8, &request, &mut status, 0);
         assert_eq!(resp8[0], 0x1200); 
         assert_eq!(resp8[1], 0x3401); 
         assert_eq!(resp8[2], 0x0000); 
@@ -296,42 +736,342 @@ mod tests {
 
     #[test]
     fn test_all_commands_press_release_logic() {
-        let mut proto = Hcp2Protocol::new();
-        let mut shared = SharedData::default();
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
 
-        let check_action = |proto: &mut Hcp2Protocol, shared: &SharedData, time: u32, expected_r2, expected_r3| {
-            let resp = proto.prepare_poll_response(8, shared, time);
+        let check_action = |proto: &mut Hcp2Protocol, request: &HpToLp, status: &mut LpToHp, time: u32, expected_r2, expected_r3| {
+            let resp = proto.prepare_poll_response(8, request, status, time);
             assert_eq!(resp[2], expected_r2, "Reg2 mismatch at time {}", time);
             assert_eq!(resp[3], expected_r3, "Reg3 mismatch at time {}", time);
         };
 
         let test_cases = [
-            (CMD_OPEN,         0x0210, 0x0000, 0x0110, 0x0000),
-            (CMD_CLOSE,        0x0220, 0x0000, 0x0120, 0x0000),
-            (CMD_STOP,         0x0240, 0x0000, 0x0140, 0x0000),
-            (CMD_HALF_OPEN,    0x0200, 0x0400, 0x0100, 0x0400),
-            (CMD_VENT,         0x0200, 0x4000, 0x0100, 0x4000),
-            (CMD_TOGGLE_LIGHT, 0x0100, 0x0200, 0x0800, 0x0200),
+            (DriveAction::Open,         0x0210, 0x0000, 0x0110, 0x0000),
+            (DriveAction::Close,        0x0220, 0x0000, 0x0120, 0x0000),
+            (DriveAction::Stop,         0x0240, 0x0000, 0x0140, 0x0000),
+            (DriveAction::HalfOpen,    0x0200, 0x0400, 0x0100, 0x0400),
+            (DriveAction::Vent,         0x0200, 0x4000, 0x0100, 0x4000),
+            (DriveAction::ToggleLight, 0x0100, 0x0200, 0x0800, 0x0200),
         ];
 
         let mut current_time = 1000;
 
-        for (cmd, press_r2, press_r3, rel_r2, rel_r3) in test_cases.iter() {
-            shared.command_request = *cmd;
-            check_action(&mut proto, &shared, current_time, *press_r2, *press_r3);
-            check_action(&mut proto, &shared, current_time + 499, *press_r2, *press_r3);
-            check_action(&mut proto, &shared, current_time + 500, *rel_r2, *rel_r3);
-            current_time += 2000;
+        for (seq, (cmd, press_r2, press_r3, rel_r2, rel_r3)) in test_cases.iter().enumerate() {
+            assert!(request.push_command(status.cmd_tail, seq as u8, *cmd));
+            check_action(&mut proto, &request, &mut status, current_time, *press_r2, *press_r3);
+            check_action(&mut proto, &request, &mut status, current_time + 499, *press_r2, *press_r3);
+            check_action(&mut proto, &request, &mut status, current_time + 500, *rel_r2, *rel_r3);
+            // Release is sent exactly once
+            check_action(&mut proto, &request, &mut status, current_time + 600, 0x0000, 0x0000);
+            proto.check_command_timeout(&mut status, current_time + 500 + ACK_TIMEOUT_MS);
+            assert_eq!(request.pending_commands(status.cmd_tail), 0);
+            current_time += 10000;
+        }
+
+        check_action(&mut proto, &request, &mut status, current_time, 0x0000, 0x0000);
+    }
+
+    #[test]
+    fn test_command_lifecycle_acknowledged() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        let closed = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
+        let opening = [0x0000, 0xC800, 0x0100, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
+        proto.handle_status_update(&closed, &mut status);
+
+        assert!(request.push_command(status.cmd_tail, 7, DriveAction::Open));
+        assert_eq!(status.find_result(7), None);
+
+        proto.prepare_poll_response(8, &request, &mut status, 1000);
+        assert_eq!(status.find_result(7), Some(CommandResult { seq: 7, status: CMD_STATUS_PRESSING }));
+
+        proto.prepare_poll_response(8, &request, &mut status, 1500);
+        assert_eq!(status.find_result(7).unwrap().status, CMD_STATUS_RELEASED);
+
+        // Unchanged broadcast does not count as an acknowledgement
+        proto.handle_status_update(&closed, &mut status);
+        assert_eq!(status.find_result(7).unwrap().status, CMD_STATUS_RELEASED);
+        assert_eq!(request.pending_commands(status.cmd_tail), 1);
+
+        proto.handle_status_update(&opening, &mut status);
+        assert_eq!(status.find_result(7).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+        assert_eq!(request.pending_commands(status.cmd_tail), 0);
+    }
+
+    #[test]
+    fn test_command_lifecycle_ack_during_press() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        let light_off = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
+        let light_on = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
+        proto.handle_status_update(&light_off, &mut status);
+
+        request.push_command(status.cmd_tail, 1, DriveAction::ToggleLight);
+        proto.prepare_poll_response(8, &request, &mut status, 0);
+        proto.handle_status_update(&light_on, &mut status);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_PRESSING);
+
+        proto.prepare_poll_response(8, &request, &mut status, 500);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    }
+
+    #[test]
+    fn test_command_lifecycle_timeout() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        request.push_command(status.cmd_tail, 3, DriveAction::Stop);
+
+        proto.prepare_poll_response(8, &request, &mut status, 0);
+        proto.prepare_poll_response(8, &request, &mut status, 500);
+        assert_eq!(status.find_result(3).unwrap().status, CMD_STATUS_RELEASED);
+
+        proto.check_command_timeout(&mut status, 500 + ACK_TIMEOUT_MS - 1);
+        assert_eq!(status.find_result(3).unwrap().status, CMD_STATUS_RELEASED);
+        proto.check_command_timeout(&mut status, 500 + ACK_TIMEOUT_MS);
+        assert_eq!(status.find_result(3).unwrap().status, CMD_STATUS_TIMED_OUT);
+    }
+
+    #[test]
+    fn test_light_on_off_only_toggles_when_needed() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        let light_off = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
+        let light_on = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
+        proto.handle_status_update(&light_off, &mut status);
+
+        // Already off: nothing pressed
+        request.push_command(status.cmd_tail, 1, DriveAction::LightOff);
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 0);
+        assert_eq!((regs[2], regs[3]), (0, 0));
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+
+        // Off -> on: toggles and waits for the light bit
+        request.push_command(status.cmd_tail, 2, DriveAction::LightOn);
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 100);
+        assert_eq!((regs[2], regs[3]), (0x0100, 0x0200));
+        proto.prepare_poll_response(8, &request, &mut status, 600);
+        // A state change alone is not the light
+        proto.handle_status_update(&[0x0000, 0x0000, 0x0100, 0, 0, 0, 0, 0, 0], &mut status);
+        assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_RELEASED);
+        proto.handle_status_update(&light_on, &mut status);
+        assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    }
+
+    #[test]
+    fn test_light_command_waits_for_first_broadcast() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        let light_on = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
+        request.push_command(status.cmd_tail, 1, DriveAction::LightOff);
+
+        // Light state unknown: nothing pressed, the command stays queued
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 0);
+        assert_eq!((regs[2], regs[3]), (0, 0));
+        assert_eq!(status.find_result(1), None);
+        assert_eq!(request.pending_commands(status.cmd_tail), 1);
+
+        // The light turns out to be on: toggled off
+        proto.handle_status_update(&light_on, &mut status);
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 100);
+        assert_eq!((regs[2], regs[3]), (0x0100, 0x0200));
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_PRESSING);
+    }
+
+    #[test]
+    fn test_light_command_retried_once() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        proto.handle_status_update(&[0x0000, 0x0000, 0x4000, 0, 0, 0, 0, 0, 0], &mut status);
+        request.push_command(status.cmd_tail, 1, DriveAction::LightOn);
+
+        proto.prepare_poll_response(8, &request, &mut status, 0);
+        proto.prepare_poll_response(8, &request, &mut status, 500);
+        proto.check_command_timeout(&mut status, 500 + LIGHT_ACK_TIMEOUT_MS);
+
+        // Drive ignored the first toggle: pressed again
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_PRESSING);
+        let now = 500 + LIGHT_ACK_TIMEOUT_MS;
+        let regs = proto.prepare_poll_response(8, &request, &mut status, now + 100);
+        assert_eq!((regs[2], regs[3]), (0x0100, 0x0200));
+        proto.prepare_poll_response(8, &request, &mut status, now + 500);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_RELEASED);
+
+        // Ignored again: gives up
+        proto.check_command_timeout(&mut status, now + 500 + LIGHT_ACK_TIMEOUT_MS);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_TIMED_OUT);
+        assert_eq!(request.pending_commands(status.cmd_tail), 0);
+    }
+
+    /// Status broadcast registers for `position` and drive `state`.
+    fn position_regs(position: u8, state: DriveState) -> [u16; 9] {
+        [0x0000, position as u16, (u8::from(state) as u16) << 8, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000]
+    }
+
+    #[test]
+    fn test_go_to_position_stops_and_learns_overshoot() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp { target_position: 100, ..Default::default() };
+        let mut status = LpToHp::default();
+        proto.handle_status_update(&position_regs(0, DriveState::Closed), &mut status);
+        request.push_command(status.cmd_tail, 1, DriveAction::GoToPosition);
+
+        // Opens like a normal command
+        let open = proto.prepare_poll_response(8, &request, &mut status, 0);
+        assert_eq!((open[2], open[3]), (0x0210, 0x0000));
+        proto.prepare_poll_response(8, &request, &mut status, 500);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_RELEASED);
+
+        // Nothing to press while travelling, the drive's own target stays separate
+        proto.handle_status_update(&[0x0000, 0xC850, 0x0100, 0, 0, 0, 0, 0, 0], &mut status);
+        assert_eq!(status.drive_target_position, 200);
+        let idle = proto.prepare_poll_response(8, &request, &mut status, 1000);
+        assert_eq!((idle[2], idle[3]), (0, 0));
+
+        // Set-point reached: stop, then wait for the door to stand still
+        proto.handle_status_update(&position_regs(100, DriveState::Opening), &mut status);
+        let stop = proto.prepare_poll_response(8, &request, &mut status, 1500);
+        assert_eq!((stop[2], stop[3]), (0x0240, 0x0000));
+        proto.prepare_poll_response(8, &request, &mut status, 2000);
+        proto.handle_status_update(&position_regs(104, DriveState::Opening), &mut status);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_RELEASED);
+        proto.handle_status_update(&position_regs(106, DriveState::Stopped), &mut status);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+        assert_eq!(proto.position_overshoot(), 3);
+        assert_eq!(status.position_overshoot, 3);
+
+        // Next move down stops 3 units early
+        request.target_position = 50;
+        request.push_command(status.cmd_tail, 2, DriveAction::GoToPosition);
+        let close = proto.prepare_poll_response(8, &request, &mut status, 3000);
+        assert_eq!((close[2], close[3]), (0x0220, 0x0000));
+        proto.prepare_poll_response(8, &request, &mut status, 3500);
+        proto.handle_status_update(&position_regs(54, DriveState::Closing), &mut status);
+        let idle = proto.prepare_poll_response(8, &request, &mut status, 4000);
+        assert_eq!((idle[2], idle[3]), (0, 0));
+        proto.handle_status_update(&position_regs(53, DriveState::Closing), &mut status);
+        let stop = proto.prepare_poll_response(8, &request, &mut status, 4100);
+        assert_eq!((stop[2], stop[3]), (0x0240, 0x0000));
+    }
+
+    #[test]
+    fn test_go_to_position_edge_cases() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp { target_position: 101, ..Default::default() };
+        let mut status = LpToHp::default();
+        proto.handle_status_update(&position_regs(100, DriveState::Stopped), &mut status);
+
+        // Within tolerance: done without pressing anything
+        request.push_command(status.cmd_tail, 1, DriveAction::GoToPosition);
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 0);
+        assert_eq!((regs[2], regs[3]), (0, 0));
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+
+        // End positions are a plain close, acknowledged by the state change
+        request.target_position = POSITION_CLOSED;
+        request.push_command(status.cmd_tail, 2, DriveAction::GoToPosition);
+        let regs = proto.prepare_poll_response(8, &request, &mut status, 100);
+        assert_eq!((regs[2], regs[3]), (0x0220, 0x0000));
+        proto.prepare_poll_response(8, &request, &mut status, 600);
+        proto.handle_status_update(&position_regs(99, DriveState::Closing), &mut status);
+        assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+
+        // Door blocked half-way: times out once the position stops changing
+        request.target_position = 150;
+        request.push_command(status.cmd_tail, 3, DriveAction::GoToPosition);
+        proto.prepare_poll_response(8, &request, &mut status, 1000);
+        proto.prepare_poll_response(8, &request, &mut status, 1500);
+        proto.handle_status_update(&position_regs(120, DriveState::Stopped), &mut status);
+        proto.check_command_timeout(&mut status, 2000);
+        proto.check_command_timeout(&mut status, 2000 + ACK_TIMEOUT_MS - 1);
+        assert_eq!(status.find_result(3).unwrap().status, CMD_STATUS_RELEASED);
+        proto.check_command_timeout(&mut status, 2000 + ACK_TIMEOUT_MS);
+        assert_eq!(status.find_result(3).unwrap().status, CMD_STATUS_TIMED_OUT);
+    }
+
+    #[test]
+    fn test_mailbox_back_to_back_commands() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        let off = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000];
+        let on = [0x0000, 0x0000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
+        let opening = [0x0000, 0xC800, 0x0110, 0x0000, 0x0000, 0x0000, 0x0010, 0x0000, 0x0000];
+        proto.handle_status_update(&off, &mut status);
+
+        // Written before the driver ever polls: neither may overwrite the other
+        assert!(request.push_command(status.cmd_tail, 10, DriveAction::ToggleLight));
+        assert!(request.push_command(status.cmd_tail, 11, DriveAction::Open));
+
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 0)[2], 0x0100);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 500)[2], 0x0800);
+        proto.handle_status_update(&on, &mut status);
+        assert_eq!(status.find_result(10).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 600)[2], 0x0210);
+        assert_eq!(status.find_result(11).unwrap().status, CMD_STATUS_PRESSING);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 1100)[2], 0x0110);
+        proto.handle_status_update(&opening, &mut status);
+        assert_eq!(status.find_result(11).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    }
+
+    #[test]
+    fn test_mailbox_same_command_twice() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+
+        request.push_command(status.cmd_tail, 1, DriveAction::Open);
+        request.push_command(status.cmd_tail, 2, DriveAction::Open);
+
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 0)[2], 0x0210);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 500)[2], 0x0110);
+        proto.check_command_timeout(&mut status, 500 + ACK_TIMEOUT_MS);
+
+        // The second, identical command gets its own full press
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 6000)[2], 0x0210);
+        assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_PRESSING);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 6500)[2], 0x0110);
+    }
+
+    #[test]
+    fn test_mailbox_full_and_rejected() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+
+        assert!(request.push_command(status.cmd_tail, 1, DriveAction::None));
+        request.commands[0].action = 0x7F;
+        for seq in 2..=CMD_QUEUE_LEN as u8 {
+            assert!(request.push_command(status.cmd_tail, seq, DriveAction::Stop));
         }
+        assert!(!request.push_command(status.cmd_tail, 99, DriveAction::Stop), "Mailbox should be full");
 
-        shared.command_request = CMD_NONE;
-        check_action(&mut proto, &shared, current_time, 0x0000, 0x0000);
+        // Invalid raw value is skipped and the next entry starts right away
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 0)[2], 0x0240);
+        assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_INVALID);
+
+        // A valid action that is not a button is rejected
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        assert!(request.push_command(status.cmd_tail, 5, DriveAction::None));
+        proto.prepare_poll_response(8, &request, &mut status, 0);
+        assert_eq!(status.find_result(5).unwrap().status, CMD_STATUS_REJECTED);
+        assert!(request.push_command(status.cmd_tail, 99, DriveAction::Stop));
     }
 
     #[test]
     fn test_dispatch_frame_busscan() {
-        let mut proto = Hcp2Protocol::new();
-        let mut shared = SharedData::default();
+        let mut proto = Hcp2Protocol::default();
+        let mailbox = HpToLp::default();
+        let mut status = LpToHp::default();
         // Byte sequence for a valid bus scan request
         let request = [
             0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 
@@ -340,7 +1080,7 @@ mod tests {
         let mut response = [0u8; 32];
         
         // Note: We use 0 as current time for the test
-        let result = proto.dispatch_frame(&request, &mut response, &mut shared, 0);
+        let result = proto.dispatch_frame(&request, &mut response, &mailbox, &mut status, 0);
         
         assert!(result.is_ok(), "Bus scan should be parsed successfully");
         let len = result.unwrap();
@@ -362,9 +1102,292 @@ mod tests {
     }
 
     #[test]
-    fn test_crc() {
-        let request = [0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02];
-        let crc = crc16(&request);
-        assert_eq!(crc, 0x35F8); 
+    fn test_configured_address_and_identity() {
+        let config = Hcp2Config { bus_addresses: [0x05, 0, 0, 0], scan_identity: [0x1111, 0x2222, 0x3333], ..Default::default() };
+        let mut proto = Hcp2Protocol::new(config);
+        let mailbox = HpToLp::default();
+        let mut status = LpToHp::default();
+        let mut response = [0u8; 32];
+
+        // Scan on the default address is no longer for us
+        let (frame, len) = with_crc(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02]);
+        assert_eq!(proto.dispatch_frame(&frame[..len], &mut response, &mailbox, &mut status, 0), Err(DispatchError::InvalidAddress));
+
+        let (frame, len) = with_crc(&[0x05, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02]);
+        assert_eq!(proto.dispatch_frame(&frame[..len], &mut response, &mailbox, &mut status, 0), Ok(15));
+        assert_eq!(response[0], 0x05);
+        assert_eq!(&response[7..13], &[0x11, 0x11, 0x22, 0x22, 0x33, 0x33]);
+    }
+
+    #[test]
+    fn test_configured_press_duration() {
+        let mut proto = Hcp2Protocol::new(Hcp2Config { press_duration_ms: 200, ..Default::default() });
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        assert!(request.push_command(status.cmd_tail, 1, DriveAction::Stop));
+
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 0)[2], 0x0240);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 199)[2], 0x0240);
+        assert_eq!(proto.prepare_poll_response(8, &request, &mut status, 200)[2], 0x0140);
+    }
+
+    const SCAN_BODY: [u8; 17] = [0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02];
+
+    fn dispatch_to(proto: &mut Hcp2Protocol, address: u8, body: &[u8], response: &mut [u8]) -> Result<usize, DispatchError> {
+        let mut addressed = [0u8; 62];
+        addressed[..body.len()].copy_from_slice(body);
+        addressed[0] = address;
+        let (frame, len) = with_crc(&addressed[..body.len()]);
+        proto.dispatch_frame(&frame[..len], response, &HpToLp::default(), &mut LpToHp::default(), 0)
+    }
+
+    #[test]
+    fn test_broadcast_poll_is_processed_but_not_answered() {
+        let mut proto = Hcp2Protocol::default();
+        let mut response = [0u8; 32];
+        // Sync write carried by a broadcast poll still updates the counter
+        let mut body = SCAN_BODY;
+        body[11] = 0x07;
+        body[12] = 0x42;
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &body, &mut response), Ok(0));
+        assert_eq!(response, [0u8; 32]);
+        assert_ne!(proto.last_frame_kind(), FrameKind::BusScan);
+        assert_eq!(proto.counter, 0x07);
+        assert_eq!(proto.command_code, 0x42);
+
+        // Idle and action polls as well
+        for qty in [2, 8] {
+            body[5] = qty;
+            assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &body, &mut response), Ok(0));
+        }
+        assert_eq!(response, [0u8; 32]);
+    }
+
+    #[test]
+    fn test_unicast_write_is_answered() {
+        let mut proto = Hcp2Protocol::default();
+        let mut response = [0u8; 32];
+        let sync = [0x02, 0x10, 0x9C, 0x41, 0x00, 0x01, 0x02, 0x05, 0x00];
+
+        let len = dispatch_to(&mut proto, ADDRESS_HCP, &sync, &mut response).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(&response[..6], &[ADDRESS_HCP, 0x10, 0x9C, 0x41, 0x00, 0x01]);
+        assert_eq!(crc16(&response[..6]), (response[6] as u16) | ((response[7] as u16) << 8));
+        assert_eq!(proto.counter, 0x05);
+
+        // Same write as a broadcast is applied silently
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &sync, &mut response[8..]), Ok(0));
+        assert_eq!(&response[8..], &[0u8; 24]);
+    }
+
+    #[test]
+    fn test_multiple_unicast_addresses() {
+        let config = Hcp2Config { bus_addresses: [0x02, 0x03, 0, 0], ..Default::default() };
+        let mut proto = Hcp2Protocol::new(config);
+        let mut response = [0u8; 32];
+
+        for address in [0x02, 0x03] {
+            assert_eq!(dispatch_to(&mut proto, address, &SCAN_BODY, &mut response), Ok(15));
+            assert_eq!(response[0], address, "Response must come from the polled address");
+            assert_eq!(proto.last_frame_kind(), FrameKind::BusScan);
+        }
+        for address in [0x01, 0x04, 0xF7] {
+            assert_eq!(dispatch_to(&mut proto, address, &SCAN_BODY, &mut response), Err(DispatchError::InvalidAddress));
+        }
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &SCAN_BODY, &mut response), Ok(0));
+    }
+
+    #[test]
+    fn test_decode_frame_both_directions() {
+        let (frame, len) = with_crc(&SCAN_BODY);
+        match BusFrame::decode(&frame[..len]) {
+            Ok(BusFrame::Request(Request::ReadWrite(ReadWriteRequest { address, read_start, read_qty, write }))) => {
+                assert_eq!((address, read_start, read_qty), (0x02, ADDR_POLL, 5));
+                assert_eq!(write.start, ADDR_SYNC_COUNTER);
+                assert_eq!(write.as_slice(), &[0x0002, 0x0000, 0x0102]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Our own bus scan answer
+        let mut response = [0u8; 32];
+        let len = Hcp2Protocol::default()
+            .dispatch_frame(&frame[..len], &mut response, &HpToLp::default(), &mut LpToHp::default(), 0)
+            .unwrap();
+        match BusFrame::decode(&response[..len]) {
+            Ok(BusFrame::ReadResponse(ReadResponse { address, regs })) => {
+                assert_eq!(address, 0x02);
+                assert_eq!(&regs.as_slice()[2..], &[0x0430, 0x10FF, 0xA845]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (frame, len) = with_crc(&[0x03, 0x10, 0x9C, 0x41, 0x00, 0x01]);
+        assert_eq!(
+            BusFrame::decode(&frame[..len]),
+            Ok(BusFrame::WriteResponse(WriteResponse { address: 0x03, start: 0x9C41, quantity: 1 }))
+        );
+
+        let (frame, len) = with_crc(&[0x03, 0x97, 0x02]);
+        assert_eq!(
+            BusFrame::decode(&frame[..len]),
+            Ok(BusFrame::Exception(ExceptionResponse { address: 0x03, function: 0x17, code: 0x02 }))
+        );
+
+        // Neither a valid request nor a valid response
+        let (frame, len) = with_crc(&[0x03, 0x17, 0x05, 0x00, 0x00, 0x00]);
+        assert!(BusFrame::decode(&frame[..len]).is_err());
+    }
+
+    #[test]
+    fn test_observe_frame_applies_broadcasts_and_rejects_commands() {
+        let mut proto = Hcp2Protocol::default();
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        assert!(request.push_command(status.cmd_tail, 7, DriveAction::Open));
+
+        let mut body = [0u8; 25];
+        body[..7].copy_from_slice(&[0x00, 0x10, 0x9D, 0x31, 0x00, 0x09, 0x12]);
+        body[11] = STATE_OPENING;
+        let (frame, len) = with_crc(&body);
+        proto.observe_frame(&BusFrame::decode(&frame[..len]).unwrap(), &request, &mut status);
+
+        assert_eq!(proto.last_frame_kind(), FrameKind::StatusBroadcast);
+        assert_eq!(status.drive_state(), DriveState::Opening);
+        assert_eq!(status.find_result(7), Some(CommandResult { seq: 7, status: CMD_STATUS_REJECTED }));
+        assert_eq!(request.pending_commands(status.cmd_tail), 0);
+
+        // Polls to other devices are only observed
+        let (frame, len) = with_crc(&SCAN_BODY);
+        proto.observe_frame(&BusFrame::decode(&frame[..len]).unwrap(), &request, &mut status);
+        assert_eq!(proto.last_frame_kind(), FrameKind::Other);
+    }
+
+    fn with_crc(body: &[u8]) -> ([u8; 64], usize) {
+        let mut frame = [0u8; 64];
+        frame[..body.len()].copy_from_slice(body);
+        let crc = crc16(body);
+        frame[body.len()] = (crc & 0xFF) as u8;
+        frame[body.len() + 1] = (crc >> 8) as u8;
+        (frame, body.len() + 2)
+    }
+
+    fn dispatch(body: &[u8]) -> Result<usize, DispatchError> {
+        let (frame, len) = with_crc(body);
+        let mut response = [0u8; 32];
+        Hcp2Protocol::default().dispatch_frame(&frame[..len], &mut response, &HpToLp::default(), &mut LpToHp::default(), 0)
+    }
+
+    #[test]
+    fn test_dispatch_rejects_inconsistent_headers() {
+        // Bus scan with the write quantity claiming 0x7F registers
+        assert_eq!(
+            dispatch(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x7F, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02]),
+            Err(DispatchError::TooManyRegisters)
+        );
+        // Quantity 3 but byte count 4
+        assert_eq!(
+            dispatch(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x04, 0x00, 0x02, 0x00, 0x00]),
+            Err(DispatchError::QuantityMismatch)
+        );
+        // Odd byte count
+        assert_eq!(
+            dispatch(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x05, 0x00, 0x02, 0x00, 0x00, 0x01]),
+            Err(DispatchError::OddByteCount)
+        );
+        // Byte count says 6 but only 4 data bytes follow
+        assert_eq!(
+            dispatch(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00]),
+            Err(DispatchError::LengthMismatch)
+        );
+        // Read quantity larger than a poll response
+        assert_eq!(
+            dispatch(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x40, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02]),
+            Err(DispatchError::TooManyRegisters)
+        );
+        // Status broadcast truncated inside the header
+        assert_eq!(dispatch(&[0x00, 0x10, 0x9D, 0x31]), Err(DispatchError::FrameTooShort));
+    }
+
+    #[test]
+    fn test_dispatch_response_buffer_too_small() {
+        let (frame, len) = with_crc(&[0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02]);
+        let mut response = [0u8; 14];
+        let result = Hcp2Protocol::default().dispatch_frame(&frame[..len], &mut response, &HpToLp::default(), &mut LpToHp::default(), 0);
+        assert_eq!(result, Err(DispatchError::BufferTooSmall));
+    }
+
+    fn exception_of(response: &[u8]) -> ExceptionResponse {
+        match BusFrame::decode(response) {
+            Ok(BusFrame::Exception(exception)) => exception,
+            other => panic!("expected an exception, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exception_responses_disabled_by_default() {
+        let mut proto = Hcp2Protocol::default();
+        let mut response = [0u8; 32];
+        // Unknown function, unknown read address, unknown quantity
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &[0x02, 0x03, 0x9C, 0xB9, 0x00, 0x02], &mut response), Err(DispatchError::InvalidFunction));
+        let mut body = SCAN_BODY;
+        body[2] = 0x12;
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &body, &mut response), Ok(0));
+        let mut body = SCAN_BODY;
+        body[5] = 3;
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &body, &mut response), Ok(ReadResponse::frame_len(3)));
+        assert_eq!(&response[3..9], &[0; 6]);
+    }
+
+    #[test]
+    fn test_exception_responses_per_case() {
+        let mut response = [0u8; 32];
+        let all = EXCEPTION_ILLEGAL_FUNCTION | EXCEPTION_ILLEGAL_DATA_ADDRESS | EXCEPTION_ILLEGAL_DATA_VALUE;
+        let mut proto = Hcp2Protocol::new(Hcp2Config { exception_responses: all, ..Default::default() });
+
+        let read_holding = [0x02, 0x03, 0x9C, 0xB9, 0x00, 0x02];
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &read_holding, &mut response), Ok(ExceptionResponse::FRAME_LEN));
+        assert_eq!(exception_of(&response[..5]), ExceptionResponse { address: ADDRESS_HCP, function: 0x03, code: 0x01 });
+        assert_eq!(proto.last_frame_kind(), FrameKind::Exception);
+
+        // Read from an address other than the poll registers
+        let mut body = SCAN_BODY;
+        body[2] = 0x12;
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &body, &mut response), Ok(ExceptionResponse::FRAME_LEN));
+        assert_eq!(exception_of(&response[..5]).code, ExceptionCode::IllegalDataAddress as u8);
+
+        // Write to an unknown register is not applied
+        let write = [0x02, 0x10, 0x12, 0x34, 0x00, 0x01, 0x02, 0x05, 0x00];
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &write, &mut response), Ok(ExceptionResponse::FRAME_LEN));
+        assert_eq!(exception_of(&response[..5]), ExceptionResponse { address: ADDRESS_HCP, function: 0x10, code: 0x02 });
+
+        // Poll quantities we do not serve, including ones too large to answer at all
+        for qty in [0, 3, 0x40] {
+            let mut body = SCAN_BODY;
+            body[5] = qty;
+            assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &body, &mut response), Ok(ExceptionResponse::FRAME_LEN));
+            assert_eq!(exception_of(&response[..5]).code, ExceptionCode::IllegalDataValue as u8);
+        }
+
+        // Served requests are unaffected, broadcasts are never answered
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &SCAN_BODY, &mut response), Ok(15));
+        assert_eq!(proto.last_frame_kind(), FrameKind::BusScan);
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &read_holding, &mut response), Err(DispatchError::InvalidFunction));
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_BROADCAST, &write, &mut response), Ok(0));
+
+        // Only the enabled cases
+        let mut proto = Hcp2Protocol::new(Hcp2Config { exception_responses: EXCEPTION_ILLEGAL_DATA_VALUE, ..Default::default() });
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &read_holding, &mut response), Err(DispatchError::InvalidFunction));
+        assert_eq!(dispatch_to(&mut proto, ADDRESS_HCP, &write, &mut response), Ok(WriteResponse::FRAME_LEN));
+    }
+
+    #[test]
+    fn test_exception_needs_valid_crc() {
+        let config = Hcp2Config { exception_responses: EXCEPTION_ILLEGAL_FUNCTION, ..Default::default() };
+        let mut proto = Hcp2Protocol::new(config);
+        let mut response = [0u8; 32];
+        let frame = [ADDRESS_HCP, 0x03, 0x9C, 0xB9, 0x00, 0x02, 0x00, 0x00];
+        let result = proto.dispatch_frame(&frame, &mut response, &HpToLp::default(), &mut LpToHp::default(), 0);
+        assert_eq!(result, Err(DispatchError::CrcMismatch));
     }
 }
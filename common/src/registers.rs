@@ -8,36 +8,130 @@ pub const ADDR_STATUS_UPDATE: u16 = 0x9D31;
 pub const ADDR_SYNC_COUNTER: u16 = 0x9C41;
 pub const ADDR_POLL: u16 = 0x9CB9;
 
-#[repr(u8)]
+/// Registers in a 0x9D31 status broadcast.
+pub const STATUS_REGISTER_COUNT: usize = 9;
+
+/// Door position units: 0 = closed, 200 = fully open.
+pub const POSITION_CLOSED: u8 = 0;
+pub const POSITION_OPEN: u8 = 200;
+
+/// Drive state codes (high byte of status register 2).
+pub const STATE_STOPPED: u8 = 0x00;
+pub const STATE_OPENING: u8 = 0x01;
+pub const STATE_CLOSING: u8 = 0x02;
+pub const STATE_MOVE_HALF: u8 = 0x05;
+pub const STATE_MOVE_VENTING: u8 = 0x09;
+pub const STATE_VENT_REACHED: u8 = 0x0A;
+pub const STATE_OPEN: u8 = 0x20;
+pub const STATE_CLOSED: u8 = 0x40;
+pub const STATE_HALF_OPEN_REACHED: u8 = 0x80;
+
+/// Drive state. Codes we have no name for are kept as `Unknown`, so an error or
+/// maintenance state never looks like a normal stop.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DriveState {
-    Stopped = 0x00,
-    Opening = 0x01,
-    Closing = 0x02,
-    MoveHalf = 0x05,
-    MoveVenting = 0x09,
-    VentReached = 0x0A,
-    Open = 0x20,
-    Closed = 0x40,
-    HalfOpenReached = 0x80,
+    Stopped,
+    Opening,
+    Closing,
+    MoveHalf,
+    MoveVenting,
+    VentReached,
+    Open,
+    Closed,
+    HalfOpenReached,
+    Unknown(u8),
 }
 
 impl From<u8> for DriveState {
     fn from(val: u8) -> Self {
         match val {
-            0x01 => DriveState::Opening,
-            0x02 => DriveState::Closing,
-            0x05 => DriveState::MoveHalf,
-            0x09 => DriveState::MoveVenting,
-            0x0A => DriveState::VentReached,
-            0x20 => DriveState::Open,
-            0x40 => DriveState::Closed,
-            0x80 => DriveState::HalfOpenReached,
-            _ => DriveState::Stopped,
+            STATE_STOPPED => DriveState::Stopped,
+            STATE_OPENING => DriveState::Opening,
+            STATE_CLOSING => DriveState::Closing,
+            STATE_MOVE_HALF => DriveState::MoveHalf,
+            STATE_MOVE_VENTING => DriveState::MoveVenting,
+            STATE_VENT_REACHED => DriveState::VentReached,
+            STATE_OPEN => DriveState::Open,
+            STATE_CLOSED => DriveState::Closed,
+            STATE_HALF_OPEN_REACHED => DriveState::HalfOpenReached,
+            code => DriveState::Unknown(code),
+        }
+    }
+}
+
+impl From<DriveState> for u8 {
+    fn from(state: DriveState) -> Self {
+        match state {
+            DriveState::Stopped => STATE_STOPPED,
+            DriveState::Opening => STATE_OPENING,
+            DriveState::Closing => STATE_CLOSING,
+            DriveState::MoveHalf => STATE_MOVE_HALF,
+            DriveState::MoveVenting => STATE_MOVE_VENTING,
+            DriveState::VentReached => STATE_VENT_REACHED,
+            DriveState::Open => STATE_OPEN,
+            DriveState::Closed => STATE_CLOSED,
+            DriveState::HalfOpenReached => STATE_HALF_OPEN_REACHED,
+            DriveState::Unknown(code) => code,
+        }
+    }
+}
+
+impl DriveState {
+    /// Whether the door is travelling.
+    pub fn is_moving(self) -> bool {
+        matches!(self, DriveState::Opening | DriveState::Closing | DriveState::MoveHalf | DriveState::MoveVenting)
+    }
+
+    pub fn is_known(self) -> bool {
+        !matches!(self, DriveState::Unknown(_))
+    }
+}
+
+/// Whether `state` (`LpToHp::current_state`) is one of the `STATE_*` codes, for the C++
+/// side. Any other code is passed through from the drive and says nothing about movement.
+#[no_mangle]
+pub extern "C" fn hcp2_state_is_known(state: u8) -> bool {
+    DriveState::from(state).is_known()
+}
+
+/// Low byte of status register 2. Its meaning is not documented yet, so it is only
+/// exposed bit by bit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StateFlags(pub u8);
+
+impl StateFlags {
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether every bit of `mask` is set.
+    pub fn contains(self, mask: u8) -> bool {
+        self.0 & mask == mask
+    }
+
+    /// Indices (0-7) of the set bits.
+    pub fn set_bits(self) -> impl Iterator<Item = u8> {
+        (0..8).filter(move |bit| self.0 & (1 << bit) != 0)
+    }
+}
+
+/// Status register 2 decoded without losing anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriveStatus {
+    pub state: DriveState,
+    pub flags: StateFlags,
+}
+
+impl From<u16> for DriveStatus {
+    fn from(reg: u16) -> Self {
+        Self {
+            state: DriveState::from((reg >> 8) as u8),
+            flags: StateFlags(reg as u8),
         }
     }
 }
 
+/// Command queued by the HP core. The values are stable, they are stored raw in `CommandEntry::action`.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DriveAction {
@@ -48,4 +142,106 @@ pub enum DriveAction {
     HalfOpen = 4,
     Vent = 5,
     ToggleLight = 6,
-}
\ No newline at end of file
+    /// Drive to `HpToLp::target_position` and stop there.
+    GoToPosition = 7,
+    /// Toggle the light only if it is off
+    LightOn = 8,
+    /// Toggle the light only if it is on
+    LightOff = 9,
+}
+
+/// Registers 2 and 3 of a poll response.
+type ButtonRegisters = (u16, u16);
+
+/// Button registers per action: (pressing, release).
+const ACTION_ENCODING: [(DriveAction, ButtonRegisters, ButtonRegisters); 6] = [
+    (DriveAction::Open, (0x0210, 0x0000), (0x0110, 0x0000)),
+    (DriveAction::Close, (0x0220, 0x0000), (0x0120, 0x0000)),
+    (DriveAction::Stop, (0x0240, 0x0000), (0x0140, 0x0000)),
+    (DriveAction::HalfOpen, (0x0200, 0x0400), (0x0100, 0x0400)),
+    (DriveAction::Vent, (0x0200, 0x4000), (0x0100, 0x4000)),
+    (DriveAction::ToggleLight, (0x0100, 0x0200), (0x0800, 0x0200)),
+];
+
+impl TryFrom<u8> for DriveAction {
+    /// The rejected raw value
+    type Error = u8;
+
+    fn try_from(val: u8) -> Result<Self, u8> {
+        match val {
+            0 => Ok(DriveAction::None),
+            1 => Ok(DriveAction::Open),
+            2 => Ok(DriveAction::Close),
+            3 => Ok(DriveAction::Stop),
+            4 => Ok(DriveAction::HalfOpen),
+            5 => Ok(DriveAction::Vent),
+            6 => Ok(DriveAction::ToggleLight),
+            7 => Ok(DriveAction::GoToPosition),
+            8 => Ok(DriveAction::LightOn),
+            9 => Ok(DriveAction::LightOff),
+            _ => Err(val),
+        }
+    }
+}
+
+impl DriveAction {
+    /// The (pressing, release) registers, or None if the action is not a single button.
+    pub fn registers(self) -> Option<(ButtonRegisters, ButtonRegisters)> {
+        ACTION_ENCODING
+            .iter()
+            .find(|(action, _, _)| *action == self)
+            .map(|(_, press, release)| (*press, *release))
+    }
+
+    /// The action whose pressing registers are `(reg2, reg3)`.
+    pub fn from_pressing(reg2: u16, reg3: u16) -> Option<Self> {
+        ACTION_ENCODING
+            .iter()
+            .find(|(_, press, _)| *press == (reg2, reg3))
+            .map(|(action, _, _)| *action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drive_state_is_lossless() {
+        for code in 0..=u8::MAX {
+            assert_eq!(u8::from(DriveState::from(code)), code);
+        }
+        assert_eq!(DriveState::from(0x10), DriveState::Unknown(0x10));
+        assert!(!DriveState::from(0x10).is_known());
+        assert!(DriveState::from(STATE_STOPPED).is_known());
+        assert!(hcp2_state_is_known(STATE_HALF_OPEN_REACHED));
+        assert!(!hcp2_state_is_known(0x10));
+    }
+
+    #[test]
+    fn test_action_encoding_roundtrip() {
+        for raw in 0..=u8::MAX {
+            let Ok(action) = DriveAction::try_from(raw) else {
+                assert!(raw > DriveAction::LightOff as u8);
+                continue;
+            };
+            assert_eq!(action as u8, raw);
+            if let Some(((r2, r3), _)) = action.registers() {
+                assert_eq!(DriveAction::from_pressing(r2, r3), Some(action));
+            }
+        }
+        assert_eq!(DriveAction::None.registers(), None);
+        assert_eq!(DriveAction::GoToPosition.registers(), None);
+        assert_eq!(DriveAction::LightOn.registers(), None);
+        assert_eq!(DriveAction::from_pressing(0x0000, 0x0000), None);
+    }
+
+    #[test]
+    fn test_drive_status_flags() {
+        let status = DriveStatus::from(0x4081);
+        assert_eq!(status.state, DriveState::Closed);
+        assert!(status.flags.contains(0x80));
+        assert!(!status.flags.contains(0x82));
+        assert!(status.flags.set_bits().eq([0, 7]));
+    }
+}
@@ -1,63 +1,467 @@
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{fence, Ordering};
+
+use crate::config::Hcp2Config;
+use crate::health::DriverHealth;
+use crate::registers::{DriveAction, DriveState, StateFlags, STATUS_REGISTER_COUNT};
+use crate::stats::BusStats;
+use crate::trace::{TraceEntry, TraceRing};
+
+/// First word of every `LayoutHeader` ("HCP2" in memory order).
+pub const SHARED_MAGIC: u32 = 0x3250_4348;
+/// Bump whenever a struct placed in shared memory changes size, order or meaning.
+pub const SHARED_LAYOUT_VERSION: u32 = 6;
+
+/// Number of slots in the HP -> LP command mailbox.
+pub const CMD_QUEUE_LEN: usize = 4;
+
+/// How often a reader retries before giving up on a region that is being written.
+pub const SEQLOCK_READ_RETRIES: usize = 4;
+
+/// One queued command, written by the HP core.
 #[repr(C)]
-#[derive(Debug, Default)]
-pub struct SharedData {
-    /// 0 = Free, 1 = HP core writing, 2 = LP core writing
-    pub owner_flag: u8,
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommandEntry {
+    /// Sequence ID chosen by the producer, echoed in the matching `CommandResult`
+    pub seq: u8,
+    /// Requested `DriveAction`, raw so that a bad value from the other core stays detectable
+    pub action: u8,
+}
 
-    /// HP -> LP: Requested action (0 = None, 1 = Open, 2 = Close, 3 = Stop, 4 = HalfOpen, 5 = Vent, 6 = Light)
-    pub command_request: u8,
-    /// HP -> LP: Target position (0-200)
+impl CommandEntry {
+    pub fn new(seq: u8, action: DriveAction) -> Self {
+        Self { seq, action: action as u8 }
+    }
+
+    /// The requested action, or the raw value if it is not a `DriveAction`.
+    pub fn action(&self) -> Result<DriveAction, u8> {
+        DriveAction::try_from(self.action)
+    }
+}
+
+/// Outcome of a queued command, written by the driver into the entry's slot.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommandResult {
+    /// Sequence ID of the entry this result belongs to
+    pub seq: u8,
+    /// Lifecycle of the command (see `CMD_STATUS_*`)
+    pub status: u8,
+}
+
+/// Everything the HP core publishes for the driver. Only the HP core writes this.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HpToLp {
+    /// Producer index of the command mailbox (free-running)
+    pub cmd_head: u8,
+    /// Set-point for `DriveAction::GoToPosition` (0-200). Read when the command starts,
+    /// so the latest set-point wins over the one current when it was queued.
     pub target_position: u8,
 
-    /// LP -> HP: Current state of the drive
+    pub _pad: [u8; 2],
+
+    /// Command mailbox, slot = index % CMD_QUEUE_LEN
+    pub commands: [CommandEntry; CMD_QUEUE_LEN],
+}
+
+/// Everything the driver publishes for the HP core. Only the driver writes this.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LpToHp {
+    /// Current state code of the drive, unknown codes included (see `STATE_*`)
     pub current_state: u8,
-    /// LP -> HP: Current position (0-200)
+    /// Current position (0-200)
     pub current_position: u8,
-    /// LP -> HP: Light status
+    /// Target position reported by the drive (0-200), not the `HpToLp` set-point
+    pub drive_target_position: u8,
+    /// Light status
     pub light_on: bool,
-    
-    // Explicit padding to align `last_update_ts` to 4 bytes (offsets: 0..5 filled, need 2 bytes to reach 8? No. 0,1,2,3,4,5 = 6 bytes used. Need 2 bytes pad.)
-    pub _pad1: [u8; 2],
 
-    /// LP -> HP: Timestamp of last valid packet
+    /// Timestamp of last valid packet
     pub last_update_ts: u32,
-    /// LP -> HP: Error code
+    /// Error code (see `LinkError`)
     pub error_code: u8,
-    
-    pub _pad2: [u8; 3], // Pad to 16 bytes total
+    /// Consumer index of the command mailbox (free-running)
+    pub cmd_tail: u8,
+    /// A valid frame was seen within the silence timeout
+    pub link_online: bool,
+    /// Learned travel after a stop command, in position units
+    pub position_overshoot: u8,
+    /// Low byte of the state register (see `StateFlags`)
+    pub state_flags: u8,
+    /// Most recent state code without a `DriveState` name
+    pub last_unknown_state: u8,
+
+    pub _pad: [u8; 2],
+
+    /// How often the drive entered a state code without a `DriveState` name
+    pub unknown_states: u32,
+
+    /// Result for the command in the same mailbox slot
+    pub results: [CommandResult; CMD_QUEUE_LEN],
+    /// Drive registers as received, including the ones not decoded above
+    pub raw: RawRegisters,
+}
+
+/// Unprocessed register values as last written by the drive.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RawRegisters {
+    /// All registers of the last 0x9D31 status broadcast
+    pub status: [u16; STATUS_REGISTER_COUNT],
+    /// Last sync register write (counter high byte, command code low byte)
+    pub sync: u16,
+    /// Incremented whenever `status` or the sync command code changes.
+    /// The sync counter alone changes with every poll and is not counted.
+    pub changes: u32,
+}
+
+impl RawRegisters {
+    fn count_change(&mut self) {
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    /// Stores a status broadcast block (at least `STATUS_REGISTER_COUNT` registers).
+    pub fn set_status(&mut self, regs: &[u16]) {
+        let regs = &regs[..STATUS_REGISTER_COUNT];
+        if self.status != regs {
+            self.status.copy_from_slice(regs);
+            self.count_change();
+        }
+    }
+
+    pub fn set_sync(&mut self, reg: u16) {
+        let command_changed = (self.sync ^ reg) & 0x00FF != 0;
+        self.sync = reg;
+        if command_changed {
+            self.count_change();
+        }
+    }
+}
+
+/// A single-writer seqlock around `T`.
+///
+/// The writer makes `generation` odd, writes the payload and makes it even again.
+/// Readers copy the payload and retry if the generation was odd or changed meanwhile,
+/// so neither side ever waits on the other and torn copies are never returned.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SeqLock<T> {
+    pub generation: u32,
+    pub data: T,
+}
+
+impl<T: Copy> SeqLock<T> {
+    fn read_generation(&self) -> u32 { unsafe { core::ptr::read_volatile(&self.generation) } }
+    fn write_generation(&mut self, val: u32) { unsafe { core::ptr::write_volatile(&mut self.generation, val) } }
+
+    /// Publishes a new payload. Must only be called by the region's single writer.
+    pub fn write(&mut self, val: &T) {
+        let gen = self.read_generation();
+        self.write_generation(gen.wrapping_add(1));
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.data, *val) };
+        fence(Ordering::Release);
+        self.write_generation(gen.wrapping_add(2));
+    }
+
+    /// For a writer that was restarted: if its previous run stopped half-way through `write`,
+    /// the generation is still odd and every later write would leave it odd. The payload may be
+    /// torn, so the caller must `write` a fresh one right after.
+    pub fn recover(&mut self) {
+        let gen = self.read_generation();
+        if gen & 1 != 0 {
+            self.write_generation(gen.wrapping_add(1));
+        }
+    }
+
+    /// Returns a consistent snapshot, or None if the writer was busy on every attempt.
+    pub fn read(&self) -> Option<T> {
+        for _ in 0..SEQLOCK_READ_RETRIES {
+            let before = self.read_generation();
+            if before & 1 != 0 {
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let val = unsafe { core::ptr::read_volatile(&self.data) };
+            fence(Ordering::Acquire);
+            if self.read_generation() == before {
+                return Some(val);
+            }
+        }
+        None
+    }
+}
+
+/// Identifies the shared memory layout a binary was built against.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutHeader {
+    /// `SHARED_MAGIC`, 0 until the owner wrote the header
+    pub magic: u32,
+    /// `SHARED_LAYOUT_VERSION`
+    pub version: u32,
+    /// Size of `SharedData` in bytes
+    pub size: u32,
+}
+
+impl LayoutHeader {
+    /// The layout of this build.
+    pub const fn current() -> Self {
+        Self {
+            magic: SHARED_MAGIC,
+            version: SHARED_LAYOUT_VERSION,
+            size: size_of::<SharedData>() as u32,
+        }
+    }
+}
+
+/// Both headers keep their offsets in every layout version, so a mismatch is always detectable.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SharedData {
+    /// HP -> LP: Layout the HP side was built against, written before the driver is started
+    pub layout: LayoutHeader,
+    /// LP -> HP: Layout the driver was built against, written once when the driver starts
+    pub driver_layout: LayoutHeader,
+    /// HP -> LP: Commands and set-points
+    pub hp_to_lp: SeqLock<HpToLp>,
+    /// LP -> HP: Drive status and command results
+    pub lp_to_hp: SeqLock<LpToHp>,
+    /// HP -> LP: Driver tuning, written once before the LP core is started
+    pub config: Hcp2Config,
+    /// LP -> HP: Bus counters
+    pub stats: SeqLock<BusStats>,
+    /// LP -> HP: Heartbeat and last panic of the driver core
+    pub health: DriverHealth,
+}
+
+/// Everything in the LP RAM window the HP core maps in LP mode.
+/// In HP mode the frame trace lives in `hp-firmware` instead.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct LpSharedMemory {
+    pub data: SharedData,
+    /// Recent frames, written by the LP driver
+    pub trace: TraceRing,
 }
 
 impl SharedData {
-    pub fn read_owner(&self) -> u8 { unsafe { core::ptr::read_volatile(&self.owner_flag) } }
-    pub fn write_owner(&mut self, val: u8) { unsafe { core::ptr::write_volatile(&mut self.owner_flag, val) } }
+    /// Driver side of the layout handshake: announces the driver's layout and returns
+    /// whether the HP side uses the same one. On false nothing else in the block may be trusted,
+    /// so the driver must not run.
+    pub fn accept_layout(&mut self) -> bool {
+        let current = LayoutHeader::current();
+        unsafe {
+            core::ptr::write_volatile(&mut self.driver_layout.version, current.version);
+            core::ptr::write_volatile(&mut self.driver_layout.size, current.size);
+            // The magic goes last: once it is visible, the rest of the header is too
+            fence(Ordering::Release);
+            core::ptr::write_volatile(&mut self.driver_layout.magic, current.magic);
+            core::ptr::read_volatile(&self.layout) == current
+        }
+    }
 
-    pub fn read_command(&self) -> u8 { unsafe { core::ptr::read_volatile(&self.command_request) } }
-    pub fn write_command(&mut self, val: u8) { unsafe { core::ptr::write_volatile(&mut self.command_request, val) } }
+    /// Producer side: queues `action` under `seq`. Returns false if the mailbox is full
+    /// or a region could not be read consistently.
+    pub fn push_command(&mut self, seq: u8, action: DriveAction) -> bool {
+        let (Some(mut request), Some(status)) = (self.hp_to_lp.read(), self.lp_to_hp.read()) else {
+            return false;
+        };
+        if !request.push_command(status.cmd_tail, seq, action) {
+            return false;
+        }
+        self.hp_to_lp.write(&request);
+        true
+    }
+}
+
+impl HpToLp {
+    /// Number of commands queued and not yet completed, given the consumer index.
+    pub fn pending_commands(&self, cmd_tail: u8) -> u8 {
+        self.cmd_head.wrapping_sub(cmd_tail)
+    }
+
+    pub fn command(&self, index: u8) -> CommandEntry {
+        self.commands[index as usize % CMD_QUEUE_LEN]
+    }
+
+    /// Queues `action` under `seq`. Returns false if the mailbox is full.
+    pub fn push_command(&mut self, cmd_tail: u8, seq: u8, action: DriveAction) -> bool {
+        if self.pending_commands(cmd_tail) as usize >= CMD_QUEUE_LEN {
+            return false;
+        }
+        self.commands[self.cmd_head as usize % CMD_QUEUE_LEN] = CommandEntry::new(seq, action);
+        self.cmd_head = self.cmd_head.wrapping_add(1);
+        true
+    }
+}
 
-    pub fn read_target_pos(&self) -> u8 { unsafe { core::ptr::read_volatile(&self.target_position) } }
-    pub fn write_target_pos(&mut self, val: u8) { unsafe { core::ptr::write_volatile(&mut self.target_position, val) } }
+impl LpToHp {
+    pub fn drive_state(&self) -> DriveState {
+        DriveState::from(self.current_state)
+    }
 
-    pub fn read_state(&self) -> u8 { unsafe { core::ptr::read_volatile(&self.current_state) } }
-    pub fn write_state(&mut self, val: u8) { unsafe { core::ptr::write_volatile(&mut self.current_state, val) } }
+    pub fn state_flags(&self) -> StateFlags {
+        StateFlags(self.state_flags)
+    }
 
-    pub fn read_current_pos(&self) -> u8 { unsafe { core::ptr::read_volatile(&self.current_position) } }
-    pub fn write_current_pos(&mut self, val: u8) { unsafe { core::ptr::write_volatile(&mut self.current_position, val) } }
+    pub fn set_result(&mut self, index: u8, val: CommandResult) {
+        self.results[index as usize % CMD_QUEUE_LEN] = val;
+    }
 
-    pub fn read_light(&self) -> bool { unsafe { core::ptr::read_volatile(&self.light_on) } }
-    pub fn write_light(&mut self, val: bool) { unsafe { core::ptr::write_volatile(&mut self.light_on, val) } }
+    /// Publishes `CMD_STATUS_PENDING` for queued entries the driver has not started yet, so
+    /// their slots stop showing the result of the command that used them before.
+    pub fn mark_pending(&mut self, request: &HpToLp) {
+        let queued = request.pending_commands(self.cmd_tail).min(CMD_QUEUE_LEN as u8);
+        for offset in 0..queued {
+            let index = self.cmd_tail.wrapping_add(offset);
+            let seq = request.command(index).seq;
+            if self.results[index as usize % CMD_QUEUE_LEN].seq != seq {
+                self.set_result(index, CommandResult { seq, status: CMD_STATUS_PENDING });
+            }
+        }
+    }
 
-    pub fn read_ts(&self) -> u32 { unsafe { core::ptr::read_volatile(&self.last_update_ts) } }
-    pub fn write_ts(&mut self, val: u32) { unsafe { core::ptr::write_volatile(&mut self.last_update_ts, val) } }
+    /// Looks up the result for `seq`. Returns None while the driver has not seen it yet.
+    pub fn find_result(&self, seq: u8) -> Option<CommandResult> {
+        self.results
+            .iter()
+            .copied()
+            .find(|r| r.seq == seq && r.status != CMD_STATUS_IDLE)
+    }
 }
 
-pub const OWNER_FREE: u8 = 0;
-pub const OWNER_HP: u8 = 1;
-pub const OWNER_LP: u8 = 2;
+/// No command has been issued yet.
+pub const CMD_STATUS_IDLE: u8 = 0;
+/// Command is queued and the driver has seen it, but has not started it yet.
+pub const CMD_STATUS_PENDING: u8 = 1;
+/// Driver is sending the "pressing" registers.
+pub const CMD_STATUS_PRESSING: u8 = 2;
+/// "Release" was sent, waiting for the drive to react.
+pub const CMD_STATUS_RELEASED: u8 = 3;
+/// The drive state or light changed after the command was sent
+/// (for `DriveAction::GoToPosition`: the door stopped at the set-point,
+/// for `LightOn`/`LightOff`: the light is in the requested state).
+pub const CMD_STATUS_ACKNOWLEDGED: u8 = 4;
+/// No state change was observed within the acknowledge timeout
+/// (for `DriveAction::GoToPosition`: the position stopped changing before the move finished,
+/// for `LightOn`/`LightOff`: the light did not follow two toggles).
+pub const CMD_STATUS_TIMED_OUT: u8 = 5;
+/// The action cannot be executed (`DriveAction::None`, or the driver is listen-only) and was skipped.
+pub const CMD_STATUS_REJECTED: u8 = 6;
+/// The raw action value is not a `DriveAction`; the entry was skipped.
+pub const CMD_STATUS_INVALID: u8 = 7;
 
-pub const CMD_NONE: u8 = 0;
-pub const CMD_OPEN: u8 = 1;
-pub const CMD_CLOSE: u8 = 2;
-pub const CMD_STOP: u8 = 3;
-pub const CMD_HALF_OPEN: u8 = 4;
-pub const CMD_VENT: u8 = 5;
-pub const CMD_TOGGLE_LIGHT: u8 = 6;
+// Everything below is read by code built from another copy of this crate (the LP image or
+// the generated C++ header). A failing assertion means the layout changed: update the numbers
+// and bump `SHARED_LAYOUT_VERSION`.
+const _: () = {
+    assert!(offset_of!(SharedData, layout) == 0);
+    assert!(offset_of!(SharedData, driver_layout) == 12);
+    assert!(size_of::<LayoutHeader>() == 12);
+
+    assert!(size_of::<CommandEntry>() == 2);
+    assert!(size_of::<CommandResult>() == 2);
+    assert!(offset_of!(HpToLp, commands) == 4);
+    assert!(size_of::<HpToLp>() == 12);
+
+    assert!(offset_of!(LpToHp, last_update_ts) == 4);
+    assert!(offset_of!(LpToHp, unknown_states) == 16);
+    assert!(offset_of!(LpToHp, results) == 20);
+    assert!(offset_of!(LpToHp, raw) == 28);
+    assert!(size_of::<RawRegisters>() == 24);
+    assert!(size_of::<LpToHp>() == 52);
+
+    assert!(size_of::<Hcp2Config>() == 32);
+    assert!(size_of::<BusStats>() == 104);
+
+    assert!(offset_of!(SharedData, hp_to_lp) == 24);
+    assert!(offset_of!(SharedData, lp_to_hp) == 40);
+    assert!(offset_of!(SharedData, config) == 96);
+    assert!(offset_of!(SharedData, stats) == 128);
+    assert!(size_of::<DriverHealth>() == 40);
+    assert!(offset_of!(SharedData, health) == 236);
+    assert!(size_of::<SharedData>() == 276);
+
+    assert!(size_of::<TraceEntry>() == 12 + crate::trace::TRACE_FRAME_BYTES);
+    assert!(offset_of!(LpSharedMemory, trace) == size_of::<SharedData>());
+    assert!(size_of::<LpSharedMemory>() == size_of::<SharedData>() + size_of::<TraceRing>());
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seqlock_roundtrip() {
+        let mut lock: SeqLock<LpToHp> = SeqLock::default();
+        let status = LpToHp { current_position: 42, light_on: true, ..Default::default() };
+        lock.write(&status);
+        assert_eq!(lock.generation, 2);
+        assert_eq!(lock.read(), Some(status));
+    }
+
+    #[test]
+    fn test_seqlock_reader_never_sees_partial_write() {
+        // Writer stopped half-way (odd generation): reader must give up instead of spinning
+        let lock = SeqLock {
+            generation: 3,
+            data: LpToHp { current_position: 99, ..Default::default() },
+        };
+        assert_eq!(lock.read(), None);
+    }
+
+    #[test]
+    fn test_push_command_through_regions() {
+        let mut shared = SharedData::default();
+        for seq in 0..CMD_QUEUE_LEN as u8 {
+            assert!(shared.push_command(seq, DriveAction::Open));
+        }
+        assert!(!shared.push_command(9, DriveAction::Open));
+
+        // Consumer frees one slot
+        let mut status = shared.lp_to_hp.read().unwrap();
+        status.cmd_tail = 1;
+        shared.lp_to_hp.write(&status);
+        assert!(shared.push_command(9, DriveAction::Close));
+
+        let request = shared.hp_to_lp.read().unwrap();
+        assert_eq!(request.command(4), CommandEntry::new(9, DriveAction::Close));
+        assert_eq!(request.command(4).action(), Ok(DriveAction::Close));
+        assert_eq!(CommandEntry { seq: 1, action: 0x7F }.action(), Err(0x7F));
+    }
+
+    #[test]
+    fn test_queued_commands_marked_pending() {
+        let mut request = HpToLp::default();
+        let mut status = LpToHp::default();
+        status.set_result(0, CommandResult { seq: 7, status: CMD_STATUS_ACKNOWLEDGED });
+        assert!(request.push_command(0, 8, DriveAction::Open));
+        assert!(request.push_command(0, 9, DriveAction::Close));
+
+        status.mark_pending(&request);
+        assert_eq!(status.find_result(7), None);
+        assert_eq!(status.find_result(8), Some(CommandResult { seq: 8, status: CMD_STATUS_PENDING }));
+        assert_eq!(status.find_result(9), Some(CommandResult { seq: 9, status: CMD_STATUS_PENDING }));
+
+        // A slot the driver already works on is left alone
+        status.set_result(0, CommandResult { seq: 8, status: CMD_STATUS_PRESSING });
+        status.mark_pending(&request);
+        assert_eq!(status.find_result(8).unwrap().status, CMD_STATUS_PRESSING);
+    }
+
+    #[test]
+    fn test_layout_handshake() {
+        let mut shared = SharedData::default();
+        assert!(!shared.accept_layout());
+        assert_eq!(shared.driver_layout, LayoutHeader::current());
+
+        shared.layout = LayoutHeader { version: SHARED_LAYOUT_VERSION + 1, ..LayoutHeader::current() };
+        assert!(!shared.accept_layout());
+
+        shared.layout = LayoutHeader::current();
+        assert!(shared.accept_layout());
+    }
+}
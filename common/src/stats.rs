@@ -0,0 +1,233 @@
+use crate::framer::FramerError;
+use crate::hal::{LINE_ERROR_FRAMING, LINE_ERROR_OVERRUN, LINE_ERROR_PARITY};
+use crate::protocol::{DispatchError, FrameKind};
+
+/// Bus counters published by the driver. All counters are free-running and wrap.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BusStats {
+    /// Complete frames taken from the RX stream (accepted or not)
+    pub frames_received: u32,
+    /// Responses the bridge transmitted
+    pub responses_sent: u32,
+    /// Frames sent to the broadcast address
+    pub broadcasts: u32,
+    /// Bus scans addressed to us
+    pub bus_scans: u32,
+    /// 2-register polls (nothing to report)
+    pub idle_polls: u32,
+    /// 8-register polls (button registers)
+    pub action_polls: u32,
+    /// Sync counter writes that did not continue the previous value
+    pub sync_jumps: u32,
+    /// Requests answered with a Modbus exception (see `Hcp2Config::exception_responses`)
+    pub exceptions_sent: u32,
+
+    /// Runs of bytes skipped between frames that did not look like a frame at all
+    pub framer_garbage: u32,
+    /// Partial frames dropped at bus silence
+    pub framer_truncated: u32,
+    /// RX buffer overflows
+    pub framer_overflows: u32,
+
+    /// UART reads flagged with a parity error, framing error or FIFO overrun
+    pub line_parity_errors: u32,
+    pub line_framing_errors: u32,
+    pub line_overruns: u32,
+
+    /// One counter per `DispatchError` variant. `crc_mismatch` also counts frames the
+    /// framer skipped for a bad CRC (`FramerError::CrcMismatch`).
+    pub frame_too_short: u32,
+    pub invalid_address: u32,
+    pub invalid_function: u32,
+    pub crc_mismatch: u32,
+    pub quantity_mismatch: u32,
+    pub odd_byte_count: u32,
+    pub too_many_registers: u32,
+    pub length_mismatch: u32,
+    pub buffer_too_small: u32,
+
+    /// Time between consecutive idle/action polls, 0 until two polls were seen
+    pub poll_interval_min_ms: u32,
+    pub poll_interval_max_ms: u32,
+    pub poll_interval_avg_ms: u32,
+}
+
+impl BusStats {
+    pub fn record_error(&mut self, e: DispatchError) {
+        let counter = match e {
+            DispatchError::FrameTooShort => &mut self.frame_too_short,
+            DispatchError::InvalidAddress => &mut self.invalid_address,
+            DispatchError::InvalidFunction => &mut self.invalid_function,
+            DispatchError::CrcMismatch => &mut self.crc_mismatch,
+            DispatchError::QuantityMismatch => &mut self.quantity_mismatch,
+            DispatchError::OddByteCount => &mut self.odd_byte_count,
+            DispatchError::TooManyRegisters => &mut self.too_many_registers,
+            DispatchError::LengthMismatch => &mut self.length_mismatch,
+            DispatchError::BufferTooSmall => &mut self.buffer_too_small,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+
+    /// Counts every class set in `errors` (`LINE_ERROR_*` bits) once.
+    pub fn record_line_errors(&mut self, errors: u8) {
+        for (bit, counter) in [
+            (LINE_ERROR_PARITY, &mut self.line_parity_errors),
+            (LINE_ERROR_FRAMING, &mut self.line_framing_errors),
+            (LINE_ERROR_OVERRUN, &mut self.line_overruns),
+        ] {
+            if errors & bit != 0 {
+                *counter = counter.wrapping_add(1);
+            }
+        }
+    }
+
+    pub fn record_framer_error(&mut self, e: FramerError) {
+        let counter = match e {
+            FramerError::Garbage => &mut self.framer_garbage,
+            FramerError::CrcMismatch => &mut self.crc_mismatch,
+            FramerError::Truncated => &mut self.framer_truncated,
+            FramerError::Overflow => &mut self.framer_overflows,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Driver-side bookkeeping behind `BusStats`.
+#[derive(Debug, Default)]
+pub struct StatsCollector {
+    stats: BusStats,
+    last_poll_ms: Option<u32>,
+    interval_total_ms: u64,
+    interval_count: u32,
+}
+
+impl StatsCollector {
+    pub fn stats(&self) -> &BusStats {
+        &self.stats
+    }
+
+    pub fn on_frame(&mut self) {
+        self.stats.frames_received = self.stats.frames_received.wrapping_add(1);
+    }
+
+    pub fn on_response(&mut self) {
+        self.stats.responses_sent = self.stats.responses_sent.wrapping_add(1);
+    }
+
+    pub fn on_broadcast(&mut self) {
+        self.stats.broadcasts = self.stats.broadcasts.wrapping_add(1);
+    }
+
+    pub fn on_sync_jumps(&mut self, total: u32) {
+        self.stats.sync_jumps = total;
+    }
+
+    pub fn on_error(&mut self, e: DispatchError) {
+        self.stats.record_error(e);
+    }
+
+    pub fn on_line_errors(&mut self, errors: u8) {
+        self.stats.record_line_errors(errors);
+    }
+
+    pub fn on_framer_error(&mut self, e: FramerError) {
+        self.stats.record_framer_error(e);
+    }
+
+    /// Counts an accepted frame addressed to us.
+    pub fn on_accepted(&mut self, kind: FrameKind, now_ms: u32) {
+        match kind {
+            FrameKind::BusScan => {
+                self.stats.bus_scans = self.stats.bus_scans.wrapping_add(1);
+                // The drive (re)starts its poll cycle after a scan
+                self.last_poll_ms = None;
+            }
+            FrameKind::IdlePoll => {
+                self.stats.idle_polls = self.stats.idle_polls.wrapping_add(1);
+                self.on_poll(now_ms);
+            }
+            FrameKind::ActionPoll => {
+                self.stats.action_polls = self.stats.action_polls.wrapping_add(1);
+                self.on_poll(now_ms);
+            }
+            FrameKind::Exception => {
+                self.stats.exceptions_sent = self.stats.exceptions_sent.wrapping_add(1);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_poll(&mut self, now_ms: u32) {
+        if let Some(last) = self.last_poll_ms {
+            let interval = now_ms.wrapping_sub(last);
+            let stats = &mut self.stats;
+            if self.interval_count == 0 || interval < stats.poll_interval_min_ms {
+                stats.poll_interval_min_ms = interval;
+            }
+            stats.poll_interval_max_ms = stats.poll_interval_max_ms.max(interval);
+
+            self.interval_total_ms += interval as u64;
+            self.interval_count = self.interval_count.saturating_add(1);
+            stats.poll_interval_avg_ms = (self.interval_total_ms / self.interval_count as u64) as u32;
+        }
+        self.last_poll_ms = Some(now_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_intervals() {
+        let mut collector = StatsCollector::default();
+        collector.on_accepted(FrameKind::IdlePoll, 1000);
+        assert_eq!(collector.stats().poll_interval_min_ms, 0);
+
+        collector.on_accepted(FrameKind::ActionPoll, 1100);
+        collector.on_accepted(FrameKind::IdlePoll, 1400);
+        collector.on_accepted(FrameKind::StatusBroadcast, 1450);
+        collector.on_accepted(FrameKind::Exception, 1500);
+        collector.on_accepted(FrameKind::IdlePoll, 1600);
+
+        let stats = collector.stats();
+        assert_eq!(stats.idle_polls, 3);
+        assert_eq!(stats.action_polls, 1);
+        assert_eq!(stats.exceptions_sent, 1);
+        assert_eq!(stats.poll_interval_min_ms, 100);
+        assert_eq!(stats.poll_interval_max_ms, 300);
+        assert_eq!(stats.poll_interval_avg_ms, 200);
+    }
+
+    #[test]
+    fn test_scan_restarts_interval() {
+        let mut collector = StatsCollector::default();
+        collector.on_accepted(FrameKind::IdlePoll, 0);
+        collector.on_accepted(FrameKind::BusScan, 5000);
+        collector.on_accepted(FrameKind::IdlePoll, 9000);
+        assert_eq!(collector.stats().bus_scans, 1);
+        assert_eq!(collector.stats().poll_interval_max_ms, 0);
+
+        collector.on_accepted(FrameKind::IdlePoll, 9050);
+        assert_eq!(collector.stats().poll_interval_max_ms, 50);
+    }
+
+    #[test]
+    fn test_each_error_has_its_own_counter() {
+        let mut stats = BusStats::default();
+        stats.record_error(DispatchError::CrcMismatch);
+        stats.record_error(DispatchError::CrcMismatch);
+        stats.record_error(DispatchError::InvalidAddress);
+        stats.record_framer_error(FramerError::Overflow);
+        stats.record_framer_error(FramerError::CrcMismatch);
+        assert_eq!(stats.crc_mismatch, 3);
+        assert_eq!(stats.invalid_address, 1);
+        assert_eq!(stats.framer_overflows, 1);
+        assert_eq!(stats.frame_too_short, 0);
+
+        stats.record_line_errors(LINE_ERROR_PARITY | LINE_ERROR_OVERRUN);
+        stats.record_line_errors(LINE_ERROR_PARITY);
+        assert_eq!((stats.line_parity_errors, stats.line_framing_errors, stats.line_overruns), (2, 0, 1));
+    }
+}
@@ -0,0 +1,239 @@
+use core::sync::atomic::{fence, Ordering};
+
+use crate::codec::BusFrame;
+use crate::framer::FramerError;
+use crate::protocol::DispatchError;
+use crate::shared::SeqLock;
+
+/// Number of frames kept in a `TraceRing`.
+pub const TRACE_LEN: usize = 16;
+/// Bytes stored per frame. Longer frames are cut off; `TraceEntry::len` keeps the real length.
+pub const TRACE_FRAME_BYTES: usize = 32;
+/// `TraceEntry::result` of a frame that was accepted (otherwise a `DispatchError` value,
+/// or one of the `TRACE_RESULT_*` framer codes below for bytes that never made a frame).
+pub const TRACE_RESULT_OK: u8 = 0;
+/// Bytes skipped to resynchronise (`FramerError::Garbage`).
+pub const TRACE_RESULT_GARBAGE: u8 = 0x80;
+/// A complete frame that failed its CRC (`FramerError::CrcMismatch`).
+pub const TRACE_RESULT_CRC_MISMATCH: u8 = 0x81;
+/// The bus went quiet in the middle of a frame (`FramerError::Truncated`).
+pub const TRACE_RESULT_TRUNCATED: u8 = 0x82;
+/// The receive buffer overflowed; no bytes are kept (`FramerError::Overflow`).
+pub const TRACE_RESULT_OVERFLOW: u8 = 0x83;
+
+/// Which way a traced frame went, seen from the bridge.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the bus (drive requests, and other devices' responses when listening)
+    Rx = 0,
+    /// Sent by the bridge
+    Tx = 1,
+}
+
+/// Receives every frame the driver sees or sends, with its decode or dispatch result.
+pub trait TraceSink {
+    /// `raw` is the complete frame including CRC.
+    fn on_frame(&mut self, now_ms: u32, direction: Direction, raw: &[u8], result: Result<&BusFrame, DispatchError>);
+
+    /// Received bytes the framer rejected, see `FrameAssembler::rejected`. Ignored by default.
+    fn on_framer_error(&mut self, _now_ms: u32, _raw: &[u8], _error: FramerError) {}
+}
+
+/// Discards everything; used by `Hcp2Driver::poll`.
+pub struct NoTrace;
+
+impl TraceSink for NoTrace {
+    fn on_frame(&mut self, _now_ms: u32, _direction: Direction, _raw: &[u8], _result: Result<&BusFrame, DispatchError>) {}
+}
+
+/// One recorded frame.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Position in the trace, starting at 1 (0 = slot never written)
+    pub seq: u32,
+    pub timestamp_ms: u32,
+    /// `Direction`
+    pub direction: u8,
+    /// Length of the frame on the bus
+    pub len: u8,
+    /// `TRACE_RESULT_OK`, the `DispatchError` value or a `TRACE_RESULT_*` framer code
+    pub result: u8,
+
+    pub _pad: u8,
+
+    /// First `TRACE_FRAME_BYTES` bytes of the frame
+    pub bytes: [u8; TRACE_FRAME_BYTES],
+}
+
+impl TraceEntry {
+    /// The stored bytes (possibly fewer than `len`).
+    pub fn frame(&self) -> &[u8] {
+        &self.bytes[..(self.len as usize).min(TRACE_FRAME_BYTES)]
+    }
+}
+
+/// Ring buffer of the most recent frames.
+///
+/// Only the driver writes it. Each slot is its own seqlock and carries its sequence
+/// number, so a reader on the other core can copy it at any time and drop slots that
+/// were overwritten meanwhile.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct TraceRing {
+    /// Number of frames ever recorded (free-running)
+    pub head: u32,
+    pub entries: [SeqLock<TraceEntry>; TRACE_LEN],
+}
+
+impl TraceRing {
+    /// Empty ring, usable in a `static`.
+    pub const fn new() -> Self {
+        const EMPTY: SeqLock<TraceEntry> = SeqLock {
+            generation: 0,
+            data: TraceEntry {
+                seq: 0,
+                timestamp_ms: 0,
+                direction: 0,
+                len: 0,
+                result: 0,
+                _pad: 0,
+                bytes: [0; TRACE_FRAME_BYTES],
+            },
+        };
+        Self { head: 0, entries: [EMPTY; TRACE_LEN] }
+    }
+
+    pub fn push(&mut self, timestamp_ms: u32, direction: Direction, raw: &[u8], result: u8) {
+        let head = unsafe { core::ptr::read_volatile(&self.head) };
+        let mut entry = TraceEntry {
+            seq: head.wrapping_add(1),
+            timestamp_ms,
+            direction: direction as u8,
+            len: raw.len().min(u8::MAX as usize) as u8,
+            result,
+            ..Default::default()
+        };
+        let stored = raw.len().min(TRACE_FRAME_BYTES);
+        entry.bytes[..stored].copy_from_slice(&raw[..stored]);
+
+        self.entries[head as usize % TRACE_LEN].write(&entry);
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.head, head.wrapping_add(1)) };
+    }
+
+    /// For a restarted driver: completes any slot the previous run left half-written.
+    pub fn recover(&mut self) {
+        for slot in self.entries.iter_mut() {
+            if slot.generation & 1 != 0 {
+                slot.recover();
+                slot.write(&TraceEntry::default());
+            }
+        }
+    }
+
+    /// Copies up to `out.len()` of the latest entries, oldest first. Returns how many were copied.
+    pub fn snapshot(&self, out: &mut [TraceEntry]) -> usize {
+        let head = unsafe { core::ptr::read_volatile(&self.head) };
+        fence(Ordering::Acquire);
+        let wanted = (head as usize).min(TRACE_LEN).min(out.len());
+
+        let mut count = 0;
+        for k in 0..wanted {
+            let seq = head.wrapping_sub((wanted - k) as u32).wrapping_add(1);
+            let slot = &self.entries[seq.wrapping_sub(1) as usize % TRACE_LEN];
+            // A slot rewritten since we read `head` belongs to a newer frame; skip it
+            if let Some(entry) = slot.read().filter(|e| e.seq == seq) {
+                out[count] = entry;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+impl TraceSink for TraceRing {
+    fn on_frame(&mut self, now_ms: u32, direction: Direction, raw: &[u8], result: Result<&BusFrame, DispatchError>) {
+        let result = match result {
+            Ok(_) => TRACE_RESULT_OK,
+            Err(e) => e as u8,
+        };
+        self.push(now_ms, direction, raw, result);
+    }
+
+    fn on_framer_error(&mut self, now_ms: u32, raw: &[u8], error: FramerError) {
+        let result = match error {
+            FramerError::Garbage => TRACE_RESULT_GARBAGE,
+            FramerError::CrcMismatch => TRACE_RESULT_CRC_MISMATCH,
+            FramerError::Truncated => TRACE_RESULT_TRUNCATED,
+            FramerError::Overflow => TRACE_RESULT_OVERFLOW,
+        };
+        self.push(now_ms, Direction::Rx, raw, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_oldest_first_after_wrap() {
+        let mut ring = TraceRing::default();
+        let mut out = [TraceEntry::default(); TRACE_LEN];
+        assert_eq!(ring.snapshot(&mut out), 0);
+
+        for i in 0..(TRACE_LEN as u32 + 3) {
+            ring.push(i * 10, Direction::Rx, &[i as u8, 0x17], TRACE_RESULT_OK);
+        }
+        assert_eq!(ring.snapshot(&mut out), TRACE_LEN);
+        assert_eq!(out[0].seq, 4);
+        assert_eq!(out[0].frame(), &[3, 0x17]);
+        assert_eq!(out[TRACE_LEN - 1].timestamp_ms, (TRACE_LEN as u32 + 2) * 10);
+
+        // Asking for fewer gives the most recent ones
+        let mut last_two = [TraceEntry::default(); 2];
+        assert_eq!(ring.snapshot(&mut last_two), 2);
+        assert_eq!(last_two[1].seq, TRACE_LEN as u32 + 3);
+    }
+
+    #[test]
+    fn test_long_frame_truncated_and_result_recorded() {
+        let mut ring = TraceRing::default();
+        let long = [0xAAu8; TRACE_FRAME_BYTES + 8];
+        ring.on_frame(5, Direction::Tx, &long, Err(DispatchError::CrcMismatch));
+
+        let mut out = [TraceEntry::default(); 1];
+        assert_eq!(ring.snapshot(&mut out), 1);
+        assert_eq!(out[0].len as usize, TRACE_FRAME_BYTES + 8);
+        assert_eq!(out[0].frame().len(), TRACE_FRAME_BYTES);
+        assert_eq!(out[0].direction, Direction::Tx as u8);
+        assert_eq!(out[0].result, DispatchError::CrcMismatch as u8);
+    }
+
+    #[test]
+    fn test_framer_error_recorded() {
+        let mut ring = TraceRing::default();
+        ring.on_framer_error(7, &[0x02, 0x10, 0xFF], FramerError::CrcMismatch);
+        ring.on_framer_error(8, &[], FramerError::Overflow);
+
+        let mut out = [TraceEntry::default(); 2];
+        assert_eq!(ring.snapshot(&mut out), 2);
+        assert_eq!((out[0].direction, out[0].result), (Direction::Rx as u8, TRACE_RESULT_CRC_MISMATCH));
+        assert_eq!(out[0].frame(), &[0x02, 0x10, 0xFF]);
+        assert_eq!((out[1].len, out[1].result), (0, TRACE_RESULT_OVERFLOW));
+    }
+
+    #[test]
+    fn test_slot_being_written_is_skipped() {
+        let mut ring = TraceRing::default();
+        ring.push(0, Direction::Rx, &[1], TRACE_RESULT_OK);
+        ring.push(1, Direction::Rx, &[2], TRACE_RESULT_OK);
+        // Writer stopped half-way through the newest slot
+        ring.entries[1].generation += 1;
+
+        let mut out = [TraceEntry::default(); TRACE_LEN];
+        assert_eq!(ring.snapshot(&mut out), 1);
+        assert_eq!(out[0].frame(), &[1]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Feeds malformed and random frames through `dispatch_frame`.
+//! Every input must be answered with `Ok` or `Err`, never a panic.
+
+use hcp2_common::codec::crc16;
+use hcp2_common::protocol::Hcp2Protocol;
+use hcp2_common::shared::{HpToLp, LpToHp};
+
+/// Small xorshift generator so the test is deterministic without extra dependencies.
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+fn append_crc(frame: &mut Vec<u8>) {
+    let crc = crc16(frame);
+    frame.push((crc & 0xFF) as u8);
+    frame.push((crc >> 8) as u8);
+}
+
+fn check(proto: &mut Hcp2Protocol, frame: &[u8], out_len: usize) {
+    let mut out = vec![0u8; out_len];
+    let request = HpToLp::default();
+    let mut status = LpToHp::default();
+    if let Ok(len) = proto.dispatch_frame(frame, &mut out, &request, &mut status, 0) {
+        assert!(len <= out_len, "response of {} bytes for a {} byte buffer", len, out_len);
+    }
+}
+
+const BUS_SCAN: [u8; 17] = [
+    0x02, 0x17, 0x9C, 0xB9, 0x00, 0x05, 0x9C, 0x41, 0x00, 0x03, 0x06, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02,
+];
+
+const STATUS_BROADCAST: [u8; 25] = [
+    0x00, 0x10, 0x9D, 0x31, 0x00, 0x09, 0x12, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn random_frames_with_valid_crc() {
+    let mut rng = XorShift(0x1234_5678);
+    let mut proto = Hcp2Protocol::default();
+    for _ in 0..20_000 {
+        let len = (rng.next() % 40) as usize;
+        let mut frame: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+        // Steer most frames past the address and function checks
+        if len >= 2 && !rng.next().is_multiple_of(4) {
+            frame[0] = if rng.next().is_multiple_of(2) { 0x00 } else { 0x02 };
+            frame[1] = if rng.next().is_multiple_of(2) { 0x10 } else { 0x17 };
+        }
+        append_crc(&mut frame);
+        check(&mut proto, &frame, 64);
+    }
+}
+
+#[test]
+fn every_byte_of_valid_frames_mutated() {
+    let mut proto = Hcp2Protocol::default();
+    for base in [&BUS_SCAN[..], &STATUS_BROADCAST[..]] {
+        for pos in 0..base.len() {
+            for val in 0..=255u8 {
+                let mut frame = base.to_vec();
+                frame[pos] = val;
+                append_crc(&mut frame);
+                check(&mut proto, &frame, 64);
+            }
+        }
+    }
+}
+
+#[test]
+fn header_quantity_sweep() {
+    let mut proto = Hcp2Protocol::default();
+    for read_qty in [0u16, 1, 2, 5, 8, 9, 0x7F, 0xFFFF] {
+        for write_qty in [0u16, 1, 3, 16, 17, 0x80, 0xFFFF] {
+            for byte_count in [0u8, 1, 5, 6, 32, 0xFF] {
+                for data_len in [0usize, 6, 32] {
+                    let mut frame = vec![0x02, 0x17, 0x9C, 0xB9];
+                    frame.extend_from_slice(&read_qty.to_be_bytes());
+                    frame.extend_from_slice(&[0x9C, 0x41]);
+                    frame.extend_from_slice(&write_qty.to_be_bytes());
+                    frame.push(byte_count);
+                    frame.extend(std::iter::repeat_n(0x01, data_len));
+                    append_crc(&mut frame);
+                    for out_len in [0, 4, 7, 21, 64] {
+                        check(&mut proto, &frame, out_len);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn truncated_frames() {
+    let mut proto = Hcp2Protocol::default();
+    for base in [&BUS_SCAN[..], &STATUS_BROADCAST[..]] {
+        for len in 0..=base.len() {
+            let mut frame = base[..len].to_vec();
+            append_crc(&mut frame);
+            check(&mut proto, &frame, 64);
+            // And without any CRC at all
+            check(&mut proto, &base[..len], 64);
+        }
+    }
+}
@@ -7,6 +7,21 @@ from .build_hooks import is_lp_mode, build_lp_firmware, build_hp_firmware
 from esphome.core import CORE
 
 CONF_CORE = "core"
+CONF_BUS_ADDRESS = "bus_address"
+CONF_INTER_FRAME_TIMEOUT = "inter_frame_timeout"
+CONF_TX_TURNAROUND = "tx_turnaround"
+CONF_PRESS_DURATION = "press_duration"
+CONF_SILENCE_TIMEOUT = "silence_timeout"
+CONF_SCAN_IDENTITY = "scan_identity"
+CONF_LISTEN_ONLY = "listen_only"
+CONF_EXCEPTION_RESPONSES = "exception_responses"
+
+# Modbus exception code sent for each case
+EXCEPTION_RESPONSES = {
+    "illegal_function": 0x01,
+    "illegal_data_address": 0x02,
+    "illegal_data_value": 0x03,
+}
 
 hcp_bridge_ns = cg.esphome_ns.namespace("hcp_bridge")
 HCPBridge = hcp_bridge_ns.class_("HCPBridge", cg.Component, uart.UARTDevice)
@@ -14,6 +29,30 @@ HCPBridge = hcp_bridge_ns.class_("HCPBridge", cg.Component, uart.UARTDevice)
 # Base fields common to both
 BASE_SCHEMA = cv.Schema({
     cv.GenerateID(): cv.declare_id(HCPBridge),
+    # Driver tuning; omitted values keep the driver defaults
+    # One address or a list of up to 4; broadcasts are always processed but never answered
+    cv.Optional(CONF_BUS_ADDRESS): cv.All(
+        cv.ensure_list(cv.int_range(min=1, max=247)), cv.Length(min=1, max=4)
+    ),
+    cv.Optional(CONF_INTER_FRAME_TIMEOUT): cv.All(
+        cv.positive_time_period_milliseconds, cv.Range(min=cv.TimePeriod(milliseconds=1))
+    ),
+    cv.Optional(CONF_TX_TURNAROUND): cv.All(
+        cv.positive_time_period_milliseconds, cv.Range(min=cv.TimePeriod(milliseconds=1))
+    ),
+    cv.Optional(CONF_PRESS_DURATION): cv.All(
+        cv.positive_time_period_milliseconds, cv.Range(min=cv.TimePeriod(milliseconds=1))
+    ),
+    cv.Optional(CONF_SILENCE_TIMEOUT): cv.All(
+        cv.positive_time_period_milliseconds, cv.Range(min=cv.TimePeriod(milliseconds=1))
+    ),
+    cv.Optional(CONF_SCAN_IDENTITY): cv.All(
+        [cv.hex_uint16_t], cv.Length(min=3, max=3)
+    ),
+    cv.Optional(CONF_LISTEN_ONLY, default=False): cv.boolean,
+    cv.Optional(CONF_EXCEPTION_RESPONSES, default=[]): cv.ensure_list(
+        cv.enum(EXCEPTION_RESPONSES, lower=True)
+    ),
 }).extend(cv.COMPONENT_SCHEMA)
 
 # LP Mode Schema: Allows pins, no UART component required
@@ -35,6 +74,23 @@ CONFIG_SCHEMA = cv.Any(LP_SCHEMA, HP_SCHEMA)
 async def to_code(config):
     var = cg.new_Pvariable(config[CONF_ID])
     await cg.register_component(var, config)
+
+    if CONF_BUS_ADDRESS in config:
+        for address in config[CONF_BUS_ADDRESS]:
+            cg.add(var.add_bus_address(address))
+    if CONF_INTER_FRAME_TIMEOUT in config:
+        cg.add(var.set_inter_frame_timeout(config[CONF_INTER_FRAME_TIMEOUT]))
+    if CONF_TX_TURNAROUND in config:
+        cg.add(var.set_tx_turnaround(config[CONF_TX_TURNAROUND]))
+    if CONF_PRESS_DURATION in config:
+        cg.add(var.set_press_duration(config[CONF_PRESS_DURATION]))
+    if CONF_SILENCE_TIMEOUT in config:
+        cg.add(var.set_silence_timeout(config[CONF_SILENCE_TIMEOUT]))
+    if CONF_SCAN_IDENTITY in config:
+        cg.add(var.set_scan_identity(*config[CONF_SCAN_IDENTITY]))
+    cg.add(var.set_listen_only(config[CONF_LISTEN_ONLY]))
+    for case in config[CONF_EXCEPTION_RESPONSES]:
+        cg.add(var.add_exception_response(EXCEPTION_RESPONSES[case]))
     
     # Trigger the appropriate Rust build
     if is_lp_mode(config):
@@ -43,11 +99,14 @@ async def to_code(config):
         ))
         build_lp_firmware(config)
         cg.add_build_flag("-DUSE_HCP_LP_MODE")
+        # The HP side of LP mode uses the library's watchdog helper
+        build_hp_firmware(config)
     else:
         pin = await cg.gpio_pin_expression(config[CONF_FLOW_CONTROL_PIN])
         cg.add(var.set_flow_control_pin(pin))
 
         build_hp_firmware(config)
         await uart.register_uart_device(var, config)
-        cg.add_build_flag("-L" + str(CORE.relative_build_path("hp-firmware")))
-        cg.add_build_flag("-lhcp2_hp_lib")
\ No newline at end of file
+
+    cg.add_build_flag("-L" + str(CORE.relative_build_path("hp-firmware")))
+    cg.add_build_flag("-lhcp2_hp_lib")
\ No newline at end of file
@@ -20,29 +20,43 @@ class HCPCover : public cover::Cover, public Component {
   void setup() override {}
   
   void loop() override {
+    // The status of an offline drive is stale: keep the last position, but never report a
+    // movement we cannot follow, and republish everything once it is back
+    if (!bridge_->is_online()) {
+      if (online_ && this->current_operation != cover::COVER_OPERATION_IDLE) {
+        this->current_operation = cover::COVER_OPERATION_IDLE;
+        this->publish_state();
+      }
+      online_ = false;
+      return;
+    }
     const auto *data = bridge_->get_data();
-    if (data == nullptr) return;
+    bool force = !online_;
+    online_ = true;
 
     float pos = static_cast<float>(data->current_position) / 200.0f;
-    if (this->position != pos) {
+    if (this->position != pos || force) {
       this->position = pos;
       this->publish_state();
     }
 
-    // Map drive state to cover operation
+    // Map drive state to cover operation. A code we do not know says nothing about
+    // movement, so the last operation is kept.
+    if (!hcp2::hcp2_state_is_known(data->current_state) && !force)
+      return;
     cover::CoverOperation op = cover::COVER_OPERATION_IDLE;
     switch (data->current_state) {
-      case 0x01: // Opening
-      case 0x05: // MoveHalf
-      case 0x09: // MoveVenting
+      case hcp2::STATE_OPENING:
+      case hcp2::STATE_MOVE_HALF:
+      case hcp2::STATE_MOVE_VENTING:
         op = cover::COVER_OPERATION_OPENING;
         break;
-      case 0x02: // Closing
+      case hcp2::STATE_CLOSING:
         op = cover::COVER_OPERATION_CLOSING;
         break;
     }
     
-    if (this->current_operation != op) {
+    if (this->current_operation != op || force) {
       this->current_operation = op;
       this->publish_state();
     }
@@ -50,24 +64,23 @@ class HCPCover : public cover::Cover, public Component {
 
   void control(const cover::CoverCall &call) override {
     if (call.get_stop()) {
-      bridge_->set_command(hcp2::CMD_STOP);
+      bridge_->set_command(hcp2::DriveAction::Stop);
     } else if (call.get_position()) {
       float pos = *call.get_position();
       if (pos == 0.0f) {
-        bridge_->set_command(hcp2::CMD_CLOSE);
+        bridge_->set_command(hcp2::DriveAction::Close);
       } else if (pos == 1.0f) {
-        bridge_->set_command(hcp2::CMD_OPEN);
+        bridge_->set_command(hcp2::DriveAction::Open);
       } else {
-        // HCP2 usually handles discrete buttons, but we can set target position if supported
-        // For now, we'll just open/close based on direction
-        if (pos > this->position) bridge_->set_command(hcp2::CMD_OPEN);
-        else bridge_->set_command(hcp2::CMD_CLOSE);
+        // The driver opens or closes and stops at the set-point
+        bridge_->set_target_position(static_cast<uint8_t>(pos * 200.0f + 0.5f));
       }
     }
   }
 
  protected:
   HCPBridge *bridge_;
+  bool online_{false};
 };
 
 }  // namespace hcp_bridge
@@ -7,14 +7,27 @@ extern uint8_t lp_firmware_bin[];
 extern size_t lp_firmware_bin_size;
 #endif
 
+#ifdef USE_HCP_LP_MODE
+#include <esp_clk_tree.h>
+#endif
 #include <driver/gpio.h>
 #include <soc/soc_caps.h>
 #include <algorithm>
+#include <cstddef>
+#include <cstdio>
+#include <cstring>
 
 namespace esphome {
 namespace hcp_bridge {
 
 static const char *const TAG = "hcp_bridge";
+// How long the driver may take to answer the layout handshake after being started
+static const uint32_t LAYOUT_HANDSHAKE_TIMEOUT_MS = 1000;
+
+// Both headers keep these offsets in every layout version
+static_assert(offsetof(hcp2::SharedData, layout) == 0, "layout header must start the shared block");
+static_assert(offsetof(hcp2::SharedData, driver_layout) == sizeof(hcp2::LayoutHeader),
+              "driver layout header must follow the HP one");
 
 // Rust FFI definitions
 extern "C" {
@@ -26,14 +39,49 @@ extern "C" {
         uint32_t (*now_ms)();
         void (*sleep_ms)(uint32_t ms);
         void (*log)(void *ctx, const uint8_t *msg, size_t len);
+        // Optional, may be null
+        uint32_t (*now_us)();
+        bool (*take_rx_idle)(void *ctx);
+        bool (*wait_tx_complete)(void *ctx);
+        uint8_t (*take_line_errors)(void *ctx);
     };
 
 #ifndef USE_HCP_LP_MODE
-    void hcp_hp_init();
-    void hcp_hp_poll(const HcpHalC *hal, hcp2::SharedData *shared);
+    hcp2::FfiResult hcp_hp_create(void *storage, size_t len, const hcp2::Hcp2Config *config);
+    hcp2::FfiResult hcp_hp_poll(void *handle, const HcpHalC *hal, hcp2::SharedData *shared);
+    int32_t hcp_hp_read_trace(const void *handle, hcp2::TraceEntry *out, size_t max);
+    hcp2::FfiResult hcp_hp_destroy(void *handle);
+    void hcp_hp_set_panic_callback(void (*callback)(const hcp2::PanicRecord *record));
+    bool hcp_hp_last_panic(hcp2::PanicRecord *out);
+    void hcp_hp_clear_panic();
+#else
+    hcp2::Liveness hcp_check_liveness(hcp2::HeartbeatMonitor *monitor, const hcp2::SharedData *shared, uint32_t now_ms);
 #endif
 }
 
+// Seqlock helpers mirroring hcp2_common::shared::SeqLock. Each region has exactly one
+// writer, so neither core ever waits for the other.
+template<typename T> static bool seqlock_read(const hcp2::SeqLock<T> &lock, T *out) {
+    for (uintptr_t i = 0; i < hcp2::SEQLOCK_READ_RETRIES; i++) {
+        uint32_t before = __atomic_load_n(&lock.generation, __ATOMIC_ACQUIRE);
+        if (before & 1)
+            continue;
+        memcpy(out, &lock.data, sizeof(T));
+        __atomic_thread_fence(__ATOMIC_ACQUIRE);
+        if (__atomic_load_n(&lock.generation, __ATOMIC_RELAXED) == before)
+            return true;
+    }
+    return false;
+}
+
+template<typename T> static void seqlock_write(hcp2::SeqLock<T> &lock, const T &val) {
+    uint32_t gen = lock.generation;
+    __atomic_store_n(&lock.generation, gen + 1, __ATOMIC_RELAXED);
+    __atomic_thread_fence(__ATOMIC_RELEASE);
+    memcpy(&lock.data, &val, sizeof(T));
+    __atomic_store_n(&lock.generation, gen + 2, __ATOMIC_RELEASE);
+}
+
 // Proxy implementations
 #ifndef USE_HCP_LP_MODE
 static void log_hex(const char *label, const uint8_t *buf, size_t len) {
@@ -93,9 +141,31 @@ static void proxy_sleep_ms(uint32_t ms) {
     delay(ms);
 }
 
+static uint32_t proxy_now_us() {
+    return micros();
+}
+
+// Blocks until the UART has shifted out the last stop bit, so DE can drop right away
+static bool proxy_wait_tx_complete(void *ctx) {
+    HCPBridge *bridge = static_cast<HCPBridge *>(ctx);
+    bridge->flush();
+    return true;
+}
+
 static void proxy_log(void *ctx, const uint8_t *msg, size_t len) {
     ESP_LOGD(TAG, "Rust: %.*s", len, (const char *)msg);
 }
+
+static void log_panic(const char *prefix, const hcp2::PanicRecord &record) {
+    // `file` is only NUL-terminated when shorter than the buffer, `message` always is
+    ESP_LOGE(TAG, "%s at %.*s:%u: %s", prefix, (int) strnlen((const char *) record.file, hcp2::PANIC_FILE_LEN),
+             (const char *) record.file, (unsigned) record.line, (const char *) record.message);
+}
+
+// Runs in the panicking driver task right before the chip resets
+static void on_driver_panic(const hcp2::PanicRecord *record) {
+    log_panic("Driver panicked", *record);
+}
 #endif
 
 void HCPBridge::setup() {
@@ -103,21 +173,64 @@ void HCPBridge::setup() {
 
 #ifdef USE_HCP_LP_MODE
   // Shared memory is at fixed address 0x50003000 in LP RAM
-  shared_data_ = reinterpret_cast<hcp2::SharedData *>(0x50003000);
+  lp_memory_ = reinterpret_cast<hcp2::LpSharedMemory *>(0x50003000);
+  memset(lp_memory_, 0, sizeof(hcp2::LpSharedMemory));
+  shared_data_ = &lp_memory_->data;
 #else
   shared_data_ = new hcp2::SharedData();
 #endif
 
-  // Initialize shared memory
-  if (try_lock()) {
-    shared_data_->owner_flag = hcp2::OWNER_FREE;
-    shared_data_->command_request = hcp2::CMD_NONE;
-    shared_data_->last_update_ts = 0;
-    unlock();
-  }
-  
+  // Initialize shared memory before the driver starts, so no seqlock is needed yet
+  memset(shared_data_, 0, sizeof(hcp2::SharedData));
+#ifdef USE_HCP_LP_MODE
+  // The LP core times the bus with its cycle counter; RC_FAST is only nominally 17.5 MHz
+  uint32_t rc_fast_hz = 0;
+  if (esp_clk_tree_src_get_freq_hz(SOC_MOD_CLK_RC_FAST, ESP_CLK_TREE_SRC_FREQ_PRECISION_EXACT, &rc_fast_hz) == ESP_OK)
+    config_.lp_clock_hz = rc_fast_hz;
+  else
+    ESP_LOGW(TAG, "Could not calibrate the LP core clock, assuming %u Hz", (unsigned) hcp2::DEFAULT_LP_CLOCK_HZ);
+#endif
+  // The LP driver reads its config once at startup; zero fields mean "use the default"
+  shared_data_->config = config_;
+  // The driver compares this with its own layout and only runs if they match
+  shared_data_->layout = hcp2::LayoutHeader{hcp2::SHARED_MAGIC, hcp2::SHARED_LAYOUT_VERSION,
+                                            static_cast<uint32_t>(sizeof(hcp2::SharedData))};
+  driver_started_ms_ = millis();
+
 #if defined(USE_HCP_LP_MODE)
+  start_lp_core();
+#else
+  // The record survives the software reset that follows a driver panic
+  if (hcp_hp_last_panic(&last_panic_)) {
+    has_last_panic_ = true;
+    log_panic("Driver panicked before the last reset", last_panic_);
+    hcp_hp_clear_panic();
+  }
+  hcp_hp_set_panic_callback(on_driver_panic);
+
+  driver_lock_ = xSemaphoreCreateMutexStatic(&driver_lock_buffer_);
+  // The driver lives in this component, so several bridges can run side by side
+  hcp2::FfiResult res = hcp_hp_create(driver_storage_, sizeof(driver_storage_), &config_);
+  if (res != hcp2::FfiResult::Ok) {
+    ESP_LOGE(TAG, "Failed to create driver: %d", static_cast<int>(res));
+    this->mark_failed();
+    return;
+  }
+
+  // Initialize DE pin
+  if (de_pin_) {
+      de_pin_->setup();
+      de_pin_->digital_write(false);
+  }
+
+  start_hp_task();
+#endif
+}
+
+#ifdef USE_HCP_LP_MODE
+void HCPBridge::start_lp_core() {
   ESP_LOGI(TAG, "Starting LP Core...");
+  // Reloading also resets the driver's own RAM; the shared block behind it is kept
   esp_err_t err = ulp_lp_core_load_binary(lp_firmware_bin, lp_firmware_bin_size);
   if (err != ESP_OK) {
     ESP_LOGE(TAG, "Failed to load LP firmware: %d", err);
@@ -131,16 +244,28 @@ void HCPBridge::setup() {
   if (err != ESP_OK) {
     ESP_LOGE(TAG, "Failed to run LP core: %d", err);
   }
-#else
-  // Initialize DE pin
-  if (de_pin_) {
-      de_pin_->setup();
-      de_pin_->digital_write(false);
+}
+
+void HCPBridge::check_lp_core() {
+  if (hcp_check_liveness(&heartbeat_monitor_, shared_data_, millis()) != hcp2::Liveness::Stalled)
+    return;
+
+  // The panic fields are complete once `panicked` is set, and the driver no longer writes them
+  const hcp2::DriverHealth &health = shared_data_->health;
+  if (__atomic_load_n(&health.panicked, __ATOMIC_ACQUIRE)) {
+    ESP_LOGE(TAG, "LP core panicked at %.*s:%u, restarting it", (int) strnlen((const char *) health.panic_file,
+             hcp2::PANIC_FILE_LEN), (const char *) health.panic_file, (unsigned) health.panic_line);
+  } else {
+    ESP_LOGE(TAG, "LP core stopped responding (heartbeat %u), restarting it", (unsigned) health.heartbeat);
   }
+  dump_trace();
 
-  start_hp_task();
-#endif
+  ulp_lp_core_stop();
+  start_lp_core();
+  heartbeat_monitor_ = hcp2::HeartbeatMonitor{};
+  lp_restarts_++;
 }
+#endif
 
 #ifndef USE_HCP_LP_MODE
 void HCPBridge::start_hp_task() {
@@ -164,10 +289,7 @@ void HCPBridge::start_hp_task() {
 
 void HCPBridge::hp_core_task(void *arg) {
   HCPBridge *self = static_cast<HCPBridge *>(arg);
-  
-  // Initialize Rust driver
-  hcp_hp_init();
-  
+
   // Prepare HAL struct
   HcpHalC hal_c = {
       .ctx = self,
@@ -177,63 +299,214 @@ void HCPBridge::hp_core_task(void *arg) {
       .now_ms = proxy_now_ms,
       .sleep_ms = proxy_sleep_ms,
       .log = proxy_log,
+      .now_us = proxy_now_us,
+      // The UART component does not report idle-line events; the driver times the gap itself
+      .take_rx_idle = nullptr,
+      .wait_tx_complete = proxy_wait_tx_complete,
+      // The UART component does not report line errors; corrupt frames fail the CRC instead
+      .take_line_errors = nullptr,
   };
 
   ESP_LOGI(TAG, "Entering HP Core Loop...");
   while (true) {
-      hcp_hp_poll(&hal_c, self->shared_data_);
+      xSemaphoreTake(self->driver_lock_, portMAX_DELAY);
+      hcp2::FfiResult res = hcp_hp_poll(self->driver_storage_, &hal_c, self->shared_data_);
+      xSemaphoreGive(self->driver_lock_);
+      if (res != hcp2::FfiResult::Ok) {
+        ESP_LOGE(TAG, "Driver poll failed: %d", static_cast<int>(res));
+        break;
+      }
       delay(1); // Yield/Sleep to prevent WDT
   }
   vTaskDelete(NULL);
 }
 #endif
 
+bool HCPBridge::check_layout() {
+  if (layout_ok_)
+    return true;
+
+  // The driver writes the magic last, so the rest of its header is complete once it is set
+  uint32_t magic = __atomic_load_n(&shared_data_->driver_layout.magic, __ATOMIC_ACQUIRE);
+  if (magic == 0) {
+    if (millis() - driver_started_ms_ < LAYOUT_HANDSHAKE_TIMEOUT_MS)
+      return false;
+    ESP_LOGE(TAG, "Driver did not announce its shared memory layout; is the driver image outdated?");
+  } else {
+    const hcp2::LayoutHeader &driver = shared_data_->driver_layout;
+    if (magic == hcp2::SHARED_MAGIC && driver.version == hcp2::SHARED_LAYOUT_VERSION &&
+        driver.size == sizeof(hcp2::SharedData)) {
+      ESP_LOGI(TAG, "Driver uses shared memory layout v%u", (unsigned) driver.version);
+      layout_ok_ = true;
+      return true;
+    }
+    ESP_LOGE(TAG, "Driver shared memory layout mismatch: driver has magic 0x%08X v%u (%u bytes), expected v%u (%u bytes)",
+             (unsigned) magic, (unsigned) driver.version, (unsigned) driver.size, (unsigned) hcp2::SHARED_LAYOUT_VERSION,
+             (unsigned) sizeof(hcp2::SharedData));
+  }
+
+#ifdef USE_HCP_LP_MODE
+  ulp_lp_core_stop();
+#endif
+  this->mark_failed();
+  return false;
+}
+
 void HCPBridge::loop() {
+  // Nothing in the block can be trusted before the driver confirmed the layout
+  if (!check_layout())
+    return;
+#ifdef USE_HCP_LP_MODE
+  check_lp_core();
+#endif
+
+  if (seqlock_read(shared_data_->stats, &stats_))
+    has_stats_ = true;
+
+  hcp2::LpToHp status;
+  if (!seqlock_read(shared_data_->lp_to_hp, &status))
+    return;
+
+  if (status.error_code != status_.error_code || !has_status_) {
+    if (status.error_code != static_cast<uint8_t>(hcp2::LinkError::None)) {
+      ESP_LOGW(TAG, "Drive link problem: error code %d", status.error_code);
+      dump_trace();
+    } else
+      ESP_LOGI(TAG, "Drive link OK");
+  }
+  if (status.unknown_states != status_.unknown_states) {
+    ESP_LOGW(TAG, "Drive reported unknown state 0x%02X (flags 0x%02X), seen %u times", status.last_unknown_state,
+             status.state_flags, (unsigned) status.unknown_states);
+  }
+  if (has_status_ && status.raw.changes != status_.raw.changes) {
+    // Undecoded registers, for correlating unknown bits with drive behaviour
+    const uint16_t *r = status.raw.status;
+    ESP_LOGD(TAG, "Raw registers: %04X %04X %04X %04X %04X %04X %04X %04X %04X sync %04X", r[0], r[1], r[2],
+             r[3], r[4], r[5], r[6], r[7], r[8], status.raw.sync);
+  }
+  if (status.link_online) {
+    this->status_clear_warning();
+  } else {
+    this->status_set_warning();
+  }
+
+  status_ = status;
+  has_status_ = true;
+}
+
+size_t HCPBridge::read_trace(hcp2::TraceEntry *out, size_t max) {
+#ifdef USE_HCP_LP_MODE
+  // Mirrors hcp2_common::trace::TraceRing::snapshot
+  const hcp2::TraceRing &ring = lp_memory_->trace;
+  uint32_t head = __atomic_load_n(&ring.head, __ATOMIC_ACQUIRE);
+  size_t wanted = std::min<size_t>(std::min<size_t>(head, hcp2::TRACE_LEN), max);
+  size_t count = 0;
+  for (size_t k = 0; k < wanted; k++) {
+    uint32_t seq = head - (wanted - k) + 1;
+    hcp2::TraceEntry entry;
+    if (seqlock_read(ring.entries[(seq - 1) % hcp2::TRACE_LEN], &entry) && entry.seq == seq)
+      out[count++] = entry;
+  }
+  return count;
+#else
+  xSemaphoreTake(driver_lock_, portMAX_DELAY);
+  int32_t count = hcp_hp_read_trace(driver_storage_, out, max);
+  xSemaphoreGive(driver_lock_);
+  return count < 0 ? 0 : static_cast<size_t>(count);
+#endif
+}
+
+void HCPBridge::dump_trace() {
+  hcp2::TraceEntry entries[hcp2::TRACE_LEN];
+  size_t count = read_trace(entries, hcp2::TRACE_LEN);
+  ESP_LOGI(TAG, "Last %u frames:", (unsigned) count);
+  for (size_t i = 0; i < count; i++) {
+    const hcp2::TraceEntry &e = entries[i];
+    size_t stored = std::min<size_t>(e.len, hcp2::TRACE_FRAME_BYTES);
+    char hex[hcp2::TRACE_FRAME_BYTES * 3 + 1] = {0};
+    for (size_t j = 0; j < stored; j++)
+      sprintf(hex + j * 3, "%02X ", e.bytes[j]);
+    ESP_LOGI(TAG, "  %10u %s result=%u len=%u: %s", e.timestamp_ms,
+             e.direction == static_cast<uint8_t>(hcp2::Direction::Tx) ? "TX" : "RX", e.result, e.len, hex);
+  }
 }
 
 void HCPBridge::dump_config() {
   ESP_LOGCONFIG(TAG, "HCP Bridge:");
   ESP_LOGCONFIG(TAG, "  Shared Memory Address: %p", shared_data_);
+  ESP_LOGCONFIG(TAG, "  Shared Memory Layout: v%u (%u bytes)", (unsigned) hcp2::SHARED_LAYOUT_VERSION,
+                (unsigned) sizeof(hcp2::SharedData));
 #ifdef USE_HCP_LP_MODE
   ESP_LOGCONFIG(TAG, "  Flow Control Pin: %d", de_pin_);
 #else
   LOG_PIN("  Flow Control Pin: ", de_pin_);
 #endif
-}
-
-bool HCPBridge::try_lock() {
-  if (shared_data_->owner_flag == hcp2::OWNER_FREE) {
-    shared_data_->owner_flag = hcp2::OWNER_HP;
-    return true;
+  for (uint8_t address : config_.bus_addresses) {
+    if (address != 0)
+      ESP_LOGCONFIG(TAG, "  Bus Address: 0x%02X", address);
   }
-  return false;
+  if (config_.inter_frame_timeout_ms != 0)
+    ESP_LOGCONFIG(TAG, "  Inter-Frame Timeout: %u ms", config_.inter_frame_timeout_ms);
+  if (config_.tx_turnaround_ms != 0)
+    ESP_LOGCONFIG(TAG, "  TX Turnaround: %u ms", config_.tx_turnaround_ms);
+  if (config_.press_duration_ms != 0)
+    ESP_LOGCONFIG(TAG, "  Press Duration: %u ms", config_.press_duration_ms);
+  if (config_.silence_timeout_ms != 0)
+    ESP_LOGCONFIG(TAG, "  Silence Timeout: %u ms", config_.silence_timeout_ms);
+#ifdef USE_HCP_LP_MODE
+  if (config_.lp_clock_hz != 0)
+    ESP_LOGCONFIG(TAG, "  LP Core Clock: %u Hz", (unsigned) config_.lp_clock_hz);
+#endif
+  if (config_.listen_only)
+    ESP_LOGCONFIG(TAG, "  Mode: listen-only (never transmits)");
+  if (config_.exception_responses != 0)
+    ESP_LOGCONFIG(TAG, "  Exception Responses:%s%s%s",
+                  (config_.exception_responses & hcp2::EXCEPTION_ILLEGAL_FUNCTION) ? " illegal-function" : "",
+                  (config_.exception_responses & hcp2::EXCEPTION_ILLEGAL_DATA_ADDRESS) ? " illegal-data-address" : "",
+                  (config_.exception_responses & hcp2::EXCEPTION_ILLEGAL_DATA_VALUE) ? " illegal-data-value" : "");
+#ifdef USE_HCP_LP_MODE
+  const hcp2::DriverHealth &health = shared_data_->health;
+  ESP_LOGCONFIG(TAG, "  LP Core Starts: %u (restarted by watchdog: %u)", (unsigned) health.starts,
+                (unsigned) lp_restarts_);
+  if (health.panic_line != 0)
+    ESP_LOGCONFIG(TAG, "  Last LP Panic: %.*s:%u", (int) strnlen((const char *) health.panic_file, hcp2::PANIC_FILE_LEN),
+                  (const char *) health.panic_file, (unsigned) health.panic_line);
+#else
+  if (has_last_panic_)
+    log_panic("  Driver panicked before the last reset", last_panic_);
+#endif
 }
 
-void HCPBridge::unlock() {
-  shared_data_->owner_flag = hcp2::OWNER_FREE;
+void HCPBridge::refresh_cmd_tail_() {
+  // A stale consumer index only makes the mailbox look fuller; the rest of the status is left to loop()
+  hcp2::LpToHp status;
+  if (seqlock_read(shared_data_->lp_to_hp, &status))
+    status_.cmd_tail = status.cmd_tail;
 }
 
-void HCPBridge::set_command(uint8_t command) {
-  for (int i = 0; i < 100; i++) {
-    if (try_lock()) {
-      shared_data_->command_request = command;
-      unlock();
-      return;
-    }
-    esp_rom_delay_us(10);
+void HCPBridge::set_command(hcp2::DriveAction command) {
+  if (!layout_ok_) {
+    ESP_LOGW(TAG, "Driver not ready, dropping command %d", static_cast<int>(command));
+    return;
+  }
+  refresh_cmd_tail_();
+  uint8_t pending = request_.cmd_head - status_.cmd_tail;
+  if (pending >= hcp2::CMD_QUEUE_LEN) {
+    ESP_LOGW(TAG, "Command mailbox full, dropping command %d", static_cast<int>(command));
+    return;
   }
-  ESP_LOGW(TAG, "Failed to acquire lock for command %d", command);
+  hcp2::CommandEntry &entry = request_.commands[request_.cmd_head % hcp2::CMD_QUEUE_LEN];
+  entry.seq = next_command_seq_++;
+  entry.action = static_cast<uint8_t>(command);
+  request_.cmd_head++;
+  seqlock_write(shared_data_->hp_to_lp, request_);
+  ESP_LOGD(TAG, "Queued command %d (seq %d)", static_cast<int>(command), entry.seq);
 }
 
 void HCPBridge::set_target_position(uint8_t position) {
-  for (int i = 0; i < 100; i++) {
-    if (try_lock()) {
-      shared_data_->target_position = position;
-      unlock();
-      return;
-    }
-    esp_rom_delay_us(10);
-  }
+  // Published together with the command; the driver reads the set-point when it starts the move
+  request_.target_position = std::min<uint8_t>(position, 200);
+  set_command(hcp2::DriveAction::GoToPosition);
 }
 
 }  // namespace hcp_bridge
@@ -6,6 +6,10 @@
 #include "esphome/components/uart/uart.h"
 #endif
 #include "shared_data.h"
+#ifndef USE_HCP_LP_MODE
+#include <freertos/FreeRTOS.h>
+#include <freertos/semphr.h>
+#endif
 
 #if defined(USE_ESP32_VARIANT_ESP32C6) && defined(USE_HCP_LP_MODE)
 #include "ulp_lp_core.h"
@@ -24,8 +28,32 @@ class HCPBridge : public Component
   void loop() override;
   void dump_config() override;
 
-  void set_command(uint8_t command);
+  void set_command(hcp2::DriveAction command);
+  // Moves the door to `position` (0 = closed, 200 = open) and stops it there
   void set_target_position(uint8_t position);
+
+  // Driver tuning, applied when the driver starts. Unset values keep the driver defaults.
+  void add_bus_address(uint8_t address) {
+    for (uint8_t &slot : config_.bus_addresses) {
+      if (slot == 0) {
+        slot = address;
+        return;
+      }
+    }
+  }
+  void set_inter_frame_timeout(uint32_t ms) { config_.inter_frame_timeout_ms = ms; }
+  void set_tx_turnaround(uint32_t ms) { config_.tx_turnaround_ms = ms; }
+  void set_press_duration(uint32_t ms) { config_.press_duration_ms = ms; }
+  void set_silence_timeout(uint32_t ms) { config_.silence_timeout_ms = ms; }
+  // Only watch the bus (e.g. next to another accessory); commands are rejected
+  void set_listen_only(bool listen_only) { config_.listen_only = listen_only; }
+  // Answer unicast requests we cannot serve with Modbus exception `code` (1-3) instead of staying silent
+  void add_exception_response(uint8_t code) { config_.exception_responses |= 1 << (code - 1); }
+  void set_scan_identity(uint16_t id0, uint16_t id1, uint16_t id2) {
+    config_.scan_identity[0] = id0;
+    config_.scan_identity[1] = id1;
+    config_.scan_identity[2] = id2;
+  }
   
 #ifdef USE_HCP_LP_MODE
   void set_flow_control_pin(int de) {
@@ -41,23 +69,69 @@ class HCPBridge : public Component
   GPIOPin *get_de_pin() const { return de_pin_; }
 #endif
 
-  const hcp2::SharedData *get_data() const { return shared_data_; }
+  // Latest consistent snapshot of the driver status, or nullptr before the first one
+  const hcp2::LpToHp *get_data() const { return has_status_ ? &status_ : nullptr; }
+  // False while the drive is silent or the link is broken (see error_code)
+  bool is_online() const { return has_status_ && status_.link_online; }
+
+  // Latest bus counters, or nullptr before the driver published any
+  const hcp2::BusStats *get_stats() const { return has_stats_ ? &stats_ : nullptr; }
+
+#ifndef USE_HCP_LP_MODE
+  // Driver panic from before the last reset, or nullptr if it did not panic
+  const hcp2::PanicRecord *get_last_panic() const { return has_last_panic_ ? &last_panic_ : nullptr; }
+#endif
+
+  // Copies up to `max` recent bus frames into `out`, oldest first. Returns the count.
+  size_t read_trace(hcp2::TraceEntry *out, size_t max);
+  // Logs the recent bus frames; called automatically when the link reports a problem
+  void dump_trace();
 
  protected:
   hcp2::SharedData *shared_data_{nullptr};
+#ifdef USE_HCP_LP_MODE
+  hcp2::LpSharedMemory *lp_memory_{nullptr};
+#endif
+  hcp2::Hcp2Config config_{};
+  // Set once the driver confirmed it was built against the same shared memory layout
+  bool layout_ok_{false};
+  uint32_t driver_started_ms_{0};
   uint32_t last_sync_ms_{0};
+  uint8_t next_command_seq_{0};
+  // Only this side writes hp_to_lp, so the local copy is authoritative
+  hcp2::HpToLp request_{};
+  hcp2::LpToHp status_{};
+  bool has_status_{false};
+  hcp2::BusStats stats_{};
+  bool has_stats_{false};
 #ifdef USE_HCP_LP_MODE
+  // Restarts the LP core when its heartbeat stops
+  hcp2::HeartbeatMonitor heartbeat_monitor_{};
+  uint32_t lp_restarts_{0};
   int de_pin_{2};
 #else
   GPIOPin *de_pin_{nullptr};
+  // Caller-provided storage of the Rust driver instance (see hcp_hp_create)
+  alignas(hcp2::FFI_STORAGE_ALIGN) uint8_t driver_storage_[hcp2::HP_DRIVER_STORAGE_SIZE]{};
+  // hcp_hp_poll and hcp_hp_read_trace must not run at the same time on one handle
+  SemaphoreHandle_t driver_lock_{nullptr};
+  StaticSemaphore_t driver_lock_buffer_{};
+  hcp2::PanicRecord last_panic_{};
+  bool has_last_panic_{false};
 #endif
   
   TaskHandle_t hp_task_handle_{nullptr};
-  
-  bool try_lock();
-  void unlock();
- 
-#ifndef USE_HCP_LP_MODE
+
+  // Layout handshake with the driver. Marks the component failed (and stops the LP core)
+  // on a mismatch or when the driver never answers.
+  bool check_layout();
+  // Re-reads only the driver's consumer index of the command mailbox
+  void refresh_cmd_tail_();
+
+#ifdef USE_HCP_LP_MODE
+  void start_lp_core();
+  void check_lp_core();
+#else
   void start_hp_task();
   static void hp_core_task(void *arg);
 #endif
@@ -0,0 +1,65 @@
+import esphome.codegen as cg
+import esphome.config_validation as cv
+from esphome.components import sensor
+from esphome.const import (
+    CONF_ID,
+    ENTITY_CATEGORY_DIAGNOSTIC,
+    STATE_CLASS_MEASUREMENT,
+    STATE_CLASS_TOTAL_INCREASING,
+    UNIT_MILLISECOND,
+)
+from .. import HCPBridge, hcp_bridge_ns
+
+CONF_HCP_BRIDGE_ID = "hcp_bridge_id"
+
+HCPStatsSensor = hcp_bridge_ns.class_("HCPStatsSensor", cg.PollingComponent)
+
+COUNTERS = [
+    "frames_received",
+    "responses_sent",
+    "broadcasts",
+    "bus_scans",
+    "idle_polls",
+    "action_polls",
+    "sync_jumps",
+    "exceptions_sent",
+    "overflows",
+    "crc_errors",
+    "rx_garbage",
+    "frame_errors",
+    "parity_errors",
+    "framing_errors",
+    "rx_overruns",
+]
+INTERVALS = ["poll_interval_min", "poll_interval_max", "poll_interval_avg"]
+
+COUNTER_SCHEMA = sensor.sensor_schema(
+    accuracy_decimals=0,
+    state_class=STATE_CLASS_TOTAL_INCREASING,
+    entity_category=ENTITY_CATEGORY_DIAGNOSTIC,
+)
+INTERVAL_SCHEMA = sensor.sensor_schema(
+    unit_of_measurement=UNIT_MILLISECOND,
+    accuracy_decimals=0,
+    state_class=STATE_CLASS_MEASUREMENT,
+    entity_category=ENTITY_CATEGORY_DIAGNOSTIC,
+)
+
+CONFIG_SCHEMA = cv.Schema({
+    cv.GenerateID(): cv.declare_id(HCPStatsSensor),
+    cv.GenerateID(CONF_HCP_BRIDGE_ID): cv.use_id(HCPBridge),
+    **{cv.Optional(key): COUNTER_SCHEMA for key in COUNTERS},
+    **{cv.Optional(key): INTERVAL_SCHEMA for key in INTERVALS},
+}).extend(cv.polling_component_schema("60s"))
+
+async def to_code(config):
+    var = cg.new_Pvariable(config[CONF_ID])
+    await cg.register_component(var, config)
+
+    bridge = await cg.get_variable(config[CONF_HCP_BRIDGE_ID])
+    cg.add(var.set_bridge(bridge))
+
+    for key in COUNTERS + INTERVALS:
+        if key in config:
+            sens = await sensor.new_sensor(config[key])
+            cg.add(getattr(var, f"set_{key}_sensor")(sens))
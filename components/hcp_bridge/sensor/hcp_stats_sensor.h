@@ -0,0 +1,87 @@
+#pragma once
+
+#include "esphome/core/component.h"
+#include "esphome/components/sensor/sensor.h"
+#include "../hcp_bridge.h"
+
+namespace esphome {
+namespace hcp_bridge {
+
+// Publishes the driver's bus counters as diagnostic sensors
+class HCPStatsSensor : public PollingComponent {
+ public:
+  void set_bridge(HCPBridge *bridge) { bridge_ = bridge; }
+
+  void set_frames_received_sensor(sensor::Sensor *s) { frames_received_ = s; }
+  void set_responses_sent_sensor(sensor::Sensor *s) { responses_sent_ = s; }
+  void set_broadcasts_sensor(sensor::Sensor *s) { broadcasts_ = s; }
+  void set_bus_scans_sensor(sensor::Sensor *s) { bus_scans_ = s; }
+  void set_idle_polls_sensor(sensor::Sensor *s) { idle_polls_ = s; }
+  void set_action_polls_sensor(sensor::Sensor *s) { action_polls_ = s; }
+  void set_sync_jumps_sensor(sensor::Sensor *s) { sync_jumps_ = s; }
+  void set_exceptions_sent_sensor(sensor::Sensor *s) { exceptions_sent_ = s; }
+  void set_overflows_sensor(sensor::Sensor *s) { overflows_ = s; }
+  void set_crc_errors_sensor(sensor::Sensor *s) { crc_errors_ = s; }
+  void set_rx_garbage_sensor(sensor::Sensor *s) { rx_garbage_ = s; }
+  void set_frame_errors_sensor(sensor::Sensor *s) { frame_errors_ = s; }
+  void set_parity_errors_sensor(sensor::Sensor *s) { parity_errors_ = s; }
+  void set_framing_errors_sensor(sensor::Sensor *s) { framing_errors_ = s; }
+  void set_rx_overruns_sensor(sensor::Sensor *s) { rx_overruns_ = s; }
+  void set_poll_interval_min_sensor(sensor::Sensor *s) { poll_interval_min_ = s; }
+  void set_poll_interval_max_sensor(sensor::Sensor *s) { poll_interval_max_ = s; }
+  void set_poll_interval_avg_sensor(sensor::Sensor *s) { poll_interval_avg_ = s; }
+
+  void update() override {
+    const hcp2::BusStats *stats = bridge_->get_stats();
+    if (stats == nullptr) return;
+
+    publish(frames_received_, stats->frames_received);
+    publish(responses_sent_, stats->responses_sent);
+    publish(broadcasts_, stats->broadcasts);
+    publish(bus_scans_, stats->bus_scans);
+    publish(idle_polls_, stats->idle_polls);
+    publish(action_polls_, stats->action_polls);
+    publish(sync_jumps_, stats->sync_jumps);
+    publish(exceptions_sent_, stats->exceptions_sent);
+    publish(overflows_, stats->framer_overflows);
+    publish(crc_errors_, stats->crc_mismatch);
+    publish(rx_garbage_, stats->framer_garbage);
+    publish(frame_errors_, stats->frame_too_short + stats->invalid_function + stats->quantity_mismatch +
+                               stats->odd_byte_count + stats->too_many_registers + stats->length_mismatch +
+                               stats->buffer_too_small + stats->framer_truncated);
+    publish(parity_errors_, stats->line_parity_errors);
+    publish(framing_errors_, stats->line_framing_errors);
+    publish(rx_overruns_, stats->line_overruns);
+    publish(poll_interval_min_, stats->poll_interval_min_ms);
+    publish(poll_interval_max_, stats->poll_interval_max_ms);
+    publish(poll_interval_avg_, stats->poll_interval_avg_ms);
+  }
+
+ protected:
+  static void publish(sensor::Sensor *s, uint32_t value) {
+    if (s != nullptr) s->publish_state(value);
+  }
+
+  HCPBridge *bridge_;
+  sensor::Sensor *frames_received_{nullptr};
+  sensor::Sensor *responses_sent_{nullptr};
+  sensor::Sensor *broadcasts_{nullptr};
+  sensor::Sensor *bus_scans_{nullptr};
+  sensor::Sensor *idle_polls_{nullptr};
+  sensor::Sensor *action_polls_{nullptr};
+  sensor::Sensor *sync_jumps_{nullptr};
+  sensor::Sensor *exceptions_sent_{nullptr};
+  sensor::Sensor *overflows_{nullptr};
+  sensor::Sensor *crc_errors_{nullptr};
+  sensor::Sensor *rx_garbage_{nullptr};
+  sensor::Sensor *frame_errors_{nullptr};
+  sensor::Sensor *parity_errors_{nullptr};
+  sensor::Sensor *framing_errors_{nullptr};
+  sensor::Sensor *rx_overruns_{nullptr};
+  sensor::Sensor *poll_interval_min_{nullptr};
+  sensor::Sensor *poll_interval_max_{nullptr};
+  sensor::Sensor *poll_interval_avg_{nullptr};
+};
+
+}  // namespace hcp_bridge
+}  // namespace esphome
@@ -11,23 +11,28 @@ class HCPLightSwitch : public switch_::Switch, public Component {
   void set_bridge(HCPBridge *bridge) { bridge_ = bridge; }
 
   void loop() override {
+    // Nothing is published while the drive is offline; the last state is stale until it is back
+    if (!bridge_->is_online()) {
+      online_ = false;
+      return;
+    }
     const auto *data = bridge_->get_data();
-    if (data == nullptr) return;
+    bool force = !online_;
+    online_ = true;
 
-    if (this->state != data->light_on) {
+    if (this->state != data->light_on || force) {
       this->publish_state(data->light_on);
     }
   }
 
   void write_state(bool state) override {
-    // HCP2 uses a toggle command for light
-    if (state != this->state) {
-      bridge_->set_command(hcp2::CMD_TOGGLE_LIGHT);
-    }
+    // The drive only has a toggle; the driver presses it only if the light differs
+    bridge_->set_command(state ? hcp2::DriveAction::LightOn : hcp2::DriveAction::LightOff);
   }
 
  protected:
   HCPBridge *bridge_;
+  bool online_{false};
 };
 
 }  // namespace hcp_bridge
@@ -11,25 +11,32 @@ class HCPVentSwitch : public switch_::Switch, public Component {
   void set_bridge(HCPBridge *bridge) { bridge_ = bridge; }
 
   void loop() override {
+    // Nothing is published while the drive is offline; the last state is stale until it is back
+    if (!bridge_->is_online()) {
+      online_ = false;
+      return;
+    }
     const auto *data = bridge_->get_data();
-    if (data == nullptr) return;
+    bool force = !online_;
+    online_ = true;
 
-    bool venting = (data->current_state == 0x0A); // VentReached
-    if (this->state != venting) {
+    bool venting = (data->current_state == hcp2::STATE_VENT_REACHED);
+    if (this->state != venting || force) {
       this->publish_state(venting);
     }
   }
 
   void write_state(bool state) override {
     if (state) {
-      bridge_->set_command(hcp2::CMD_VENT);
+      bridge_->set_command(hcp2::DriveAction::Vent);
     } else {
-      bridge_->set_command(hcp2::CMD_CLOSE);
+      bridge_->set_command(hcp2::DriveAction::Close);
     }
   }
 
  protected:
   HCPBridge *bridge_;
+  bool online_{false};
 };
 
 }  // namespace hcp_bridge
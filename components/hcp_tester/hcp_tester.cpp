@@ -17,9 +17,11 @@ extern "C" {
         void (*log)(void *ctx, const uint8_t *msg, size_t len);
     };
 
-    void hcp_tester_init();
-    void hcp_tester_poll(const TesterHalC *hal, TesterState *state);
-    void hcp_tester_set_control(float target_pos, bool toggle_light);
+    // Return 0 on success or a negative hcp2::FfiResult
+    int32_t hcp_tester_create(void *storage, size_t len);
+    int32_t hcp_tester_poll(void *handle, const TesterHalC *hal, TesterState *state);
+    int32_t hcp_tester_set_control(void *handle, float target_pos, bool toggle_light);
+    int32_t hcp_tester_destroy(void *handle);
 }
 
 // Helper to log hex buffers using ESPHome's logger
@@ -91,7 +93,11 @@ void HCPTester::setup() {
         flow_control_pin_->setup();
         flow_control_pin_->digital_write(false);
     }
-    hcp_tester_init();
+    int32_t res = hcp_tester_create(storage_, sizeof(storage_));
+    if (res != 0) {
+        ESP_LOGE(TAG, "Failed to create tester: %d", res);
+        this->mark_failed();
+    }
 }
 
 void HCPTester::loop() {
@@ -104,7 +110,11 @@ void HCPTester::loop() {
         .log = proxy_log,
     };
 
-    hcp_tester_poll(&hal, &state_);
+    int32_t res = hcp_tester_poll(storage_, &hal, &state_);
+    if (res != 0) {
+        ESP_LOGE(TAG, "Tester poll failed: %d", res);
+        this->mark_failed();
+    }
 }
 
 void HCPTester::dump_config() {
@@ -113,11 +123,11 @@ void HCPTester::dump_config() {
 }
 
 void HCPTester::set_target_position(float pos) {
-    hcp_tester_set_control(pos, false);
+    hcp_tester_set_control(storage_, pos, false);
 }
 
 void HCPTester::toggle_light() {
-    hcp_tester_set_control(state_.target_pos, true);
+    hcp_tester_set_control(storage_, state_.target_pos, true);
 }
 
 // Cover Implementation
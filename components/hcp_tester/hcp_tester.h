@@ -8,6 +8,10 @@
 namespace esphome {
 namespace hcp_tester {
 
+// Mirror hcp2_common::ffi::TESTER_STORAGE_SIZE and FFI_STORAGE_ALIGN
+static const size_t TESTER_STORAGE_SIZE = 128;
+static const size_t TESTER_STORAGE_ALIGN = 8;
+
 struct TesterState {
     float current_pos;
     float target_pos;
@@ -33,6 +37,8 @@ class HCPTester : public Component, public uart::UARTDevice {
 
  protected:
   GPIOPin *flow_control_pin_{nullptr};
+  // Caller-provided storage of the Rust tester instance (see hcp_tester_create)
+  alignas(TESTER_STORAGE_ALIGN) uint8_t storage_[TESTER_STORAGE_SIZE]{};
 };
 
 class HCPTesterCover : public cover::Cover, public Component {
@@ -1,6 +1,13 @@
 #![no_std]
-use hcp2_common::{Hcp2Driver, HcpHal, SharedData};
-use panic_halt as _;
+use core::ffi::c_void;
+use core::mem::{align_of, size_of, MaybeUninit};
+use hcp2_common::ffi::{self, FfiObject, FfiResult, FFI_STORAGE_ALIGN, HP_DRIVER_STORAGE_SIZE};
+use hcp2_common::health::{HeartbeatMonitor, Liveness, PanicRecord};
+use hcp2_common::trace::{TraceEntry, TraceRing};
+use hcp2_common::{Hcp2Config, Hcp2Driver, HcpHal, SharedData};
+
+/// Called with the panic record before the chip is reset.
+pub type PanicCallback = extern "C" fn(record: *const PanicRecord);
 
 // C-compatible struct for function pointers
 #[repr(C)]
@@ -12,6 +19,11 @@ pub struct HcpHalC {
     pub now_ms: extern "C" fn() -> u32,
     pub sleep_ms: extern "C" fn(u32),
     pub log: extern "C" fn(*mut core::ffi::c_void, *const u8, usize),
+    // Optional, may be null (see the provided methods of `HcpHal`)
+    pub now_us: Option<extern "C" fn() -> u32>,
+    pub take_rx_idle: Option<extern "C" fn(*mut core::ffi::c_void) -> bool>,
+    pub wait_tx_complete: Option<extern "C" fn(*mut core::ffi::c_void) -> bool>,
+    pub take_line_errors: Option<extern "C" fn(*mut core::ffi::c_void) -> u8>,
 }
 
 struct HcpHalWrapper<'a> {
@@ -44,21 +56,190 @@ impl<'a> HcpHal for HcpHalWrapper<'a> {
     fn log(&mut self, message: &str) {
         (self.inner.log)(self.inner.ctx, message.as_ptr(), message.len());
     }
+
+    fn now_us(&self) -> Option<u32> {
+        self.inner.now_us.map(|now_us| now_us())
+    }
+
+    fn take_rx_idle(&mut self) -> bool {
+        self.inner.take_rx_idle.is_some_and(|take_rx_idle| take_rx_idle(self.inner.ctx))
+    }
+
+    fn wait_tx_complete(&mut self) -> bool {
+        self.inner.wait_tx_complete.is_some_and(|wait_tx_complete| wait_tx_complete(self.inner.ctx))
+    }
+
+    fn take_line_errors(&mut self) -> u8 {
+        self.inner.take_line_errors.map_or(0, |take_line_errors| take_line_errors(self.inner.ctx))
+    }
 }
 
-static mut DRIVER: Option<Hcp2Driver> = None;
+/// One driver instance, placed in storage owned by the caller.
+struct HpDriver {
+    driver: Hcp2Driver,
+    /// Recent frames. Written by `hcp_hp_poll`, read through `hcp_hp_read_trace`.
+    trace: TraceRing,
+}
 
+impl FfiObject for HpDriver {
+    const TAG: u32 = 0x4850_4431;
+}
+
+// The tag word and alignment padding come on top of the instance
+const _: () = assert!(size_of::<HpDriver>() + FFI_STORAGE_ALIGN <= HP_DRIVER_STORAGE_SIZE);
+const _: () = assert!(align_of::<HpDriver>() <= FFI_STORAGE_ALIGN);
+
+/// Creates a driver in `storage` (`len` bytes, at least `HP_DRIVER_STORAGE_SIZE` is enough,
+/// aligned to `FFI_STORAGE_ALIGN`). `storage` is the handle for the other `hcp_hp_*` calls.
+/// `config` may be null to use the built-in defaults. Each instance drives its own bus
+/// through the HAL and shared block passed to `hcp_hp_poll`.
+///
+/// # Safety
+/// `storage` must be null or valid for `len` bytes and not be used otherwise until `hcp_hp_destroy`.
+/// `config` must be null or point to a valid `Hcp2Config`.
 #[no_mangle]
-pub unsafe extern "C" fn hcp_hp_init() {
-    DRIVER = Some(Hcp2Driver::new());
+pub unsafe extern "C" fn hcp_hp_create(storage: *mut c_void, len: usize, config: *const Hcp2Config) -> FfiResult {
+    let config = config.as_ref().copied().unwrap_or_default();
+    let instance = HpDriver { driver: Hcp2Driver::new(config), trace: TraceRing::new() };
+    ffi::create(storage as *mut u8, len, instance).map(|_| ()).into()
 }
 
+/// Runs one driver iteration. Does nothing while `shared_ptr` does not carry this library's
+/// layout header (see `SharedData::accept_layout`).
+///
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_hp_create`. `hal_ptr` and `shared_ptr`
+/// must be null or valid. Must not run concurrently with any other call on the same handle.
 #[no_mangle]
-pub unsafe extern "C" fn hcp_hp_poll(hal_ptr: *const HcpHalC, shared_ptr: *mut SharedData) {
-    if let Some(driver) = core::ptr::addr_of_mut!(DRIVER).as_mut().unwrap().as_mut() {
-        let hal_c = &*hal_ptr;
+pub unsafe extern "C" fn hcp_hp_poll(handle: *mut c_void, hal_ptr: *const HcpHalC, shared_ptr: *mut SharedData) -> FfiResult {
+    let instance = match ffi::get::<HpDriver>(handle as *mut u8) {
+        Ok(instance) => instance,
+        Err(e) => return e,
+    };
+    let (Some(hal_c), Some(shared)) = (hal_ptr.as_ref(), shared_ptr.as_mut()) else {
+        return FfiResult::NullPointer;
+    };
+    if shared.accept_layout() {
         let mut hal = HcpHalWrapper { inner: hal_c };
-        let shared = &mut *shared_ptr;
-        driver.poll(&mut hal, shared);
+        instance.driver.poll_with_trace(&mut hal, shared, &mut instance.trace);
+    }
+    FfiResult::Ok
+}
+
+/// Copies up to `max` of the most recent frames into `out`, oldest first. Returns how many
+/// were written, or a negative `FfiResult`. Callers reading from another task than the one
+/// polling must serialize the two calls, e.g. with a mutex.
+///
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_hp_create`. `out` must be null or valid for `max` entries.
+/// Must not run concurrently with `hcp_hp_poll` on the same handle.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_hp_read_trace(handle: *const c_void, out: *mut TraceEntry, max: usize) -> i32 {
+    let instance = match ffi::get_ref::<HpDriver>(handle as *const u8) {
+        Ok(instance) => instance,
+        Err(e) => return e as i32,
+    };
+    if out.is_null() {
+        return FfiResult::NullPointer as i32;
+    }
+    let out = core::slice::from_raw_parts_mut(out, max);
+    instance.trace.snapshot(out) as i32
+}
+
+/// Ends the driver in `handle`; the storage may be reused afterwards.
+///
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_hp_create`. No other call on it may be running.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_hp_destroy(handle: *mut c_void) -> FfiResult {
+    ffi::destroy::<HpDriver>(handle as *mut u8).into()
+}
+
+/// Watchdog over the driver heartbeat in `shared` (LP mode). Returns `Liveness::Stalled`
+/// once it has not moved for the monitor's timeout; the caller then restarts the LP core and
+/// zeroes `monitor`. Start with a zeroed `monitor` for the default timeout. A null `monitor`
+/// or `shared` reports `Liveness::Stalled`, as no heartbeat can be seen.
+///
+/// # Safety
+/// `monitor` and `shared` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_check_liveness(monitor: *mut HeartbeatMonitor, shared: *const SharedData, now_ms: u32) -> Liveness {
+    if monitor.is_null() || shared.is_null() {
+        return Liveness::Stalled;
+    }
+    let heartbeat = core::ptr::read_volatile(core::ptr::addr_of!((*shared).health.heartbeat));
+    (*monitor).check(heartbeat, now_ms)
+}
+
+/// Last panic. Lives in ESP-IDF's `.noinit` region, which keeps its contents across a
+/// software reset, so the record written by the panic handler is still there after the reboot.
+/// Random after power-on; only trusted with `PanicRecord::is_valid`.
+#[link_section = ".noinit.hcp2_panic"]
+static mut LAST_PANIC: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+static mut PANIC_CALLBACK: Option<PanicCallback> = None;
+
+fn last_panic() -> *mut PanicRecord {
+    unsafe { (*core::ptr::addr_of_mut!(LAST_PANIC)).as_mut_ptr() }
+}
+
+/// Registers a function to call when the driver panics, e.g. to log the record.
+/// It runs in the panicking task and must not call back into this library. Null removes it.
+///
+/// # Safety
+/// Must not run concurrently with `hcp_hp_poll`.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_hp_set_panic_callback(callback: Option<PanicCallback>) {
+    PANIC_CALLBACK = callback;
+}
+
+/// Copies the panic recorded before the last software reset into `out`.
+/// Returns false (and leaves `out` alone) if there is none.
+///
+/// # Safety
+/// `out` must be valid. Must not run concurrently with a panic.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_hp_last_panic(out: *mut PanicRecord) -> bool {
+    let record = core::ptr::read_volatile(last_panic());
+    if out.is_null() || !record.is_valid() {
+        return false;
+    }
+    *out = record;
+    true
+}
+
+/// Forgets the recorded panic, so it is not reported again after the next reset.
+///
+/// # Safety
+/// Must not run concurrently with a panic.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_hp_clear_panic() {
+    core::ptr::write_volatile(last_panic(), PanicRecord::empty());
+}
+
+#[cfg(not(test))]
+extern "C" {
+    /// ESP-IDF's `abort`: prints a backtrace and resets the chip.
+    fn abort() -> !;
+}
+
+/// Records the panic, hands it to the registered callback and resets the chip instead of
+/// freezing the task.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    static mut PANICKING: bool = false;
+    unsafe {
+        // A panic inside the callback must not overwrite the original record
+        if !core::ptr::replace(core::ptr::addr_of_mut!(PANICKING), true) {
+            let record = &mut *last_panic();
+            match info.location() {
+                Some(location) => record.record(location.file(), location.line(), format_args!("{}", info.message())),
+                None => record.record("", 0, format_args!("{}", info.message())),
+            }
+            if let Some(callback) = PANIC_CALLBACK {
+                callback(record);
+            }
+        }
+        abort()
     }
 }
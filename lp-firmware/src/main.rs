@@ -3,20 +3,66 @@
 
 use esp_lp_hal::{
     delay::Delay,
+    pac,
     prelude::*,
 };
-use hcp2_common::{SharedData, Hcp2Driver, HcpHal};
-use panic_halt as _;
+use hcp2_common::hal::{LINE_ERROR_FRAMING, LINE_ERROR_OVERRUN, LINE_ERROR_PARITY};
+use hcp2_common::shared::LpSharedMemory;
+use hcp2_common::{Hcp2Driver, HcpHal};
 use embedded_hal::delay::DelayNs;
 use embedded_hal::digital::OutputPin;
 
 // Shared memory at fixed address for HP core to find
 const SHARED_MEM_ADDR: usize = 0x50003000;
 
+/// Receive timeout in bit times: 3.5 characters of 11 bits (8E1).
+const RX_IDLE_BITS: u16 = 39;
+/// Longest wait for the transmitter in `wait_tx_complete` (a full 128-byte frame at 9600 baud).
+const TX_COMPLETE_TIMEOUT_US: u64 = 150_000;
+/// LP core clock when it runs from the crystal (40 MHz / 2).
+const XTAL_D2_HZ: u64 = 20_000_000;
+
+/// `LpUart` only moves data; the event flags are read from the registers directly.
+fn uart_regs() -> &'static pac::lp_uart::RegisterBlock {
+    unsafe { &*pac::LP_UART::PTR }
+}
+
 struct LpHal {
     uart: esp_lp_hal::uart::LpUart,
     dir_pin: esp_lp_hal::gpio::Output<2>,
-    current_ms: u32,
+    /// `mcycle` ticks per second (the LP core runs from RC_FAST or XTAL/2)
+    clock_hz: u64,
+}
+
+impl LpHal {
+    /// `rc_fast_hz` is the RC_FAST frequency measured by the HP core; it is only nominally
+    /// 17.5 MHz and varies by several percent between chips, which would skew the RTU gap.
+    fn new(uart: esp_lp_hal::uart::LpUart, dir_pin: esp_lp_hal::gpio::Output<2>, rc_fast_hz: u32) -> Self {
+        let xtal = unsafe { &*pac::LP_CLKRST::PTR }.lp_clk_conf().read().fast_clk_sel().bit_is_set();
+        let regs = uart_regs();
+        unsafe { regs.tout_conf_sync().modify(|_, w| w.rx_tout_en().set_bit().rx_tout_thrhd().bits(RX_IDLE_BITS)) };
+        // Registers ending in _sync only take effect after an update
+        regs.reg_update().write(|w| w.reg_update().set_bit());
+        regs.int_clr().write(|w| {
+            w.rxfifo_tout().clear_bit_by_one();
+            w.parity_err().clear_bit_by_one();
+            w.frm_err().clear_bit_by_one();
+            w.rxfifo_ovf().clear_bit_by_one();
+            w.tx_done().clear_bit_by_one()
+        });
+        let clock_hz = if xtal { XTAL_D2_HZ } else { rc_fast_hz as u64 };
+        Self { uart, dir_pin, clock_hz }
+    }
+
+    fn now_cycles(&self) -> u64 {
+        riscv::register::mcycle::read64()
+    }
+
+    /// Time since start in units of `1 / per_second` s. Split so the product cannot overflow.
+    fn elapsed(&self, per_second: u64) -> u64 {
+        let cycles = self.now_cycles();
+        cycles / self.clock_hz * per_second + cycles % self.clock_hz * per_second / self.clock_hz
+    }
 }
 
 impl HcpHal for LpHal {
@@ -35,6 +81,8 @@ impl HcpHal for LpHal {
     }
 
     fn uart_write(&mut self, buf: &[u8]) -> usize {
+        // A stale flag would end `wait_tx_complete` before these bytes are out
+        uart_regs().int_clr().write(|w| w.tx_done().clear_bit_by_one());
         let _ = self.uart.write_bytes(buf);
         buf.len()
     }
@@ -48,17 +96,64 @@ impl HcpHal for LpHal {
     }
 
     fn now_ms(&self) -> u32 {
-        self.current_ms
+        self.elapsed(1000) as u32
     }
 
     fn sleep_ms(&mut self, ms: u32) {
         Delay.delay_ms(ms);
-        self.current_ms = self.current_ms.wrapping_add(ms);
     }
 
     fn log(&mut self, _message: &str) {
         // No logging on LP core for now
     }
+
+    fn now_us(&self) -> Option<u32> {
+        Some(self.elapsed(1_000_000) as u32)
+    }
+
+    fn take_rx_idle(&mut self) -> bool {
+        let regs = uart_regs();
+        let idle = regs.int_raw().read().rxfifo_tout().bit_is_set();
+        if idle {
+            regs.int_clr().write(|w| w.rxfifo_tout().clear_bit_by_one());
+        }
+        idle
+    }
+
+    fn take_line_errors(&mut self) -> u8 {
+        let regs = uart_regs();
+        let raw = regs.int_raw().read();
+        let mut errors = 0;
+        if raw.parity_err().bit_is_set() {
+            errors |= LINE_ERROR_PARITY;
+        }
+        if raw.frm_err().bit_is_set() {
+            errors |= LINE_ERROR_FRAMING;
+        }
+        if raw.rxfifo_ovf().bit_is_set() {
+            errors |= LINE_ERROR_OVERRUN;
+        }
+        if errors != 0 {
+            regs.int_clr().write(|w| {
+                w.parity_err().clear_bit_by_one();
+                w.frm_err().clear_bit_by_one();
+                w.rxfifo_ovf().clear_bit_by_one()
+            });
+        }
+        errors
+    }
+
+    fn wait_tx_complete(&mut self) -> bool {
+        let regs = uart_regs();
+        let start = self.now_cycles();
+        let timeout = TX_COMPLETE_TIMEOUT_US * self.clock_hz / 1_000_000;
+        while !regs.int_raw().read().tx_done().bit_is_set() {
+            if self.now_cycles().wrapping_sub(start) > timeout {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 #[entry]
@@ -67,19 +162,46 @@ fn main() -> ! {
     let mut dir_pin = unsafe { esp_lp_hal::gpio::conjure_output::<2>().unwrap() };
     let _ = dir_pin.set_low();
 
-    let shared_data: &mut SharedData = unsafe { &mut *(SHARED_MEM_ADDR as *mut SharedData) };
+    let shared_mem: &mut LpSharedMemory = unsafe { &mut *(SHARED_MEM_ADDR as *mut LpSharedMemory) };
 
-    let mut hal = LpHal {
-        uart,
-        dir_pin,
-        current_ms: 0,
-    };
+    // Built against a different layout than the HP image: the config and mailbox would be
+    // read from the wrong offsets, so stay off the bus and let the HP core report it.
+    if !shared_mem.data.accept_layout() {
+        loop {
+            Delay.delay_ms(1000);
+        }
+    }
 
-    let mut driver = Hcp2Driver::new();
+    // The HP core fills in the config block before starting us
+    let config = shared_mem.data.config;
+    let mut hal = LpHal::new(uart, dir_pin, config.resolved().lp_clock_hz);
+
+    // Keeps the last panic; only the heartbeat restarts
+    shared_mem.data.health.on_start();
+
+    let mut driver = Hcp2Driver::new(config);
+    // We may have been restarted by the HP core after a hang or panic
+    driver.resume(&mut shared_mem.data);
+    shared_mem.trace.recover();
 
     loop {
-        driver.poll(&mut hal, shared_data);
-        // Short sleep to yield/save power, matching the logic of sleep_ms updating time
+        driver.poll_with_trace(&mut hal, &mut shared_mem.data, &mut shared_mem.trace);
+        shared_mem.data.health.beat();
+        // Short sleep to save power; well below the inter-frame gap
         hal.sleep_ms(1);
     }
-}
\ No newline at end of file
+}
+
+/// Leaves the location in shared memory for the HP core, which notices the stopped
+/// heartbeat and restarts us.
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let health = unsafe { &mut (*(SHARED_MEM_ADDR as *mut LpSharedMemory)).data.health };
+    match info.location() {
+        Some(location) => health.record_panic(location.file(), location.line()),
+        None => health.record_panic("", 0),
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -1,3 +1,4 @@
+use hcp2_common::codec::{ReadResponse, ReadWriteRequest, RegisterBlock, WriteRequest};
 use hcp2_common::registers::*;
 use hcp2_common::hal::HcpHal;
 use crate::garage_physics::GaragePhysics;
@@ -17,6 +18,12 @@ pub struct DriveProtocol {
     pub scan_address: u8,
 }
 
+impl Default for DriveProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DriveProtocol {
     pub fn new() -> Self {
         Self {
@@ -49,10 +56,7 @@ impl DriveProtocol {
                     self.scan_address -= 1;
                 }
 
-                let len = self.build_read_write_frame(&mut out_buf, target_addr, 
-                    ADDR_POLL, 5, 
-                    ADDR_SYNC_COUNTER, 3, 
-                    &[0, 0, 0]);
+                let len = Self::encode_read_write(&mut out_buf, target_addr, 5, &[0, 0, 0]);
                 
                 hal.log("Scanning...");
                 self.send_frame(hal, &out_buf[..len]);
@@ -65,7 +69,7 @@ impl DriveProtocol {
                 // Reg 1: Target | Current
                 let reg1 = ((physics.target_position as u16) << 8) | (physics.current_position as u16);
                 // Reg 2: State
-                let reg2 = (physics.get_drive_state() as u16) << 8;
+                let reg2 = (u8::from(physics.get_drive_state()) as u16) << 8;
                 // Reg 6: Light (Bit 0x10)
                 let reg6 = if physics.light_on { 0x0010 } else { 0x0000 };
 
@@ -74,7 +78,9 @@ impl DriveProtocol {
                     0x0000, 0x0000, reg6, 0x0000, 0x0000
                 ];
                 
-                let len = self.build_write_frame(&mut out_buf, ADDRESS_BROADCAST, ADDR_STATUS_UPDATE, &regs);
+                let len = RegisterBlock::new(ADDR_STATUS_UPDATE, &regs)
+                    .and_then(|write| WriteRequest { address: ADDRESS_BROADCAST, write }.encode(&mut out_buf))
+                    .unwrap_or(0);
                 
                 hal.log("Broadcasting status...");
                 self.send_frame(hal, &out_buf[..len]);
@@ -93,10 +99,7 @@ impl DriveProtocol {
                 let sync_val = ((self.sync_counter as u16) << 8) | (self.command_code as u16);
 
                 // Send Poll Request: Read 8 registers
-                let len = self.build_read_write_frame(&mut out_buf, self.scan_address, 
-                    ADDR_POLL, 8, 
-                    ADDR_SYNC_COUNTER, 1, 
-                    &[sync_val]);
+                let len = Self::encode_read_write(&mut out_buf, self.scan_address, 8, &[sync_val]);
                 
                 hal.log("Polling...");
                 self.send_frame(hal, &out_buf[..len]);
@@ -114,7 +117,7 @@ impl DriveProtocol {
     }
 
     fn send_frame<H: HcpHal>(&self, hal: &mut H, frame: &[u8]) {
-        if frame.len() > 0 {
+        if !frame.is_empty() {
             hal.set_tx_enable(true);
             hal.uart_write(frame);
             hal.set_tx_enable(false);
@@ -122,49 +125,27 @@ impl DriveProtocol {
     }
 
     pub fn handle_response(&mut self, frame: &[u8], physics: &mut GaragePhysics) {
-        // Simple validation
-        if frame.len() < 4 { return; }
-        
-        // Verify CRC
-        let len = frame.len();
-        let received_crc = ((frame[len - 1] as u16) << 8) | (frame[len - 2] as u16);
-        let calculated_crc = self.crc16(&frame[..len - 2]);
-        if received_crc != calculated_crc {
+        // Anything that is not a well-formed 0x17 response is ignored
+        let Ok(response) = ReadResponse::decode(frame) else {
             return;
-        }
+        };
 
         // Parse response based on state
         match self.state {
-            DriveProtocolState::Scan => {
-                // If we got a valid response, check if the address matches what we just scanned.
-                // Or simply assume if we got ANY valid Modbus response, it's the device.
-                
-                let addr = frame[0];
-                let func = frame[1];
-
-                // Validate Address (cannot be broadcast) and Function (must be 0x17)
-                if addr != 0x00 && func == FUNC_READ_WRITE_MULTIPLE_REGISTERS {
-                    self.scan_address = addr; // Lock onto this address
-                    self.state = DriveProtocolState::Broadcast;
-                }
+            // Any valid response to the scan is the device; lock onto its address
+            // (a response can never come from the broadcast address)
+            DriveProtocolState::Scan if response.address != ADDRESS_BROADCAST => {
+                self.scan_address = response.address;
+                self.state = DriveProtocolState::Broadcast;
             },
             DriveProtocolState::Poll => {
-                let addr = frame[0];
                 // Ensure response is from the device we are polling
-                if addr != self.scan_address { return; }
-
-                // Expecting function 0x17 response
-                if frame[1] != FUNC_READ_WRITE_MULTIPLE_REGISTERS { return; }
-                let byte_count = frame[2] as usize;
-                if frame.len() < 3 + byte_count { return; }
-
-                // Parse action registers (Index 2 and 3 -> Bytes 7-10)
-                if byte_count >= 8 {
-                    let r2 = ((frame[7] as u16) << 8) | (frame[8] as u16);
-                    let r3 = ((frame[9] as u16) << 8) | (frame[10] as u16);
-                    
-                    let action = self.decode_action(r2, r3);
-                    if action != DriveAction::None {
+                if response.address != self.scan_address { return; }
+
+                // Parse action registers (index 2 and 3)
+                if let [_, _, r2, r3, ..] = *response.regs.as_slice() {
+                    // Trigger on the pressing registers; the release matches no action
+                    if let Some(action) = DriveAction::from_pressing(r2, r3) {
                         physics.handle_action(action);
                     }
                 }
@@ -174,80 +155,11 @@ impl DriveProtocol {
         }
     }
 
-    fn decode_action(&self, r2: u16, r3: u16) -> DriveAction {
-        // Logic from PROTOCOL.md
-        // Note: The protocol sends Pressing then Release. We should trigger on Pressing.
-        match (r2, r3) {
-            (0x0210, 0x0000) => DriveAction::Open,
-            (0x0220, 0x0000) => DriveAction::Close,
-            (0x0240, 0x0000) => DriveAction::Stop,
-            (0x0200, 0x0400) => DriveAction::HalfOpen,
-            (0x0200, 0x4000) => DriveAction::Vent,
-            (0x0100, 0x0200) => DriveAction::ToggleLight,
-            _ => DriveAction::None,
-        }
-    }
-
-    fn build_write_frame(&self, buf: &mut [u8], addr: u8, start: u16, regs: &[u16]) -> usize {
-        buf[0] = addr;
-        buf[1] = FUNC_WRITE_MULTIPLE_REGISTERS;
-        buf[2] = (start >> 8) as u8;
-        buf[3] = (start & 0xFF) as u8;
-        let qty = regs.len() as u16;
-        buf[4] = (qty >> 8) as u8;
-        buf[5] = (qty & 0xFF) as u8;
-        buf[6] = (qty * 2) as u8;
-        
-        for (i, &reg) in regs.iter().enumerate() {
-            buf[7 + i*2] = (reg >> 8) as u8;
-            buf[8 + i*2] = (reg & 0xFF) as u8;
-        }
-        
-        let len = 7 + (qty * 2) as usize;
-        let crc = self.crc16(&buf[..len]);
-        buf[len] = (crc & 0xFF) as u8;
-        buf[len+1] = (crc >> 8) as u8;
-        len + 2
-    }
-
-    fn build_read_write_frame(&self, buf: &mut [u8], addr: u8, rd_start: u16, rd_qty: u16, wr_start: u16, wr_qty: u16, wr_regs: &[u16]) -> usize {
-        buf[0] = addr;
-        buf[1] = FUNC_READ_WRITE_MULTIPLE_REGISTERS;
-        buf[2] = (rd_start >> 8) as u8;
-        buf[3] = (rd_start & 0xFF) as u8;
-        buf[4] = (rd_qty >> 8) as u8;
-        buf[5] = (rd_qty & 0xFF) as u8;
-        buf[6] = (wr_start >> 8) as u8;
-        buf[7] = (wr_start & 0xFF) as u8;
-        buf[8] = (wr_qty >> 8) as u8;
-        buf[9] = (wr_qty & 0xFF) as u8;
-        buf[10] = (wr_qty * 2) as u8;
-
-        for (i, &reg) in wr_regs.iter().enumerate() {
-            buf[11 + i*2] = (reg >> 8) as u8;
-            buf[12 + i*2] = (reg & 0xFF) as u8;
-        }
-
-        let len = 11 + (wr_qty * 2) as usize;
-        let crc = self.crc16(&buf[..len]);
-        buf[len] = (crc & 0xFF) as u8;
-        buf[len+1] = (crc >> 8) as u8;
-        len + 2
-    }
-
-    fn crc16(&self, data: &[u8]) -> u16 {
-        let mut crc = 0xFFFFu16;
-        for &byte in data {
-            crc ^= byte as u16;
-            for _ in 0..8 {
-                if (crc & 1) != 0 {
-                    crc >>= 1;
-                    crc ^= 0xA001;
-                } else {
-                    crc >>= 1;
-                }
-            }
-        }
-        crc
+    /// Poll (read `read_qty` registers at `ADDR_POLL`) combined with a sync counter write.
+    /// Returns 0 if the frame could not be built, which `send_frame` skips.
+    fn encode_read_write(buf: &mut [u8], address: u8, read_qty: u16, sync_regs: &[u16]) -> usize {
+        RegisterBlock::new(ADDR_SYNC_COUNTER, sync_regs)
+            .and_then(|write| ReadWriteRequest { address, read_start: ADDR_POLL, read_qty, write }.encode(buf))
+            .unwrap_or(0)
     }
 }
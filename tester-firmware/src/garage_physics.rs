@@ -8,6 +8,12 @@ pub struct GaragePhysics {
     pub speed: f32, // Position units per tick
 }
 
+impl Default for GaragePhysics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GaragePhysics {
     pub fn new() -> Self {
         Self {
@@ -64,7 +70,8 @@ impl GaragePhysics {
                 self.vent_on = true;
             },
             DriveAction::ToggleLight => self.light_on = !self.light_on,
-            DriveAction::None => {},
+            // Not a button; the bridge turns it into Open/Close and Stop
+            DriveAction::None | DriveAction::GoToPosition | DriveAction::LightOn | DriveAction::LightOff => {},
         }
     }
 }
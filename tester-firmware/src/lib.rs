@@ -10,6 +10,9 @@ pub use garage_physics::GaragePhysics;
 pub use drive_protocol::DriveProtocol;
 pub use drive_protocol::DriveProtocolState;
 
+use core::ffi::c_void;
+use core::mem::{align_of, size_of};
+use hcp2_common::ffi::{self, FfiObject, FfiResult, FFI_STORAGE_ALIGN, TESTER_STORAGE_SIZE};
 use hcp2_common::hal::HcpHal;
 
 // FFI Interface
@@ -22,15 +25,38 @@ pub struct TesterState {
     pub last_action: u8,
 }
 
-static mut PHYSICS: Option<GaragePhysics> = None;
-static mut PROTOCOL: Option<DriveProtocol> = None;
+/// One simulated drive, placed in storage owned by the caller.
+#[derive(Default)]
+struct Tester {
+    physics: GaragePhysics,
+    protocol: DriveProtocol,
+}
+
+impl FfiObject for Tester {
+    const TAG: u32 = 0x5453_5431;
+}
+
+// The tag word and alignment padding come on top of the instance
+const _: () = assert!(size_of::<Tester>() + FFI_STORAGE_ALIGN <= TESTER_STORAGE_SIZE);
+const _: () = assert!(align_of::<Tester>() <= FFI_STORAGE_ALIGN);
 
+/// Creates a simulated drive in `storage` (`len` bytes, at least `TESTER_STORAGE_SIZE` is
+/// enough, aligned to `FFI_STORAGE_ALIGN`). `storage` is the handle for the other calls.
+///
+/// # Safety
+/// `storage` must be null or valid for `len` bytes and not be used otherwise until `hcp_tester_destroy`.
 #[no_mangle]
-pub extern "C" fn hcp_tester_init() {
-    unsafe {
-        PHYSICS = Some(GaragePhysics::new());
-        PROTOCOL = Some(DriveProtocol::new());
-    }
+pub unsafe extern "C" fn hcp_tester_create(storage: *mut c_void, len: usize) -> FfiResult {
+    ffi::create(storage as *mut u8, len, Tester::default()).map(|_| ()).into()
+}
+
+/// Ends the simulated drive in `handle`; the storage may be reused afterwards.
+///
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_tester_create`. No other call on it may be running.
+#[no_mangle]
+pub unsafe extern "C" fn hcp_tester_destroy(handle: *mut c_void) -> FfiResult {
+    ffi::destroy::<Tester>(handle as *mut u8).into()
 }
 
 #[repr(C)]
@@ -80,43 +106,53 @@ impl<'a> HcpHal for TesterHalWrapper<'a> {
     }
 }
 
+/// Runs one simulation step and copies the result into `state` (may be null).
+///
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_tester_create`; `hal` and `state` must be null or valid.
 #[no_mangle]
-pub extern "C" fn hcp_tester_poll(hal: *const TesterHalC, state: *mut TesterState) {
-    unsafe {
-        let physics = core::ptr::addr_of_mut!(PHYSICS).as_mut().unwrap().as_mut().unwrap();
-        let protocol = core::ptr::addr_of_mut!(PROTOCOL).as_mut().unwrap().as_mut().unwrap();
-        let hal_c = &*hal;
-        let mut hal_wrapper = TesterHalWrapper { inner: hal_c };
-
-        // Run Physics
-        physics.tick();
-
-        // Check for incoming response
-        protocol.check_rx(&mut hal_wrapper, physics);
-
-        // Run Protocol (Generate Request)
-        protocol.poll(&mut hal_wrapper, physics);
-
-        // Update State Struct for C++
-        if !state.is_null() {
-            (*state).current_pos = physics.current_position;
-            (*state).target_pos = physics.target_position;
-            (*state).light_on = physics.light_on;
-            (*state).vent_on = physics.vent_on;
-        }
+pub unsafe extern "C" fn hcp_tester_poll(handle: *mut c_void, hal: *const TesterHalC, state: *mut TesterState) -> FfiResult {
+    let Tester { physics, protocol } = match ffi::get::<Tester>(handle as *mut u8) {
+        Ok(tester) => tester,
+        Err(e) => return e,
+    };
+    let Some(hal_c) = hal.as_ref() else {
+        return FfiResult::NullPointer;
+    };
+    let mut hal_wrapper = TesterHalWrapper { inner: hal_c };
+
+    // Run Physics
+    physics.tick();
+
+    // Check for incoming response
+    protocol.check_rx(&mut hal_wrapper, physics);
+
+    // Run Protocol (Generate Request)
+    protocol.poll(&mut hal_wrapper, physics);
+
+    // Update State Struct for C++
+    if let Some(state) = state.as_mut() {
+        state.current_pos = physics.current_position;
+        state.target_pos = physics.target_position;
+        state.light_on = physics.light_on;
+        state.vent_on = physics.vent_on;
     }
+    FfiResult::Ok
 }
 
+/// # Safety
+/// `handle` must be null or storage passed to `hcp_tester_create`.
 #[no_mangle]
-pub extern "C" fn hcp_tester_set_control(target_pos: f32, toggle_light: bool) {
-    unsafe {
-        if let Some(physics) = core::ptr::addr_of_mut!(PHYSICS).as_mut().unwrap().as_mut() {
-            physics.target_position = target_pos;
-            if toggle_light {
-                physics.light_on = !physics.light_on;
-            }
-        }
+pub unsafe extern "C" fn hcp_tester_set_control(handle: *mut c_void, target_pos: f32, toggle_light: bool) -> FfiResult {
+    let tester = match ffi::get::<Tester>(handle as *mut u8) {
+        Ok(tester) => tester,
+        Err(e) => return e,
+    };
+    tester.physics.target_position = target_pos;
+    if toggle_light {
+        tester.physics.light_on = !tester.physics.light_on;
     }
+    FfiResult::Ok
 }
 
 #[cfg(not(feature = "std"))]
@@ -1,7 +1,15 @@
 use hcp2_tester_lib::{DriveProtocol, DriveProtocolState, GaragePhysics};
 use hcp2_common::driver::Hcp2Driver;
-use hcp2_common::shared::{SharedData, CMD_OPEN};
-use hcp2_common::hal::HcpHal;
+use hcp2_common::registers::DriveAction;
+use hcp2_common::shared::{SharedData, CMD_STATUS_ACKNOWLEDGED};
+use hcp2_common::hal::{HcpHal, LINE_ERROR_FRAMING, LINE_ERROR_OVERRUN, LINE_ERROR_PARITY};
+use hcp2_common::codec::{crc16, BusFrame, ReadResponse, ReadWriteRequest, RegisterBlock, Request, WriteRequest};
+use hcp2_common::protocol::DispatchError;
+use hcp2_common::trace::{Direction, TraceEntry, TraceRing, TraceSink, TRACE_LEN, TRACE_RESULT_CRC_MISMATCH, TRACE_RESULT_OK};
+use hcp2_common::Hcp2Config;
+use hcp2_common::config::EXCEPTION_ILLEGAL_FUNCTION;
+use hcp2_common::link::{LinkError, DEFAULT_SILENCE_TIMEOUT_MS};
+use hcp2_common::stats::BusStats;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -12,6 +20,15 @@ struct MockHal {
     now: u32,
     logs: Rc<RefCell<Vec<String>>>,
     name: String,
+    tx_enable_calls: u32,
+    /// Microsecond clock; None leaves the driver on `now`.
+    now_us: Option<u32>,
+    /// Reported once by `take_rx_idle`.
+    rx_idle: bool,
+    /// Whether `wait_tx_complete` is supported.
+    tx_complete: bool,
+    /// `LINE_ERROR_*` bits reported once, after the next read.
+    line_errors: u8,
 }
 
 impl MockHal {
@@ -22,6 +39,11 @@ impl MockHal {
             now: 0,
             logs: Rc::new(RefCell::new(Vec::new())),
             name: name.to_string(),
+            tx_enable_calls: 0,
+            now_us: None,
+            rx_idle: false,
+            tx_complete: false,
+            line_errors: 0,
         }
     }
 }
@@ -30,8 +52,8 @@ impl HcpHal for MockHal {
     fn uart_read(&mut self, buf: &mut [u8]) -> usize {
         let mut q = self.rx_queue.borrow_mut();
         let len = std::cmp::min(buf.len(), q.len());
-        for i in 0..len {
-            buf[i] = q.remove(0);
+        for (slot, byte) in buf.iter_mut().zip(q.drain(..len)) {
+            *slot = byte;
         }
         len
     }
@@ -41,7 +63,9 @@ impl HcpHal for MockHal {
         buf.len()
     }
 
-    fn set_tx_enable(&mut self, _enable: bool) {}
+    fn set_tx_enable(&mut self, _enable: bool) {
+        self.tx_enable_calls += 1;
+    }
 
     fn now_ms(&self) -> u32 {
         self.now
@@ -55,29 +79,68 @@ impl HcpHal for MockHal {
         self.logs.borrow_mut().push(message.to_string());
         println!("[{} Log] {}", self.name, message);
     }
+
+    fn now_us(&self) -> Option<u32> {
+        self.now_us
+    }
+
+    fn take_rx_idle(&mut self) -> bool {
+        core::mem::take(&mut self.rx_idle)
+    }
+
+    fn wait_tx_complete(&mut self) -> bool {
+        self.tx_complete
+    }
+
+    fn take_line_errors(&mut self) -> u8 {
+        core::mem::take(&mut self.line_errors)
+    }
+}
+
+/// Tester and bridge on one simulated bus: each HAL reads what the other one writes.
+struct Bench {
+    bus_tester_to_bridge: Rc<RefCell<Vec<u8>>>,
+    bus_bridge_to_tester: Rc<RefCell<Vec<u8>>>,
+    physics: GaragePhysics,
+    protocol: DriveProtocol,
+    tester_hal: MockHal,
+    bridge_hal: MockHal,
+    bridge: Hcp2Driver,
+    shared: SharedData,
+}
+
+impl Bench {
+    fn new() -> Self {
+        let bus_tester_to_bridge = Rc::new(RefCell::new(Vec::new()));
+        let bus_bridge_to_tester = Rc::new(RefCell::new(Vec::new()));
+        Self {
+            tester_hal: MockHal::new(bus_bridge_to_tester.clone(), bus_tester_to_bridge.clone(), "Tester"),
+            bridge_hal: MockHal::new(bus_tester_to_bridge.clone(), bus_bridge_to_tester.clone(), "Bridge"),
+            bus_tester_to_bridge,
+            bus_bridge_to_tester,
+            physics: GaragePhysics::new(),
+            protocol: DriveProtocol::new(),
+            bridge: Hcp2Driver::default(),
+            shared: SharedData::default(),
+        }
+    }
 }
 
 #[test]
 fn test_simulation_loop() {
     // 1. Setup
-    // Shared "Bus" (Buffers)
     // Tester writes to Bus1, Bridge reads from Bus1
     // Bridge writes to Bus2, Tester reads from Bus2
-    let bus_tester_to_bridge = Rc::new(RefCell::new(Vec::new()));
-    let bus_bridge_to_tester = Rc::new(RefCell::new(Vec::new()));
-
-    // Instantiate Components
-    let mut physics = GaragePhysics::new();
-    let mut protocol = DriveProtocol::new();
-    
-    // Tester HAL: RX=Bus2, TX=Bus1
-    let mut tester_hal = MockHal::new(bus_bridge_to_tester.clone(), bus_tester_to_bridge.clone(), "Tester");
-    
-    // Bridge HAL: RX=Bus1, TX=Bus2
-    let mut bridge_hal = MockHal::new(bus_tester_to_bridge.clone(), bus_bridge_to_tester.clone(), "Bridge");
-    
-    let mut bridge_driver = Hcp2Driver::new();
-    let mut shared_data = SharedData::default();
+    let Bench {
+        bus_tester_to_bridge,
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        bridge: mut bridge_driver,
+        shared: mut shared_data,
+    } = Bench::new();
 
     let mut current_time = 1000u32;
     let _step_ms = 10;
@@ -116,7 +179,7 @@ fn test_simulation_loop() {
     bridge_hal.now = current_time;
     bridge_driver.poll(&mut bridge_hal, &mut shared_data);
     
-    // Modbus RTU Timeout: Need to advance time > 10ms and poll again to trigger processing
+    // A complete frame is handled right away; polling again after the gap is harmless
     current_time += 15;
     bridge_hal.now = current_time;
     bridge_driver.poll(&mut bridge_hal, &mut shared_data);
@@ -151,7 +214,7 @@ fn test_simulation_loop() {
     bridge_driver.poll(&mut bridge_hal, &mut shared_data);
 
     // Verify SharedData updated (Initial state should be Stopped/Closed)
-    assert_eq!(shared_data.current_state, 0x40); // 0x40 = Closed (from DriveState::Closed)
+    assert_eq!(shared_data.lp_to_hp.read().unwrap().current_state, 0x40); // 0x40 = Closed (from DriveState::Closed)
 
     // Tester should now POLL
     assert_eq!(protocol.state, DriveProtocolState::Poll);
@@ -164,7 +227,7 @@ fn test_simulation_loop() {
 
     // --- USER ACTION: OPEN DOOR ---
     println!("--- Simulation: Sending Open Command ---");
-    shared_data.command_request = CMD_OPEN;
+    shared_data.push_command(1, DriveAction::Open);
 
     // Bridge processes Poll Request
     bridge_driver.poll(&mut bridge_hal, &mut shared_data);
@@ -183,7 +246,7 @@ fn test_simulation_loop() {
     
     // Verify Physics target updated
     // 0 = Closed, 200 = Open (100.0%)
-    // If CMD_OPEN sent, target should be 100.0 (or 200 int)
+    // If Open sent, target should be 100.0 (or 200 int)
     // GaragePhysics uses float 0.0-1.0 or 0.0-100.0?
     // Let's check GaragePhysics struct.
     // Assuming 0.0 to 1.0 or similar.
@@ -232,7 +295,580 @@ fn test_simulation_loop() {
     }
     
     assert!(physics.current_position > start_pos, "Door should have moved opened");
-    assert_eq!(shared_data.current_state, 0x01); // 0x01 = Opening
+    let status = shared_data.lp_to_hp.read().unwrap();
+    assert_eq!(status.current_state, 0x01); // 0x01 = Opening
+    assert!(status.link_online, "Bridge should consider the drive online");
+    assert_eq!(status.error_code, LinkError::None as u8);
+    assert!(status.last_update_ts > 1000, "Valid frames should be timestamped");
+
+    // --- PHASE 4: DRIVE GOES SILENT ---
+    bridge_hal.now = current_time + 15 + DEFAULT_SILENCE_TIMEOUT_MS + 1;
+    bridge_driver.poll(&mut bridge_hal, &mut shared_data);
+    let status = shared_data.lp_to_hp.read().unwrap();
+    assert!(!status.link_online, "Bridge should report the drive offline after silence");
+    assert_eq!(status.error_code, LinkError::BusSilent as u8);
     
     println!("Test Complete. Final Pos: {}", physics.current_position);
-}
\ No newline at end of file
+}
+#[test]
+fn test_configured_silence_timeout() {
+    let Bench { mut physics, mut protocol, mut tester_hal, mut bridge_hal, mut shared, .. } = Bench::new();
+    let mut bridge = Hcp2Driver::new(Hcp2Config { silence_timeout_ms: 500, ..Default::default() });
+    protocol.scan_address = 0x02;
+
+    let mut now = 1000;
+    for _ in 0..5 {
+        now += 100;
+        tester_hal.now = now;
+        bridge_hal.now = now;
+        protocol.poll(&mut tester_hal, &mut physics);
+        bridge.poll(&mut bridge_hal, &mut shared);
+        protocol.check_rx(&mut tester_hal, &mut physics);
+    }
+    assert!(shared.lp_to_hp.read().unwrap().link_online);
+
+    // Well within the default timeout, but past the configured one
+    bridge_hal.now = now + 501;
+    bridge.poll(&mut bridge_hal, &mut shared);
+    let status = shared.lp_to_hp.read().unwrap();
+    assert!(!status.link_online);
+    assert_eq!(status.error_code, LinkError::BusSilent as u8);
+}
+
+#[test]
+fn test_back_to_back_frames_in_one_read() {
+    let Bench {
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        bridge: mut bridge_driver,
+        shared: mut shared_data,
+        ..
+    } = Bench::new();
+
+    // Skip discovery: the tester already knows the bridge
+    protocol.scan_address = 0x02;
+    protocol.state = DriveProtocolState::Broadcast;
+
+    // Status broadcast immediately followed by a poll, with no gap in between
+    tester_hal.now = 1000;
+    protocol.poll(&mut tester_hal, &mut physics);
+    tester_hal.now = 1100;
+    protocol.poll(&mut tester_hal, &mut physics);
+
+    bridge_hal.now = 1100;
+    bridge_driver.poll(&mut bridge_hal, &mut shared_data);
+
+    assert_eq!(shared_data.lp_to_hp.read().unwrap().current_state, 0x40, "Broadcast should be applied");
+    assert!(!bus_bridge_to_tester.borrow().is_empty(), "Poll should be answered in the same pass");
+    assert!(!bridge_hal.logs.borrow().iter().any(|l| l.starts_with("Error")));
+}
+
+#[test]
+fn test_hardware_timed_frame_boundaries() {
+    let rx = Rc::new(RefCell::new(Vec::new()));
+    let tx = Rc::new(RefCell::new(Vec::new()));
+    let mut hal = MockHal::new(rx.clone(), tx.clone(), "Bridge");
+    let mut driver = Hcp2Driver::default();
+    let mut shared = SharedData::default();
+    hal.now = 1000;
+    hal.now_us = Some(1_000_000);
+    hal.tx_complete = true;
+
+    let mut frame = [0u8; 32];
+    // Action poll of our address
+    let write = RegisterBlock::new(0x9C41, &[0x0100]).unwrap();
+    let len = ReadWriteRequest { address: 0x02, read_start: 0x9CB9, read_qty: 8, write }.encode(&mut frame).unwrap();
+
+    // The RTU gap (1.75 ms) ends a partial frame well before the millisecond timeout
+    rx.borrow_mut().extend_from_slice(&frame[..4]);
+    driver.poll(&mut hal, &mut shared);
+    hal.now_us = Some(1_002_000);
+    driver.poll(&mut hal, &mut shared);
+    assert_eq!(driver.stats().framer_truncated, 1);
+
+    // An idle line reported by the UART ends it without any time passing
+    rx.borrow_mut().extend_from_slice(&frame[..4]);
+    driver.poll(&mut hal, &mut shared);
+    hal.rx_idle = true;
+    driver.poll(&mut hal, &mut shared);
+    assert_eq!(driver.stats().framer_truncated, 2);
+
+    // With TX complete supported, the bus is released without the turnaround sleep
+    rx.borrow_mut().extend_from_slice(&frame[..len]);
+    driver.poll(&mut hal, &mut shared);
+    assert!(!tx.borrow().is_empty());
+    assert_eq!(driver.stats().responses_sent, 1);
+    assert_eq!(hal.now, 1000);
+}
+
+#[test]
+fn test_line_errors_discard_frame() {
+    let Bench {
+        bus_tester_to_bridge,
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        mut bridge,
+        mut shared,
+    } = Bench::new();
+    protocol.scan_address = 0x02;
+
+    // The scan arrives in two reads; the UART flags a parity error in the first
+    tester_hal.now = 1000;
+    protocol.poll(&mut tester_hal, &mut physics);
+    let scan = core::mem::take(&mut *bus_tester_to_bridge.borrow_mut());
+    let mut corrupt = scan.clone();
+    corrupt[3] ^= 0x10;
+    bridge_hal.now = 1000;
+    bridge_hal.line_errors = LINE_ERROR_PARITY;
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&corrupt[..6]);
+    bridge.poll(&mut bridge_hal, &mut shared);
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&corrupt[6..]);
+    bridge.poll(&mut bridge_hal, &mut shared);
+
+    // Dropped without an answer, and without counting the tail as garbage
+    assert!(bus_bridge_to_tester.borrow().is_empty());
+    let stats = *bridge.stats();
+    assert_eq!((stats.line_parity_errors, stats.line_framing_errors, stats.line_overruns), (1, 0, 0));
+    assert_eq!((stats.frames_received, stats.framer_garbage, stats.crc_mismatch), (0, 0, 0));
+    assert!(bridge_hal.logs.borrow().iter().any(|l| l == "Error: UART Line Error"));
+
+    // The bus goes quiet
+    bridge_hal.now = 1015;
+    bridge.poll(&mut bridge_hal, &mut shared);
+
+    // A read with several error classes counts each of them once; the frame it completes
+    // is still decided by its CRC
+    bridge_hal.now = 1020;
+    bridge_hal.line_errors = LINE_ERROR_FRAMING | LINE_ERROR_OVERRUN;
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&corrupt);
+    bridge.poll(&mut bridge_hal, &mut shared);
+    assert!(bus_bridge_to_tester.borrow().is_empty());
+
+    // The drive retries after the gap and is answered
+    bridge_hal.now = 1040;
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&scan);
+    bridge.poll(&mut bridge_hal, &mut shared);
+    assert!(!bus_bridge_to_tester.borrow().is_empty());
+
+    let stats = shared.stats.read().expect("stats published");
+    assert_eq!((stats.line_parity_errors, stats.line_framing_errors, stats.line_overruns), (1, 1, 1));
+    assert_eq!((stats.bus_scans, stats.crc_mismatch, stats.framer_garbage), (1, 1, 0));
+}
+
+#[test]
+fn test_frame_after_line_error_answered() {
+    let Bench {
+        bus_tester_to_bridge,
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        mut bridge,
+        mut shared,
+    } = Bench::new();
+    protocol.scan_address = 0x02;
+
+    tester_hal.now = 1000;
+    protocol.poll(&mut tester_hal, &mut physics);
+    let scan = core::mem::take(&mut *bus_tester_to_bridge.borrow_mut());
+    let mut corrupt = scan.clone();
+    corrupt[3] ^= 0x10;
+
+    // The UART flags the start of a frame, and the next frame follows without a gap
+    bridge_hal.now = 1000;
+    bridge_hal.line_errors = LINE_ERROR_PARITY;
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&corrupt[..6]);
+    bridge.poll(&mut bridge_hal, &mut shared);
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&corrupt[6..]);
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&scan);
+    bridge.poll(&mut bridge_hal, &mut shared);
+
+    // Only the flagged frame is lost
+    assert!(!bus_bridge_to_tester.borrow().is_empty());
+    let stats = *bridge.stats();
+    assert_eq!((stats.line_parity_errors, stats.bus_scans), (1, 1));
+    assert_eq!((stats.framer_garbage, stats.crc_mismatch), (0, 0));
+}
+
+#[derive(Default)]
+struct RecordingTrace {
+    frames: Vec<(Direction, BusFrame)>,
+}
+
+impl TraceSink for RecordingTrace {
+    fn on_frame(&mut self, _now_ms: u32, direction: Direction, _raw: &[u8], result: Result<&BusFrame, DispatchError>) {
+        if let Ok(frame) = result {
+            self.frames.push((direction, *frame));
+        }
+    }
+}
+
+#[test]
+fn test_illegal_function_answered_with_exception() {
+    let rx = Rc::new(RefCell::new(Vec::new()));
+    let tx = Rc::new(RefCell::new(Vec::new()));
+    let mut bridge_hal = MockHal::new(rx.clone(), tx.clone(), "Bridge");
+    let config = Hcp2Config { exception_responses: EXCEPTION_ILLEGAL_FUNCTION, ..Default::default() };
+    let mut bridge = Hcp2Driver::new(config);
+    let mut shared = SharedData::default();
+
+    // Read holding registers, which the drive never uses
+    let mut request = vec![0x02, 0x03, 0x9C, 0xB9, 0x00, 0x02];
+    let crc = crc16(&request);
+    request.extend_from_slice(&crc.to_le_bytes());
+    rx.borrow_mut().extend_from_slice(&request);
+    bridge_hal.now = 1000;
+    bridge.poll(&mut bridge_hal, &mut shared);
+
+    let mut expected = vec![0x02, 0x83, 0x01];
+    let crc = crc16(&expected);
+    expected.extend_from_slice(&crc.to_le_bytes());
+    assert_eq!(*tx.borrow(), expected);
+    assert_eq!(bridge_hal.tx_enable_calls, 2);
+}
+
+#[test]
+fn test_listen_only_sniffer() {
+    // Tester and bridge talk normally; the sniffer gets a copy of everything on the bus
+    let Bench {
+        bus_tester_to_bridge,
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        mut bridge,
+        shared: mut bridge_shared,
+    } = Bench::new();
+    let bus_sniffer = Rc::new(RefCell::new(Vec::new()));
+    let mut sniffer_hal = MockHal::new(bus_sniffer.clone(), Rc::new(RefCell::new(Vec::new())), "Sniffer");
+    let mut sniffer = Hcp2Driver::new(Hcp2Config { listen_only: true, ..Default::default() });
+    let mut sniffer_shared = SharedData::default();
+    let mut trace = RecordingTrace::default();
+
+    // A command queued on the sniffer can never be sent
+    assert!(sniffer_shared.push_command(3, DriveAction::Open));
+
+    protocol.scan_address = 0x02;
+    protocol.state = DriveProtocolState::Broadcast;
+    let mut now = 1000;
+    for _ in 0..2 {
+        // Broadcast, then poll
+        for _ in 0..2 {
+            now += 100;
+            tester_hal.now = now;
+            bridge_hal.now = now;
+            sniffer_hal.now = now;
+            protocol.poll(&mut tester_hal, &mut physics);
+            bus_sniffer.borrow_mut().extend_from_slice(&bus_tester_to_bridge.borrow());
+            bridge.poll(&mut bridge_hal, &mut bridge_shared);
+
+            let response = bus_bridge_to_tester.borrow_mut().split_off(0);
+            bus_sniffer.borrow_mut().extend_from_slice(&response);
+            if !response.is_empty() {
+                protocol.handle_response(&response, &mut physics);
+            }
+            sniffer.poll_with_trace(&mut sniffer_hal, &mut sniffer_shared, &mut trace);
+        }
+    }
+
+    assert_eq!(sniffer_hal.tx_enable_calls, 0, "Sniffer must never drive the bus");
+    assert!(bridge_hal.tx_enable_calls > 0);
+
+    // Both directions decoded
+    let broadcasts = trace.frames.iter().filter(|(_, f)| matches!(f, BusFrame::Request(Request::Write(WriteRequest { address: 0, .. })))).count();
+    let polls = trace.frames.iter().filter(|(_, f)| matches!(f, BusFrame::Request(Request::ReadWrite(ReadWriteRequest { address: 2, .. })))).count();
+    let responses = trace.frames.iter().filter(|(_, f)| matches!(f, BusFrame::ReadResponse(ReadResponse { address: 2, .. }))).count();
+    assert_eq!((broadcasts, polls, responses), (2, 2, 2));
+    assert!(trace.frames.iter().all(|(d, _)| *d == Direction::Rx));
+
+    // Status taken from the broadcasts, command rejected
+    let status = sniffer_shared.lp_to_hp.read().unwrap();
+    assert_eq!(status.current_state, 0x40);
+    assert!(status.link_online);
+    assert_eq!(status.find_result(3).unwrap().status, hcp2_common::shared::CMD_STATUS_REJECTED);
+}
+
+#[test]
+fn test_trace_ring_records_transactions() {
+    let Bench {
+        bus_bridge_to_tester,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        mut bridge,
+        mut shared,
+        ..
+    } = Bench::new();
+    let mut trace = TraceRing::default();
+
+    // Scan of another address, then of ours
+    protocol.scan_address = 0x03;
+    for now in [1000, 1100] {
+        tester_hal.now = now;
+        bridge_hal.now = now;
+        protocol.poll(&mut tester_hal, &mut physics);
+        bridge.poll_with_trace(&mut bridge_hal, &mut shared, &mut trace);
+    }
+
+    let mut entries = [TraceEntry::default(); TRACE_LEN];
+    let count = trace.snapshot(&mut entries);
+    assert_eq!(count, 3);
+
+    assert_eq!(entries[0].direction, Direction::Rx as u8);
+    assert_eq!(entries[0].frame()[0], 0x03);
+    assert_eq!(entries[0].result, DispatchError::InvalidAddress as u8);
+
+    assert_eq!(entries[1].direction, Direction::Rx as u8);
+    assert_eq!(entries[1].frame()[0], 0x02);
+    assert_eq!(entries[1].result, TRACE_RESULT_OK);
+
+    assert_eq!(entries[2].direction, Direction::Tx as u8);
+    assert_eq!(entries[2].timestamp_ms, 1100);
+    assert_eq!(entries[2].frame(), &bus_bridge_to_tester.borrow()[..]);
+}
+
+#[test]
+fn test_bus_stats_published() {
+    let Bench {
+        bus_tester_to_bridge,
+        mut physics,
+        mut protocol,
+        mut tester_hal,
+        mut bridge_hal,
+        mut bridge,
+        mut shared,
+        ..
+    } = Bench::new();
+
+    // Scan of another address, scan of ours, then broadcast + three polls 100 ms apart
+    protocol.scan_address = 0x03;
+    for now in [1000, 1100, 1200, 1300, 1400, 1500] {
+        tester_hal.now = now;
+        bridge_hal.now = now;
+        protocol.poll(&mut tester_hal, &mut physics);
+        bridge.poll(&mut bridge_hal, &mut shared);
+        protocol.check_rx(&mut tester_hal, &mut physics);
+    }
+
+    let stats: BusStats = shared.stats.read().expect("stats published");
+    assert_eq!(stats, *bridge.stats());
+    assert_eq!(stats.frames_received, 6);
+    assert_eq!(stats.responses_sent, 3);
+    assert_eq!(stats.invalid_address, 1);
+    assert_eq!(stats.bus_scans, 1);
+    assert_eq!(stats.broadcasts, 2);
+    assert_eq!(stats.action_polls, 2);
+    assert_eq!(stats.sync_jumps, 0);
+    // The drive alternates broadcast and poll
+    assert_eq!((stats.poll_interval_min_ms, stats.poll_interval_max_ms, stats.poll_interval_avg_ms), (200, 200, 200));
+
+    // A complete frame with a bad CRC is skipped by the framer and counted as a CRC error
+    bus_tester_to_bridge.borrow_mut().extend_from_slice(&[0x02, 0x10, 0x9C, 0x41, 0x00, 0x01, 0x02, 0x05, 0x00, 0xFF, 0xFF]);
+    bridge_hal.now = 1600;
+    bridge.poll(&mut bridge_hal, &mut shared);
+    bridge_hal.now = 1700;
+    bridge.poll(&mut bridge_hal, &mut shared);
+    let stats = shared.stats.read().unwrap();
+    assert_eq!((stats.crc_mismatch, stats.framer_garbage), (1, 0));
+    assert_eq!(stats.frames_received, 6);
+}
+
+#[test]
+fn test_trace_ring_records_framer_errors() {
+    let rx = Rc::new(RefCell::new(Vec::new()));
+    let mut bridge_hal = MockHal::new(rx.clone(), Rc::new(RefCell::new(Vec::new())), "Bridge");
+    let mut bridge = Hcp2Driver::default();
+    let mut shared = SharedData::default();
+    let mut trace = TraceRing::default();
+
+    // A complete write with a bad CRC
+    let corrupt = [0x02, 0x10, 0x9C, 0x41, 0x00, 0x01, 0x02, 0x05, 0x00, 0xFF, 0xFF];
+    rx.borrow_mut().extend_from_slice(&corrupt);
+    bridge_hal.now = 1000;
+    bridge.poll_with_trace(&mut bridge_hal, &mut shared, &mut trace);
+    bridge_hal.now = 1100;
+    bridge.poll_with_trace(&mut bridge_hal, &mut shared, &mut trace);
+
+    let mut entries = [TraceEntry::default(); TRACE_LEN];
+    assert_eq!(trace.snapshot(&mut entries), 1);
+    assert_eq!(entries[0].direction, Direction::Rx as u8);
+    assert_eq!(entries[0].result, TRACE_RESULT_CRC_MISMATCH);
+    assert_eq!(entries[0].timestamp_ms, 1000);
+    assert_eq!(entries[0].frame(), &corrupt[..]);
+}
+
+#[test]
+fn test_go_to_position() {
+    let Bench { mut physics, mut protocol, mut tester_hal, mut bridge_hal, mut bridge, mut shared, .. } = Bench::new();
+    physics.speed = 4.0;
+    protocol.scan_address = 0x02;
+
+    let mut now = 1000;
+    let mut run = |ms: u32, physics: &mut GaragePhysics, shared: &mut SharedData| {
+        for _ in 0..ms / 100 {
+            now += 100;
+            tester_hal.now = now;
+            bridge_hal.now = now;
+            physics.tick();
+            protocol.poll(&mut tester_hal, physics);
+            bridge.poll(&mut bridge_hal, shared);
+            protocol.check_rx(&mut tester_hal, physics);
+        }
+    };
+    run(500, &mut physics, &mut shared);
+
+    let mut request = shared.hp_to_lp.read().unwrap();
+    request.target_position = 120;
+    shared.hp_to_lp.write(&request);
+    assert!(shared.push_command(1, DriveAction::GoToPosition));
+    run(10_000, &mut physics, &mut shared);
+
+    let status = shared.lp_to_hp.read().unwrap();
+    assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    let first_error = (physics.current_position - 120.0).abs();
+    assert!(first_error <= 12.0, "stopped at {}", physics.current_position);
+    assert!(status.position_overshoot > 0, "overshoot should be learned");
+
+    // Same move back down again benefits from the learned overshoot
+    let mut request = shared.hp_to_lp.read().unwrap();
+    request.target_position = 60;
+    shared.hp_to_lp.write(&request);
+    assert!(shared.push_command(2, DriveAction::GoToPosition));
+    run(10_000, &mut physics, &mut shared);
+
+    let status = shared.lp_to_hp.read().unwrap();
+    assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    assert!((physics.current_position - 60.0).abs() < first_error, "stopped at {}", physics.current_position);
+}
+
+#[test]
+fn test_light_on_off() {
+    let Bench { mut physics, mut protocol, mut tester_hal, mut bridge_hal, mut bridge, mut shared, .. } = Bench::new();
+    protocol.scan_address = 0x02;
+
+    let mut now = 1000;
+    let mut run = |ms: u32, physics: &mut GaragePhysics, shared: &mut SharedData| {
+        for _ in 0..ms / 100 {
+            now += 100;
+            tester_hal.now = now;
+            bridge_hal.now = now;
+            protocol.poll(&mut tester_hal, physics);
+            bridge.poll(&mut bridge_hal, shared);
+            protocol.check_rx(&mut tester_hal, physics);
+        }
+    };
+    run(500, &mut physics, &mut shared);
+
+    // Turning it on twice leaves it on
+    assert!(shared.push_command(1, DriveAction::LightOn));
+    run(2000, &mut physics, &mut shared);
+    assert!(physics.light_on);
+    assert!(shared.push_command(2, DriveAction::LightOn));
+    run(2000, &mut physics, &mut shared);
+    assert!(physics.light_on);
+
+    let status = shared.lp_to_hp.read().unwrap();
+    assert!(status.light_on);
+    assert_eq!(status.find_result(1).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+    assert_eq!(status.find_result(2).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+
+    assert!(shared.push_command(3, DriveAction::LightOff));
+    run(2000, &mut physics, &mut shared);
+    assert!(!physics.light_on);
+    assert_eq!(shared.lp_to_hp.read().unwrap().find_result(3).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+}
+
+#[test]
+fn test_driver_restart_drops_queued_commands() {
+    let Bench { mut physics, mut protocol, mut tester_hal, mut bridge_hal, mut shared, .. } = Bench::new();
+    protocol.scan_address = 0x02;
+
+    let mut now = 1000;
+    let mut run = |ms: u32, bridge: &mut Hcp2Driver, physics: &mut GaragePhysics, shared: &mut SharedData| {
+        for _ in 0..ms / 100 {
+            now += 100;
+            tester_hal.now = now;
+            bridge_hal.now = now;
+            protocol.poll(&mut tester_hal, physics);
+            bridge.poll(&mut bridge_hal, shared);
+            protocol.check_rx(&mut tester_hal, physics);
+        }
+    };
+    let mut bridge = Hcp2Driver::default();
+    run(500, &mut bridge, &mut physics, &mut shared);
+
+    // The driver hangs half-way through publishing while a command is queued
+    assert!(shared.push_command(1, DriveAction::Open));
+    shared.lp_to_hp.generation += 1;
+    assert!(shared.lp_to_hp.read().is_none());
+
+    let mut bridge = Hcp2Driver::default();
+    bridge.resume(&mut shared);
+    let status = shared.lp_to_hp.read().expect("region readable again after resume");
+    assert_eq!(status.cmd_tail, 1);
+    assert!(status.find_result(1).is_none());
+
+    run(3000, &mut bridge, &mut physics, &mut shared);
+    assert_eq!(physics.target_position, 0.0, "stale command must not be executed");
+
+    // Commands queued after the restart run normally
+    assert!(shared.push_command(2, DriveAction::Open));
+    run(3000, &mut bridge, &mut physics, &mut shared);
+    assert_eq!(physics.target_position, 200.0);
+    assert_eq!(shared.lp_to_hp.read().unwrap().find_result(2).unwrap().status, CMD_STATUS_ACKNOWLEDGED);
+}
+
+#[test]
+fn test_ffi_handles_are_independent() {
+    use hcp2_common::ffi::{FfiResult, TESTER_STORAGE_SIZE};
+    use hcp2_tester_lib::{hcp_tester_create, hcp_tester_destroy, hcp_tester_poll, hcp_tester_set_control, TesterHalC, TesterState};
+    use std::ffi::c_void;
+
+    extern "C" fn read_uart(_: *mut c_void, _: *mut u8, _: usize) -> i32 { 0 }
+    extern "C" fn write_uart(_: *mut c_void, _: *const u8, len: usize) -> i32 { len as i32 }
+    extern "C" fn set_tx_enable(_: *mut c_void, _: bool) {}
+    extern "C" fn now_ms() -> u32 { 0 }
+    extern "C" fn log(_: *mut c_void, _: *const u8, _: usize) {}
+    let hal = TesterHalC { ctx: std::ptr::null_mut(), read_uart, write_uart, set_tx_enable, now_ms, log };
+
+    #[repr(C, align(8))]
+    struct Storage([u8; TESTER_STORAGE_SIZE]);
+    let mut left = Storage([0; TESTER_STORAGE_SIZE]);
+    let mut right = Storage([0; TESTER_STORAGE_SIZE]);
+    let left = left.0.as_mut_ptr() as *mut c_void;
+    let right = right.0.as_mut_ptr() as *mut c_void;
+    let mut state = TesterState { current_pos: 0.0, target_pos: 0.0, light_on: false, vent_on: false, last_action: 0 };
+
+    unsafe {
+        // Misuse is reported instead of panicking
+        assert_eq!(hcp_tester_poll(left, &hal, &mut state), FfiResult::InvalidHandle);
+        assert_eq!(hcp_tester_create(std::ptr::null_mut(), TESTER_STORAGE_SIZE), FfiResult::NullPointer);
+        assert_eq!(hcp_tester_create(left, 8), FfiResult::StorageTooSmall);
+
+        assert_eq!(hcp_tester_create(left, TESTER_STORAGE_SIZE), FfiResult::Ok);
+        assert_eq!(hcp_tester_create(right, TESTER_STORAGE_SIZE), FfiResult::Ok);
+        assert_eq!(hcp_tester_poll(left, std::ptr::null(), &mut state), FfiResult::NullPointer);
+
+        assert_eq!(hcp_tester_set_control(left, 200.0, true), FfiResult::Ok);
+        assert_eq!(hcp_tester_poll(left, &hal, &mut state), FfiResult::Ok);
+        assert_eq!(state.target_pos, 200.0);
+        assert!(state.light_on);
+        assert_eq!(hcp_tester_poll(right, &hal, &mut state), FfiResult::Ok);
+        assert_eq!(state.target_pos, 0.0);
+        assert!(!state.light_on);
+
+        assert_eq!(hcp_tester_destroy(left), FfiResult::Ok);
+        assert_eq!(hcp_tester_poll(left, &hal, &mut state), FfiResult::InvalidHandle);
+        assert_eq!(hcp_tester_destroy(left), FfiResult::InvalidHandle);
+        assert_eq!(hcp_tester_destroy(right), FfiResult::Ok);
+    }
+}